@@ -1,8 +1,9 @@
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use dakv_logger::set_logger_level;
-use sql_protocol::{Handler, Listener, SqlResult};
+use sql_protocol::{Auth, Handler, Listener, Session, SqlResult};
 
 struct Server {
     listener: Listener,
@@ -23,10 +24,11 @@ impl Server {
 struct DB {}
 
 impl Handler for DB {
-    fn new_connection(&self) {}
-    fn close_connection(&self) {}
+    fn new_connection(&self, _session: &mut Session) {}
+    fn close_connection(&self, _session: &mut Session) {}
     fn com_query(
         &self,
+        _session: &mut Session,
         sql: &str,
         callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
     ) -> io::Result<()> {
@@ -35,6 +37,9 @@ impl Handler for DB {
         assert!(!sql.is_empty());
         callback(SqlResult::default())
     }
+    fn check_auth(&self, _auth: &Auth, _salt: &[u8], _addr: &SocketAddr) -> bool {
+        true
+    }
 }
 
 fn main() {
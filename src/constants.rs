@@ -18,6 +18,9 @@ pub const MYSQL_NATIVE_PASSWORD: &'static str = "mysql_native_password";
 pub const MYSQL_CLEAR_PASSWORD: &'static str = "mysql_clear_password";
 // MYSQL_DIALOG uses the dialog plugin on the client side. It transmits data in the clear.
 pub const MYSQL_DIALOG: &'static str = "dialog";
+// CACHING_SHA2_PASSWORD uses a salt and transmits a SHA256 hash on the wire,
+// a full authentication sends the password in the clear over TLS.
+pub const CACHING_SHA2_PASSWORD: &'static str = "caching_sha2_password";
 
 // See http://dev.mysql.com/doc/internals/en/character-set.html#packet-Protocol::CharacterSet
 pub const CHARACTER_SET_UTF8: u8 = 33;
@@ -28,7 +31,19 @@ pub const SERVER_STATUS_AUTOCOMMIT: u16 = 0x0002;
 // Packet
 pub const OK_PACKET: u8 = 0x00;
 pub const ERR_PACKET: u8 = 0xff;
-pub const EOF_PACKET: u8 = 0xff;
+pub const EOF_PACKET: u8 = 0xfe;
+pub const AUTH_MORE_DATA_PACKET: u8 = 0x01;
+
+// caching_sha2_password status sent in AuthMoreData.
+// See https://dev.mysql.com/doc/dev/mysql-server/latest/page_caching_sha2_authentication_exchanges.html
+pub const CACHING_SHA2_REQUEST_PUBLIC_KEY: u8 = 0x02;
+pub const CACHING_SHA2_FAST_AUTH_SUCCESS: u8 = 0x03;
+pub const CACHING_SHA2_PERFORM_FULL_AUTH: u8 = 0x04;
+
+// Question types of the dialog plugin, the lowest bit marks the last question.
+pub const DIALOG_LAST_QUESTION: u8 = 0x01;
+pub const DIALOG_ORDINARY_QUESTION: u8 = 0x02;
+pub const DIALOG_PASSWORD_QUESTION: u8 = 0x04;
 
 //flags
 pub const SERVER_MORE_RESULTS_EXISTS: u16 = 0x0008;
@@ -312,7 +327,7 @@ impl_from!(usize);
 // Originally found in include/mysql/errmsg.h and
 // https://dev.mysql.com/doc/refman/5.7/en/error-messages-client.html
 #[allow(dead_code)]
-enum ClientError {
+pub enum ClientError {
     // CRUnknownError is CR_UNKNOWN_ERROR
     CRUnknownError = 2000,
     // CRConnectionError is CR_CONNECTION_ERROR
@@ -355,6 +370,7 @@ enum ClientError {
 // The below are in sorted order by value, grouped by vterror code they should be bucketed into.
 // See above reference for more information on each code.
 #[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ServerError {
     // unknown
     ERUnknownError = 1105,
@@ -508,6 +524,7 @@ pub enum ServerError {
     ERIncorrectGlobalLocalVar = 1238,
     ERWrongFKDef = 1239,
     ERKeyRefDoNotMatchTableRef = 1240,
+    ERUnknownStmtHandler = 1243,
     ERCyclicReference = 1245,
     ERCollationCharsetMismatch = 1253,
     ERCantAggregate2Collations = 1267,
@@ -530,11 +547,13 @@ pub enum ServerError {
     ERTruncatedWrongValueForField = 1366,
     ERDataTooLong = 1406,
     ERDataOutOfRange = 1690,
+    ERSecureTransportRequired = 3159,
 }
 
 // Sql states for errors.
 // Originally found in include/mysql/sql_state.h
 #[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum StateError {
     // SSUnknownSqlstate is ER_SIGNAL_EXCEPTION in
     // include/mysql/sql_state.h, but:
@@ -564,6 +583,14 @@ pub enum StateError {
     SSAccessDeniedError,
     // SSLockDeadlock is ER_LOCK_DEADLOCK
     SSLockDeadlock,
+    // SSBadDb is ER_BAD_DB_ERROR
+    SSBadDb,
+    // SSQueryInterrupted is ER_QUERY_INTERRUPTED
+    SSQueryInterrupted,
+    // SSNoSuchTable is ER_NO_SUCH_TABLE
+    SSNoSuchTable,
+    // SSSyntaxError is ER_PARSE_ERROR and ER_SYNTAX_ERROR
+    SSSyntaxError,
 }
 
 impl Into<&'static str> for StateError {
@@ -581,6 +608,10 @@ impl Into<&'static str> for StateError {
             StateError::SSCantDoThisDuringAnTransaction => "25000",
             StateError::SSAccessDeniedError => "28000",
             StateError::SSLockDeadlock => "40001",
+            StateError::SSBadDb => "42000",
+            StateError::SSQueryInterrupted => "70100",
+            StateError::SSNoSuchTable => "42S02",
+            StateError::SSSyntaxError => "42000",
         };
     }
 }
@@ -642,7 +673,9 @@ fn convert_character_value(c: &str) -> i32 {
     };
 }
 
-fn is_conn_err(num: i32) -> bool {
+/// Whether the error code is a connection-level failure: the client errors
+/// up to CR_NAMEDPIPESETSTATE_ERROR and ER_QUERY_INTERRUPTED.
+pub fn is_conn_err(num: i32) -> bool {
     (num >= ClientError::CRUnknownError as i32 && num <= ClientError::CRNamedPipeStateError as i32)
         || num == ServerError::ERQueryInterrupted as i32
 }
@@ -674,6 +707,7 @@ pub const DEFAULT_SALT: &'static [u8; 20] = &[
     0x38, 0x26, 0x55, 0x58, 0x3b, 0x5d, 0x44, 0x78, 0x53, 0x73, 0x6b, 0x41,
 ];
 
+// TLSVersion is the minimum protocol version a TLS connection may use.
 pub enum TLSVersion {
     VersionTLS10 = 0x0301,
     VersionTLS11 = 0x0302,
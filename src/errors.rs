@@ -1,5 +1,10 @@
 use std::error;
+use std::fmt;
+use std::io;
 use std::result;
+
+use crate::constants::{is_conn_err, ServerError, StateError};
+
 /// A shortcut to box an error.
 #[macro_export]
 macro_rules! box_err {
@@ -28,6 +33,12 @@ quick_error! {
             description(err.description())
             display("Io {}", err)
         }
+        Tls(err: native_tls::Error) {
+            from()
+            cause(err)
+            description(err.description())
+            display("Tls {}", err)
+        }
         // Auth
         ReadClientFlagError {
             description("Read client flags error when unpacking packets")
@@ -59,6 +70,32 @@ quick_error! {
         ReadPluginError {
             description("Read plugin name error when unpacking packets")
         }
+        ReadConnAttrsError {
+            description("Read connection attributes error when unpacking packets")
+        }
+        TlsNotConfigured {
+            description("Client requested TLS but no certificate is configured")
+        }
+        SecureTransportRequired {
+            description("Connections using insecure transport are prohibited")
+        }
+        AuthRequiresSecureConnection {
+            description("Authentication requires secure connection")
+        }
+        MalformedAuthMoreData {
+            description("Malformed auth more data packet")
+        }
+        MalformedAuthSwitchRequest {
+            description("Malformed auth switch request packet")
+        }
+        AccessDenied(user: String) {
+            description("Access denied")
+            display("Access denied for user {}", user)
+        }
+        BadDb(db: String) {
+            description("Unknown database")
+            display("Unknown database '{}'", db)
+        }
         InvalidPluginError(s: String) {
             from()
             description(err.description())
@@ -92,6 +129,13 @@ quick_error! {
         ParseComSetOptionError{
             description("Parse com set option error when unpacking packets")
         }
+        ParseComStmtExecuteError{
+            description("Parse com statement execute error when unpacking packets")
+        }
+        UnknownStmtHandler(stmt_id: u32) {
+            description("Unknown prepared statement handler")
+            display("Unknown prepared statement handler ({})", stmt_id)
+        }
         ReadNextPacketError{
             description("Read next packet error")
         }
@@ -104,7 +148,241 @@ quick_error! {
         ComQuit{
             description("Com Quit")
         }
+        ServerShutdown {
+            description("Server shutdown in progress")
+        }
+        QueryInterrupted {
+            description("Query execution was interrupted")
+        }
+        NoSuchThread(id: u32) {
+            description("Unknown thread id")
+            display("Unknown thread id: {}", id)
+        }
+        KillDenied(id: u32) {
+            description("Kill denied")
+            display("You are not owner of thread {}", id)
+        }
+        // Client
+        MalformedPacket {
+            description("Malformed packet")
+        }
+        WrongParamsCount(expected: u16, got: usize) {
+            description("Wrong number of parameters")
+            display("Statement takes {} parameters, got {}", expected, got)
+        }
+        PoolTimeout {
+            description("Timed out waiting for a pooled connection")
+        }
+        NoWritableHost {
+            description("No writable host is available")
+        }
+        DeprecateEofNotSupported {
+            description("The backend does not support CLIENT_DEPRECATE_EOF")
+        }
+        Server(code: u16, state: String, message: String) {
+            description("Server error")
+            display("{} (errno {}) (sqlstate {})", message, code, state)
+        }
     }
 }
 
 pub type ProtoResult<T> = result::Result<T, ProtoError>;
+
+impl ProtoError {
+    /// Whether the error is a connection-level failure, the connection is
+    /// lost and the command may be retried on a new one.
+    pub fn is_conn_err(&self) -> bool {
+        match self {
+            // The socket failed, the client reports CR_SERVER_LOST. The other
+            // I/O errors, e.g. a malformed packet, are not retried.
+            ProtoError::Io(e) => is_socket_err(e.kind()),
+            ProtoError::Server(code, _, _) => is_conn_err(*code as i32),
+            _ => false,
+        }
+    }
+}
+
+fn is_socket_err(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+/// SqlError is an error reported to the client with an ERR packet, the
+/// connection stays open. Handlers return it converted into an io::Error:
+///
+/// ```
+/// use sql_protocol::{ServerError, SqlError, StateError};
+///
+/// let err: std::io::Error = SqlError::new(
+///     ServerError::ERNoSuchTable,
+///     StateError::SSNoSuchTable,
+///     "Table 'test.t' doesn't exist",
+/// )
+/// .into();
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlError {
+    pub code: ServerError,
+    pub state: StateError,
+    pub message: String,
+}
+
+impl SqlError {
+    pub fn new<S: Into<String>>(code: ServerError, state: StateError, message: S) -> Self {
+        SqlError {
+            code,
+            state,
+            message: message.into(),
+        }
+    }
+
+    fn unknown(message: String) -> Self {
+        SqlError::new(
+            ServerError::ERUnknownError,
+            StateError::SSUnknownSQLState,
+            message,
+        )
+    }
+}
+
+impl fmt::Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state: &str = self.state.into();
+        write!(
+            f,
+            "{} (errno {}) (sqlstate {})",
+            self.message, self.code as u16, state
+        )
+    }
+}
+
+impl error::Error for SqlError {}
+
+impl From<SqlError> for io::Error {
+    fn from(err: SqlError) -> Self {
+        io::Error::new(io::ErrorKind::Other, err)
+    }
+}
+
+impl From<&ProtoError> for SqlError {
+    /// Map err onto the code and the state the client gets, an io::Error
+    /// wrapping a SqlError is reported as is.
+    fn from(err: &ProtoError) -> Self {
+        match err {
+            ProtoError::Io(e) => match e.get_ref().and_then(|e| e.downcast_ref::<SqlError>()) {
+                Some(e) => e.clone(),
+                None => SqlError::unknown(e.to_string()),
+            },
+            ProtoError::AccessDenied(user) => SqlError::new(
+                ServerError::ERAccessDeniedError,
+                StateError::SSAccessDeniedError,
+                format!("Access denied for user '{}'", user),
+            ),
+            ProtoError::SecureTransportRequired => SqlError::new(
+                ServerError::ERSecureTransportRequired,
+                StateError::SSUnknownSQLState,
+                "Connections using insecure transport are prohibited",
+            ),
+            ProtoError::BadDb(db) => SqlError::new(
+                ServerError::ERBadDb,
+                StateError::SSBadDb,
+                format!("Unknown database '{}'", db),
+            ),
+            ProtoError::UnknownStmtHandler(stmt_id) => SqlError::new(
+                ServerError::ERUnknownStmtHandler,
+                StateError::SSUnknownSQLState,
+                format!("Unknown prepared statement handler ({})", stmt_id),
+            ),
+            ProtoError::ParseComStatementError
+            | ProtoError::ParseComSetOptionError
+            | ProtoError::ParseComStmtExecuteError
+            | ProtoError::EmptyPacketError => SqlError::new(
+                ServerError::ERUnknownComError,
+                StateError::SSUnknownComError,
+                "Malformed packet",
+            ),
+            ProtoError::ServerShutdown => SqlError::new(
+                ServerError::ERServerShutdown,
+                StateError::SSServerShutdown,
+                "Server shutdown in progress",
+            ),
+            ProtoError::QueryInterrupted => SqlError::new(
+                ServerError::ERQueryInterrupted,
+                StateError::SSQueryInterrupted,
+                "Query execution was interrupted",
+            ),
+            ProtoError::NoSuchThread(id) => SqlError::new(
+                ServerError::ERNoSuchThread,
+                StateError::SSUnknownSQLState,
+                format!("Unknown thread id: {}", id),
+            ),
+            ProtoError::KillDenied(id) => SqlError::new(
+                ServerError::ERKillDenied,
+                StateError::SSUnknownSQLState,
+                format!("You are not owner of thread {}", id),
+            ),
+            e => SqlError::unknown(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::constants::{ServerError, StateError};
+    use crate::errors::{ProtoError, SqlError};
+
+    #[test]
+    fn test_sql_error() {
+        let err = SqlError::new(
+            ServerError::ERDupEntry,
+            StateError::SSDupKey,
+            "Duplicate entry '1' for key 'PRIMARY'",
+        );
+        assert_eq!(
+            err.to_string(),
+            "Duplicate entry '1' for key 'PRIMARY' (errno 1062) (sqlstate 23000)"
+        );
+        let io_err: io::Error = err.clone().into();
+        assert_eq!(SqlError::from(&ProtoError::Io(io_err)), err);
+
+        let err = SqlError::from(&ProtoError::Io(io::Error::new(
+            io::ErrorKind::Other,
+            "broken",
+        )));
+        assert_eq!(err.code, ServerError::ERUnknownError);
+        assert_eq!(err.message, "broken");
+        let err = SqlError::from(&ProtoError::BadDb("db".to_string()));
+        assert_eq!(err.code, ServerError::ERBadDb);
+        assert_eq!(err.state, StateError::SSBadDb);
+        assert_eq!(err.message, "Unknown database 'db'");
+        let err = SqlError::from(&ProtoError::ComQuit);
+        assert_eq!(err.code, ServerError::ERUnknownError);
+    }
+
+    #[test]
+    fn test_is_conn_err() {
+        let server = |code: u16| ProtoError::Server(code, "HY000".to_string(), "".to_string());
+        let io_err = |kind: io::ErrorKind| ProtoError::Io(io::Error::new(kind, "io"));
+        assert!(io_err(io::ErrorKind::ConnectionReset).is_conn_err());
+        assert!(io_err(io::ErrorKind::UnexpectedEof).is_conn_err());
+        assert!(!io_err(io::ErrorKind::InvalidData).is_conn_err());
+        assert!(!ProtoError::Io(io::Error::new(io::ErrorKind::Other, "handler")).is_conn_err());
+        assert!(server(2013).is_conn_err());
+        assert!(server(ServerError::ERQueryInterrupted as u16).is_conn_err());
+        assert!(!server(ServerError::ERNoSuchTable as u16).is_conn_err());
+        assert!(!server(2027).is_conn_err());
+        assert!(!ProtoError::AccessDenied("root".to_string()).is_conn_err());
+    }
+}
@@ -2,6 +2,7 @@
 #![allow(clippy::needless_return)]
 #![allow(clippy::redundant_static_lifetimes)]
 #![feature(box_syntax)]
+#![recursion_limit = "256"]
 #[macro_use]
 extern crate quick_error;
 #[macro_use]
@@ -9,8 +10,25 @@ extern crate lazy_static;
 
 mod constants;
 mod errors;
+mod mysql_proxy;
 mod proto;
 mod sql_type;
 
-pub use crate::proto::{Handler, Listener};
-pub use crate::sql_type::SqlResult;
+pub use crate::constants::{
+    ServerError, StateError, TLSVersion, CACHING_SHA2_PASSWORD, MYSQL_CLEAR_PASSWORD,
+    MYSQL_DIALOG, MYSQL_NATIVE_PASSWORD,
+};
+pub use crate::errors::{ProtoError, ProtoResult, SqlError};
+pub use crate::mysql_proxy::{Proxy, ProxyHandler};
+pub use crate::proto::{
+    caching_sha2_more_data, gen_auth_response, gen_caching_sha2_password, native_password_hash,
+    parse_auth_more_data, parse_auth_switch_request, verify_native_password,
+    verify_native_password_hash, write_auth_switch_request, Auth, CancelToken, Client, Extensions,
+    FailoverClient, Handler, Listener, Pool, PooledClient, PrepareData, ProcessInfo, Session,
+    Sha2Cache, ShutdownHandle, Statement, TlsConfig,
+};
+#[cfg(feature = "async")]
+pub use crate::proto::{AsyncHandler, AsyncListener, Dialog, ResultWriter};
+pub use crate::sql_type::{Field, MysqlType, SqlResult, Value};
+#[cfg(feature = "async")]
+pub use async_trait::async_trait;
@@ -0,0 +1,616 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use crate::constants::{
+    CapabilityFlag, PacketType, ServerError, StateError, DEFAULT_CLIENT_CAPABILITY, ERR_PACKET,
+    OK_PACKET, SERVER_MORE_RESULTS_EXISTS,
+};
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::{
+    parse_end_packet, parse_ok_packet, Auth, Client, Connection, Handler, Packets, ReadLenEncode,
+    Session,
+};
+use crate::sql_type::SqlResult;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use dakv_logger::prelude::*;
+
+// RELAYED_CAPABILITY holds the capability flags changing how the results are
+// framed, the backend connection is opened with the ones of the client.
+const RELAYED_CAPABILITY: u32 = CapabilityFlag::CapabilityClientDeprecateEOF as u32
+    | CapabilityFlag::CapabilityClientMultiStatements as u32
+    | CapabilityFlag::CapabilityClientMultiResults as u32;
+
+// CURSOR_TYPE_MASK holds the cursor type bits of the COM_STMT_EXECUTE flags,
+// the other bits, e.g. PARAMETER_COUNT_AVAILABLE, are relayed as is.
+const CURSOR_TYPE_MASK: u8 = 0x07;
+
+/// Return whether the COM_STMT_EXECUTE packet data asks for a cursor.
+fn opens_cursor(data: &[u8]) -> bool {
+    matches!(data.get(5), Some(&flags) if flags & CURSOR_TYPE_MASK != 0)
+}
+
+pub trait ProxyHandler: Send + Sync {
+    // check_auth is called once the client handshake response is parsed, it
+    // returns whether the user may connect to the proxy, see Handler::check_auth.
+    fn check_auth(&self, auth: &Auth, salt: &[u8], addr: &SocketAddr) -> bool;
+    // backend_credentials is called once the client is authenticated, it
+    // returns the user and the password the session logs in to the backend with.
+    fn backend_credentials(&self, session: &Session) -> (String, String);
+    // new_connection is called once the backend accepted the session.
+    fn new_connection(&self, _session: &mut Session) {}
+    // close_connection is called when the client or the backend connection
+    // is closed.
+    fn close_connection(&self, _session: &mut Session) {}
+}
+
+/// Proxy relays the commands of its clients to a backend server and the
+/// answers back. Every client gets its own backend connection, logged in
+/// with the credentials the ProxyHandler maps its user to. The backend
+/// connection is opened before the client is greeted, so the greeting carries
+/// its connection id, the one KILL takes, and its server version.
+/// COM_CHANGE_USER, the replication commands, the cursors and LOAD DATA LOCAL
+/// INFILE are not relayed.
+pub struct Proxy {
+    listener: TcpListener,
+    backend_addr: String,
+}
+
+impl Proxy {
+    pub fn new_tcp_proxy<Addr: ToSocketAddrs>(addr: Addr, backend_addr: &str) -> Self {
+        let listener = TcpListener::bind(addr).unwrap();
+        Proxy {
+            listener,
+            backend_addr: backend_addr.to_string(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve the clients, each one on its own thread.
+    pub fn accept(&mut self, handler: Arc<dyn ProxyHandler>) {
+        debug!("Start proxy to {} ...", self.backend_addr);
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => {
+                    error!("Empty stream");
+                    continue;
+                }
+            };
+            let backend_addr = self.backend_addr.clone();
+            let handler = handler.clone();
+            thread::spawn(move || serve(stream, &backend_addr, handler));
+        }
+    }
+}
+
+/// Open the backend connection, authenticate the client with its connection
+/// id, log in to the backend on its behalf, then relay its commands until
+/// either side closes the connection.
+fn serve(stream: TcpStream, backend_addr: &str, handler: Arc<dyn ProxyHandler>) {
+    let mut backend = match Client::open(backend_addr) {
+        Ok(backend) => backend,
+        Err(e) => {
+            error!("Connect to backend {} failed: {}", backend_addr, e);
+            // The client expects an ERR packet in place of the greeting.
+            let mut packets = Packets::new();
+            packets.set_stream(Box::new(stream));
+            if let Err(e) = write_err(&mut packets, &e) {
+                debug!("Write backend error failed: {}", e);
+            }
+            return;
+        }
+    };
+    let mut conn = Connection::new(
+        backend.connection_id(),
+        backend.server_version().to_string(),
+    );
+    let frontend = Frontend {
+        handler: handler.clone(),
+    };
+    let mut session = match conn.accept(stream, &frontend) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("Handshake failed: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = login_backend(&conn, &session, &mut backend, handler.as_ref()) {
+        error!("Log in to backend {} failed: {}", backend_addr, e);
+        if let Err(e) = write_err(conn.packets(), &e) {
+            debug!("Write backend error failed: {}", e);
+        }
+        return;
+    }
+    session.set_schema(conn.database());
+    if let Err(e) = conn
+        .packets()
+        .write_ok_packet(0, 0, backend.status_flags(), 0)
+    {
+        error!("Handshake failed: {}", e);
+        return;
+    }
+    handler.new_connection(&mut session);
+    let deprecate_eof =
+        conn.capability() & CapabilityFlag::CapabilityClientDeprecateEOF as u32 != 0;
+    let mut relay = Relay {
+        client: conn.packets(),
+        backend: &mut backend,
+        deprecate_eof,
+    };
+    if let Err(e) = relay.run() {
+        debug!(
+            "Relay of connection {} stopped: {}",
+            session.connection_id(),
+            e
+        );
+    }
+    handler.close_connection(&mut session);
+}
+
+/// Log in to the backend with the credentials the user of session maps to,
+/// the results are framed as the client expects them.
+fn login_backend(
+    conn: &Connection,
+    session: &Session,
+    backend: &mut Client,
+    handler: &dyn ProxyHandler,
+) -> ProtoResult<()> {
+    let (user, password) = handler.backend_credentials(session);
+    let capability =
+        DEFAULT_CLIENT_CAPABILITY & !RELAYED_CAPABILITY | conn.capability() & RELAYED_CAPABILITY;
+    backend.login(
+        &user,
+        &password,
+        conn.database(),
+        capability,
+        session.charset(),
+    )?;
+    // An older backend would end the rows with EOF packets, which the client
+    // does not expect.
+    if (backend.capability() ^ capability) & CapabilityFlag::CapabilityClientDeprecateEOF as u32
+        != 0
+    {
+        return Err(ProtoError::DeprecateEofNotSupported);
+    }
+    Ok(())
+}
+
+/// Report err to the client, the errors of the backend are relayed as is.
+fn write_err(packets: &mut Packets, err: &ProtoError) -> io::Result<()> {
+    match err {
+        ProtoError::Server(code, state, message) => {
+            packets.write_err_packet(*code, state.clone(), message.clone())
+        }
+        _ => packets.write_err_packet_from_err(err),
+    }
+}
+
+/// Frontend authenticates the clients of the proxy, their commands are
+/// relayed instead of handled.
+struct Frontend {
+    handler: Arc<dyn ProxyHandler>,
+}
+
+impl Handler for Frontend {
+    fn new_connection(&self, _session: &mut Session) {}
+    fn close_connection(&self, _session: &mut Session) {}
+    fn com_query(
+        &self,
+        _session: &mut Session,
+        _sql: &str,
+        _callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Queries are relayed to the backend",
+        ))
+    }
+    fn check_auth(&self, auth: &Auth, salt: &[u8], addr: &SocketAddr) -> bool {
+        self.handler.check_auth(auth, salt, addr)
+    }
+}
+
+/// Relay forwards the commands of a client to its backend connection and the
+/// answers back, packet by packet. Each side keeps its own sequence ids, which
+/// restart on every command, so the packets of an answer keep their ids.
+struct Relay<'a> {
+    client: &'a mut Packets,
+    backend: &'a mut Client,
+    // Whether the rows end with an OK packet instead of an EOF packet, and
+    // the column definitions are not followed by an EOF packet.
+    deprecate_eof: bool,
+}
+
+impl Relay<'_> {
+    /// Relay the commands until the client quits or a connection fails.
+    fn run(&mut self) -> ProtoResult<()> {
+        loop {
+            let data = self.client.read_command()?;
+            let cmd = match data.first() {
+                Some(&pt) => PacketType::from(pt as u64),
+                None => {
+                    self.client.write_err_packet(
+                        ServerError::ERUnknownComError as u16,
+                        StateError::SSUnknownComError.into(),
+                        "Malformed packet".to_string(),
+                    )?;
+                    continue;
+                }
+            };
+            match cmd {
+                PacketType::ComQuit => return self.backend.write_command_packet(&data),
+                // The backend connection belongs to the mapped user, and the
+                // replication stream does not follow the command phase.
+                cmd @ (PacketType::ComChangeUser
+                | PacketType::ComBinlogDump
+                | PacketType::ComBinlogDumpGtid
+                | PacketType::ComRegisterSlave) => {
+                    let cmd_str: &'static str = cmd.into();
+                    self.client.write_err_packet(
+                        ServerError::ERUnknownComError as u16,
+                        StateError::SSUnknownComError.into(),
+                        format!("Command not supported by the proxy: {}", cmd_str),
+                    )?;
+                }
+                // A cursor answers COM_STMT_EXECUTE with the column definitions
+                // alone, its rows are fetched later with COM_STMT_FETCH.
+                PacketType::ComStmtExecute if opens_cursor(&data) => {
+                    self.client.write_err_packet(
+                        ServerError::ERUnknownComError as u16,
+                        StateError::SSUnknownComError.into(),
+                        "Cursors are not supported by the proxy".to_string(),
+                    )?;
+                }
+                cmd => {
+                    self.backend.write_command_packet(&data)?;
+                    self.relay_answer(cmd)?;
+                }
+            }
+        }
+    }
+
+    /// Relay the answer of the backend to cmd, if it sends one.
+    fn relay_answer(&mut self, cmd: PacketType) -> ProtoResult<()> {
+        match cmd {
+            PacketType::ComQuery | PacketType::ComStmtExecute | PacketType::ComProcessInfo => {
+                self.relay_results()
+            }
+            PacketType::ComStmtPrepare => self.relay_prepare(),
+            PacketType::ComFieldList | PacketType::ComStmtFetch => self.relay_rows().map(|_| ()),
+            PacketType::ComStmtClose | PacketType::ComStmtSendLongData => Ok(()),
+            _ => self.relay_packet().map(|_| ()),
+        }
+    }
+
+    /// Relay OK packets and result sets until the backend has no more results.
+    fn relay_results(&mut self) -> ProtoResult<()> {
+        loop {
+            let data = self.relay_packet()?;
+            let flags = match data.first() {
+                Some(&OK_PACKET) => parse_ok_packet(&data)?.2,
+                Some(&ERR_PACKET) => return Ok(()),
+                Some(_) => {
+                    let count = (&data[..]).read_len_int()?;
+                    self.relay_definitions(count)?;
+                    match self.relay_rows()? {
+                        Some(flags) => flags,
+                        None => return Ok(()),
+                    }
+                }
+                None => return Err(ProtoError::MalformedPacket),
+            };
+            if flags & SERVER_MORE_RESULTS_EXISTS == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Relay the answer to COM_STMT_PREPARE, its OK packet is followed by the
+    /// definitions of the parameters, then the ones of the columns.
+    fn relay_prepare(&mut self) -> ProtoResult<()> {
+        let data = self.relay_packet()?;
+        if data.first() != Some(&OK_PACKET) {
+            return Ok(());
+        }
+        let mut data = data.get(5..).ok_or(ProtoError::MalformedPacket)?;
+        let columns_count = data.read_u16::<LittleEndian>()?;
+        let params_count = data.read_u16::<LittleEndian>()?;
+        self.relay_definitions(params_count as u64)?;
+        self.relay_definitions(columns_count as u64)
+    }
+
+    /// Relay count column definitions, followed by an EOF packet unless the
+    /// client deprecates EOF.
+    fn relay_definitions(&mut self, count: u64) -> ProtoResult<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        for _ in 0..count {
+            self.relay_packet()?;
+        }
+        if !self.deprecate_eof {
+            self.relay_packet()?;
+        }
+        Ok(())
+    }
+
+    /// Relay the rows until the end of the result set, return the status
+    /// flags it ends with, None if it ends with an error.
+    fn relay_rows(&mut self) -> ProtoResult<Option<u16>> {
+        loop {
+            let data = self.relay_packet()?;
+            if data.first() == Some(&ERR_PACKET) {
+                return Ok(None);
+            }
+            if let Some(flags) = parse_end_packet(&data, self.deprecate_eof) {
+                return Ok(Some(flags));
+            }
+        }
+    }
+
+    fn relay_packet(&mut self) -> ProtoResult<Vec<u8>> {
+        let data = self.backend.read_packet()?;
+        self.client.write_packet(&data)?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::constants::{PacketType, ServerError, StateError, EOF_PACKET, ERR_PACKET};
+    use crate::errors::{ProtoError, SqlError};
+    use crate::mysql_proxy::{Proxy, ProxyHandler};
+    use crate::proto::{native_password_hash, verify_native_password_hash};
+    use crate::proto::{Auth, Client, Handler, Listener, PrepareData, Session};
+    use crate::sql_type::{Field, MysqlType, SqlResult, Type, Value};
+
+    struct BackendHandler {}
+
+    impl Handler for BackendHandler {
+        fn new_connection(&self, _session: &mut Session) {}
+        fn close_connection(&self, _session: &mut Session) {}
+        fn com_query(
+            &self,
+            session: &mut Session,
+            sql: &str,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            let column = |name: &str, val: &str| SqlResult {
+                fields: vec![Field {
+                    name: name.to_string(),
+                    typ: MysqlType::Varchar as Type,
+                    ..Default::default()
+                }],
+                rows: vec![vec![Value::from(val)]],
+                ..Default::default()
+            };
+            match sql {
+                "SELECT USER()" => callback(column("USER()", session.user())),
+                "SELECT DATABASE()" => callback(column("DATABASE()", session.schema())),
+                "SELECT CONNECTION_ID()" => callback(column(
+                    "CONNECTION_ID()",
+                    &session.connection_id().to_string(),
+                )),
+                "SELECT id, name FROM t" => callback(SqlResult {
+                    fields: vec![
+                        Field {
+                            name: "id".to_string(),
+                            typ: MysqlType::Int64 as Type,
+                            ..Default::default()
+                        },
+                        Field {
+                            name: "name".to_string(),
+                            typ: MysqlType::Varchar as Type,
+                            ..Default::default()
+                        },
+                    ],
+                    rows: vec![vec![Value::from(1i64), Value::from("a")], vec![
+                        Value::from(2i64),
+                        Value::default(),
+                    ]],
+                    ..Default::default()
+                }),
+                "INSERT INTO t VALUES (3)" => callback(SqlResult {
+                    affected_rows: 1,
+                    insert_id: 3,
+                    ..Default::default()
+                }),
+                _ => Err(SqlError::new(
+                    ServerError::ERNoSuchTable,
+                    StateError::SSNoSuchTable,
+                    "Table 'test.missing' doesn't exist",
+                )
+                .into()),
+            }
+        }
+        fn com_prepare(
+            &self,
+            _session: &mut Session,
+            _sql: &str,
+            _params_count: u16,
+        ) -> io::Result<Vec<Field>> {
+            Ok(vec![Field {
+                name: "id".to_string(),
+                typ: MysqlType::Int64 as Type,
+                ..Default::default()
+            }])
+        }
+        fn com_stmt_execute(
+            &self,
+            session: &mut Session,
+            prepare: &PrepareData,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            // Echo the bound id.
+            callback(SqlResult {
+                fields: self.com_prepare(session, &prepare.prepare_stmt, 1)?,
+                rows: vec![vec![Value {
+                    typ: MysqlType::Int64 as Type,
+                    val: prepare.params[0].val.clone(),
+                }]],
+                ..Default::default()
+            })
+        }
+        fn com_init_db(&self, _session: &mut Session, schema: &str) -> bool {
+            schema == "test"
+        }
+        fn check_auth(&self, auth: &Auth, salt: &[u8], _addr: &SocketAddr) -> bool {
+            auth.user() == "app"
+                && verify_native_password_hash(
+                    auth.auth_response(),
+                    salt,
+                    &native_password_hash("secret"),
+                )
+        }
+    }
+
+    #[derive(Default)]
+    struct MapHandler {
+        closed: AtomicUsize,
+    }
+
+    impl ProxyHandler for MapHandler {
+        fn check_auth(&self, auth: &Auth, salt: &[u8], _addr: &SocketAddr) -> bool {
+            verify_native_password_hash(auth.auth_response(), salt, &native_password_hash("pw"))
+        }
+        fn backend_credentials(&self, session: &Session) -> (String, String) {
+            match session.user() {
+                "alice" => ("app".to_string(), "secret".to_string()),
+                _ => ("app".to_string(), "wrong".to_string()),
+            }
+        }
+        fn close_connection(&self, _session: &mut Session) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn listen(handler: Arc<MapHandler>) -> SocketAddr {
+        let mut listener = Listener::new_tcp_listener("127.0.0.1:0");
+        let backend_addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.accept(Arc::new(BackendHandler {})));
+        let mut proxy = Proxy::new_tcp_proxy("127.0.0.1:0", &backend_addr.to_string());
+        let addr = proxy.local_addr().unwrap();
+        thread::spawn(move || proxy.accept(handler));
+        addr
+    }
+
+    #[test]
+    fn test_auth() {
+        let addr = listen(Arc::new(MapHandler::default()));
+        match Client::connect(addr, "alice", "wrong", "") {
+            Err(ProtoError::Server(code, _, message)) => {
+                assert_eq!(code, ServerError::ERAccessDeniedError as u16);
+                assert!(message.starts_with("Access denied for user 'alice'"));
+            }
+            _ => panic!("Wrong password is accepted"),
+        }
+        // The backend refuses the credentials bob maps to.
+        match Client::connect(addr, "bob", "pw", "") {
+            Err(ProtoError::Server(code, _, message)) => {
+                assert_eq!(code, ServerError::ERAccessDeniedError as u16);
+                assert!(message.starts_with("Access denied for user 'app'"));
+            }
+            _ => panic!("Wrong backend password is accepted"),
+        }
+        match Client::connect(addr, "alice", "pw", "unknown") {
+            Err(ProtoError::Server(code, _, message)) => {
+                assert_eq!(code, ServerError::ERBadDb as u16);
+                assert_eq!(message, "Unknown database 'unknown'");
+            }
+            _ => panic!("Unknown database is accepted"),
+        }
+
+        let mut client = Client::connect(addr, "alice", "pw", "test").unwrap();
+        let result = client.query("SELECT USER()").unwrap();
+        assert_eq!(result.rows[0][0].val, b"app");
+        let result = client.query("SELECT DATABASE()").unwrap();
+        assert_eq!(result.rows[0][0].val, b"test");
+    }
+
+    #[test]
+    fn test_relay() {
+        let handler = Arc::new(MapHandler::default());
+        let addr = listen(handler.clone());
+        let mut client = Client::connect(addr, "alice", "pw", "").unwrap();
+        // The client is greeted with the id of its backend connection.
+        let result = client.query("SELECT CONNECTION_ID()").unwrap();
+        assert_eq!(
+            result.rows[0][0].val,
+            client.connection_id().to_string().into_bytes()
+        );
+
+        let result = client.query("SELECT id, name FROM t").unwrap();
+        assert_eq!(result.fields[1].name, "name");
+        assert_eq!(result.rows.len(), 2);
+        assert_eq!(result.rows[0][1].val, b"a");
+        assert!(result.rows[1][1].is_null());
+        match client.query("SELECT * FROM missing") {
+            Err(ProtoError::Server(code, state, _)) => {
+                assert_eq!(code, ServerError::ERNoSuchTable as u16);
+                assert_eq!(state, "42S02");
+            }
+            _ => panic!("Query should fail"),
+        }
+        let results = client
+            .query_multi("INSERT INTO t VALUES (3); SELECT id, name FROM t")
+            .unwrap();
+        assert_eq!((results[0].affected_rows, results[0].insert_id), (1, 3));
+        assert_eq!(results[1].rows.len(), 2);
+        client.init_db("test").unwrap();
+        assert!(client.init_db("unknown").is_err());
+
+        let stmt = client.prepare("SELECT id FROM t WHERE id = ?").unwrap();
+        assert_eq!((stmt.params_count(), stmt.columns().len()), (1, 1));
+        for id in 1..3i64 {
+            let result = client.execute(&stmt, &[Value::from(id)]).unwrap();
+            assert_eq!(result.rows[0][0].val, id.to_string().into_bytes());
+        }
+        // COM_STMT_EXECUTE opening a read only cursor.
+        let mut data = vec![PacketType::ComStmtExecute as u8];
+        data.extend_from_slice(&stmt.id().to_le_bytes());
+        data.extend_from_slice(&[1, 1, 0, 0, 0]);
+        client.write_command_packet(&data).unwrap();
+        let data = client.read_packet().unwrap();
+        assert_eq!(data[0], ERR_PACKET);
+        assert!(String::from_utf8_lossy(&data).contains("Cursors are not supported"));
+        // PARAMETER_COUNT_AVAILABLE without a cursor type is executed.
+        let mut data = vec![PacketType::ComStmtExecute as u8];
+        data.extend_from_slice(&stmt.id().to_le_bytes());
+        data.extend_from_slice(&[0x08, 1, 0, 0, 0]);
+        // Null bitmap, new params bound and the LONGLONG parameter 2.
+        data.extend_from_slice(&[0, 1, 0x08, 0x00]);
+        data.extend_from_slice(&2i64.to_le_bytes());
+        client.write_command_packet(&data).unwrap();
+        assert_eq!(client.read_packet().unwrap(), [1]);
+        // The column definition, the row and the closing OK packet.
+        client.read_packet().unwrap();
+        assert_eq!(client.read_packet().unwrap()[0], 0);
+        assert_eq!(client.read_packet().unwrap()[0], EOF_PACKET);
+        client.close_statement(stmt).unwrap();
+        // An empty command is refused, the connection stays open.
+        client.write_command_packet(&[]).unwrap();
+        assert_eq!(client.read_packet().unwrap()[0], ERR_PACKET);
+        client.ping().unwrap();
+        client.quit().unwrap();
+        for _ in 0..100 {
+            if handler.closed.load(Ordering::SeqCst) == 1 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Connection is not closed");
+    }
+}
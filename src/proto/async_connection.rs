@@ -0,0 +1,448 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::constants::PacketType;
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::async_packets::AsyncPackets;
+use crate::proto::caching_sha2::Sha2Cache;
+use crate::proto::handshake::{dialog_question_packet, trim_nul, AuthStep, Handshake};
+use crate::proto::packets::bad_db_packet;
+use crate::proto::registry::{Registry, Tracker};
+use crate::proto::{AsyncHandler, Session};
+
+use dakv_logger::prelude::*;
+use native_tls::TlsAcceptor;
+use tokio::net::TcpStream;
+
+/// AsyncConnection is the async counterpart of Connection, it runs the same
+/// handshake and commands on a tokio task.
+pub struct AsyncConnection {
+    // handshake authenticates the client, COM_CHANGE_USER runs it again.
+    handshake: Handshake,
+    packets: AsyncPackets,
+    // tracker tells the registry of the listener when the connection is idle.
+    tracker: Tracker,
+}
+
+impl AsyncConnection {
+    pub fn new(id: u32, server_version: String) -> Self {
+        AsyncConnection {
+            handshake: Handshake::new(id, server_version),
+            packets: AsyncPackets::new(),
+            tracker: Tracker::new(id),
+        }
+    }
+
+    pub fn set_tls_acceptor(&mut self, acceptor: Option<Arc<TlsAcceptor>>) {
+        self.handshake.set_tls_acceptor(acceptor);
+    }
+
+    pub fn set_require_secure_transport(&mut self, require: bool) {
+        self.handshake.set_require_secure_transport(require);
+    }
+
+    pub fn set_auth_plugin_name(&mut self, auth_plugin_name: &str) {
+        self.handshake.set_auth_plugin_name(auth_plugin_name);
+    }
+
+    pub fn set_allow_cleartext_passwords(&mut self, allow: bool) {
+        self.handshake.set_allow_cleartext_passwords(allow);
+    }
+
+    pub fn set_auth_cache(&mut self, auth_cache: Arc<Sha2Cache>) {
+        self.handshake.set_auth_cache(auth_cache);
+    }
+
+    pub fn set_registry(&mut self, registry: Arc<Registry>) {
+        self.packets.set_registry(registry.clone());
+        self.tracker.set_registry(registry);
+    }
+
+    pub async fn handle(&mut self, stream: TcpStream, handler: Arc<dyn AsyncHandler>) {
+        debug!("Read request ...");
+
+        let addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Get peer address failed: {}", e);
+                return;
+            }
+        };
+        self.packets.set_stream(Box::new(stream));
+        let mut session = match self.handshake(handler.as_ref(), &addr).await {
+            Ok(session) => session,
+            Err(e) => {
+                error!("Handshake failed: {}", e);
+                return;
+            }
+        };
+        self.tracker.set_session(&session);
+        handler.new_connection(&mut session).await;
+        self.packets.set_capability(self.handshake.capability());
+        loop {
+            // The connection is closed once killed or on shutdown.
+            if !self.tracker.set_busy(false) {
+                if self.tracker.is_shutdown() {
+                    self.packets.reset_sequence_id();
+                    self.write_shutdown_err().await;
+                }
+                break;
+            }
+            let data = match self.packets.read_command().await {
+                Ok(data) => data,
+                Err(_) => {
+                    // The read side is closed to wake up idle connections.
+                    if self.tracker.is_shutdown() {
+                        self.packets.reset_sequence_id();
+                        self.write_shutdown_err().await;
+                    }
+                    break;
+                }
+            };
+            if !self.tracker.set_busy(true) {
+                if self.tracker.is_shutdown() {
+                    self.write_shutdown_err().await;
+                }
+                break;
+            }
+            self.tracker.set_command(&data);
+            // An empty packet is answered with an ERR by handle_command.
+            let command = data.first().map(|&pt| PacketType::from(pt as u64));
+            let result: ProtoResult<()> = match command {
+                Some(PacketType::ComChangeUser) => {
+                    self.change_user(handler.as_ref(), &addr, &mut session, &data)
+                        .await
+                }
+                _ => {
+                    self.packets
+                        .handle_command(
+                            &handler,
+                            &mut session,
+                            self.handshake.status_flag(),
+                            data.as_slice(),
+                        )
+                        .await
+                }
+            };
+            if result.is_err() {
+                break;
+            }
+            self.tracker.set_session(&session);
+        }
+        handler.close_connection(&mut session).await;
+    }
+
+    async fn write_shutdown_err(&mut self) {
+        let result = self
+            .packets
+            .write_err_packet_from_err(&ProtoError::ServerShutdown)
+            .await;
+        if let Err(e) = result {
+            debug!("Write shutdown error failed: {}", e);
+        }
+    }
+
+    /// Authenticate the client and select the database of its handshake
+    /// response, return the session of the connection.
+    async fn handshake(
+        &mut self,
+        handler: &dyn AsyncHandler,
+        addr: &SocketAddr,
+    ) -> ProtoResult<Session> {
+        let pkg = self.handshake.greet(*addr)?;
+        self.packets.write_packet(pkg.as_slice()).await?;
+        let pkg = self.packets.read_packet().await?;
+        let step = self.handshake.answer(pkg)?;
+        self.authenticate(handler, addr, step).await?;
+        let mut session = self.handshake.session();
+        self.init_db(handler, &mut session).await?;
+        self.packets
+            .write_ok_packet(0, 0, self.handshake.status_flag(), 0)
+            .await?;
+        Ok(session)
+    }
+
+    /// COM_CHANGE_USER, see Connection::change_user.
+    async fn change_user(
+        &mut self,
+        handler: &dyn AsyncHandler,
+        addr: &SocketAddr,
+        session: &mut Session,
+        data: &[u8],
+    ) -> ProtoResult<()> {
+        let step = self.handshake.change_user(data)?;
+        self.authenticate(handler, addr, step).await?;
+        let mut new_session = self.handshake.session();
+        self.init_db(handler, &mut new_session).await?;
+        *session = new_session;
+        self.tracker.set_session(session);
+        self.packets.clear_prepares();
+        self.packets.set_capability(self.handshake.capability());
+        handler.com_change_user(session).await;
+        self.packets
+            .write_ok_packet(0, 0, self.handshake.status_flag(), 0)
+            .await?;
+        Ok(())
+    }
+
+    /// Select the database sent with the credentials, see Connection::init_db.
+    async fn init_db(
+        &mut self,
+        handler: &dyn AsyncHandler,
+        session: &mut Session,
+    ) -> ProtoResult<()> {
+        let schema = self.handshake.database().to_string();
+        if schema.is_empty() {
+            return Ok(());
+        }
+        if !handler.com_init_db(session, &schema).await {
+            self.packets
+                .write_packet(bad_db_packet(&schema)?.as_slice())
+                .await?;
+            return Err(ProtoError::BadDb(schema));
+        }
+        session.set_schema(&schema);
+        Ok(())
+    }
+
+    /// Carry out the steps of the handshake, see Connection::authenticate.
+    async fn authenticate(
+        &mut self,
+        handler: &dyn AsyncHandler,
+        addr: &SocketAddr,
+        mut step: AuthStep,
+    ) -> ProtoResult<()> {
+        loop {
+            step = match step {
+                AuthStep::Ask(pkg) => {
+                    self.packets.write_packet(pkg.as_slice()).await?;
+                    let answer = self.packets.read_packet().await?;
+                    self.handshake.answer(answer)?
+                }
+                AuthStep::Tls(acceptor) => {
+                    self.upgrade_tls(&acceptor).await?;
+                    let pkg = self.packets.read_packet().await?;
+                    self.handshake.answer(pkg)?
+                }
+                AuthStep::CheckAuth => {
+                    let allowed = handler
+                        .check_auth(self.handshake.auth(), self.handshake.salt(), addr)
+                        .await;
+                    self.handshake.checked(allowed)?
+                }
+                AuthStep::Dialog => {
+                    let mut dialog = Dialog {
+                        packets: &mut self.packets,
+                        first: true,
+                    };
+                    let allowed = handler
+                        .auth_dialog(
+                            self.handshake.auth(),
+                            self.handshake.salt(),
+                            addr,
+                            &mut dialog,
+                        )
+                        .await;
+                    self.handshake.checked(allowed)?
+                }
+                AuthStep::Done(pkg) => {
+                    if let Some(pkg) = pkg {
+                        self.packets.write_packet(pkg.as_slice()).await?;
+                    }
+                    return Ok(());
+                }
+                AuthStep::Fail(pkg, err) => {
+                    self.packets.write_packet(pkg.as_slice()).await?;
+                    return Err(err);
+                }
+            };
+        }
+    }
+
+    /// Wrap the stream with TLS, the client sends the real handshake response
+    /// over the encrypted channel.
+    async fn upgrade_tls(&mut self, acceptor: &TlsAcceptor) -> ProtoResult<()> {
+        let acceptor = tokio_native_tls::TlsAcceptor::from(acceptor.clone());
+        let stream = self.packets.take_stream().expect("Stream is empty");
+        let stream = acceptor.accept(stream).await?;
+        self.packets.set_stream(Box::new(stream));
+        self.handshake.set_secure();
+        Ok(())
+    }
+}
+
+/// Dialog sends the questions of AsyncHandler::auth_dialog to the client.
+pub struct Dialog<'a> {
+    packets: &'a mut AsyncPackets,
+    first: bool,
+}
+
+impl Dialog<'_> {
+    /// Send a prompt to the client and return its answer, password tells the
+    /// client not to echo the answer and last marks the last question.
+    pub async fn ask(&mut self, prompt: &str, password: bool, last: bool) -> io::Result<String> {
+        let pkg = dialog_question_packet(self.first, prompt, password, last)?;
+        self.first = false;
+        self.packets.write_packet(pkg.as_slice()).await?;
+        let answer = self
+            .packets
+            .read_packet()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        Ok(String::from_utf8_lossy(&trim_nul(answer)).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread::{self, JoinHandle};
+
+    use crate::constants::{
+        DEFAULT_CLIENT_CAPABILITY, ERR_PACKET, MYSQL_CLEAR_PASSWORD, MYSQL_DIALOG,
+        MYSQL_NATIVE_PASSWORD, OK_PACKET,
+    };
+    use crate::errors::{ProtoError, ProtoResult};
+    use crate::proto::async_connection::{AsyncConnection, Dialog};
+    use crate::proto::async_packets::ResultWriter;
+    use crate::proto::packets::Packets;
+    use crate::proto::{
+        parse_auth_more_data, parse_auth_switch_request, verify_native_password, AsyncHandler,
+        Auth, Greeting, Session,
+    };
+
+    use async_trait::async_trait;
+
+    struct MockHandler {}
+
+    #[async_trait]
+    impl AsyncHandler for MockHandler {
+        async fn new_connection(&self, _session: &mut Session) {}
+        async fn close_connection(&self, _session: &mut Session) {}
+        async fn com_query(
+            &self,
+            _session: &mut Session,
+            _sql: &str,
+            _results: &mut ResultWriter<'_>,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn check_auth(&self, auth: &Auth, salt: &[u8], _addr: &SocketAddr) -> bool {
+            auth.user() == "root" && verify_native_password(auth.auth_response(), salt, "password")
+        }
+        async fn auth_dialog(
+            &self,
+            auth: &Auth,
+            _salt: &[u8],
+            _addr: &SocketAddr,
+            dialog: &mut Dialog<'_>,
+        ) -> bool {
+            auth.user() == "root"
+                && dialog.ask("Password: ", true, false).await.unwrap() == "password"
+                && dialog.ask("OTP: ", false, true).await.unwrap() == "123456"
+        }
+    }
+
+    /// Run the server handshake of a connection prepared by setup on a tokio
+    /// runtime in a thread, return the handshake result and the blocking
+    /// client side after it sent a mysql_native_password handshake response.
+    fn login<F>(
+        setup: F,
+        user: &str,
+        password: &str,
+    ) -> (JoinHandle<ProtoResult<Session>>, Packets)
+    where
+        F: FnOnce(&mut AsyncConnection) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_stream, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let mut conn = AsyncConnection::new(1, "8.0.0".to_string());
+                setup(&mut conn);
+                server_stream.set_nonblocking(true)?;
+                let stream = tokio::net::TcpStream::from_std(server_stream)?;
+                conn.packets.set_stream(Box::new(stream));
+                let addr = "127.0.0.1:3306".parse().unwrap();
+                conn.handshake(&MockHandler {}, &addr).await
+            })
+        });
+
+        let mut client = Packets::new();
+        client.set_stream(Box::new(client_stream));
+        let mut greeting = Greeting::default();
+        greeting
+            .parse_client_handshake_packet(&client.read_ephemeral_packet_direct().unwrap())
+            .unwrap();
+        let resp = Auth::write_handshake_resp(
+            DEFAULT_CLIENT_CAPABILITY,
+            33,
+            user.to_string(),
+            password.to_string(),
+            greeting.salt(),
+            MYSQL_NATIVE_PASSWORD,
+            "".to_string(),
+            &HashMap::new(),
+        )
+        .unwrap();
+        client.write_packet(&resp).unwrap();
+        (server, client)
+    }
+
+    #[test]
+    fn test_check_auth() {
+        let (server, mut client) = login(|_| {}, "root", "password");
+        assert_eq!(client.read_ephemeral_packet_direct().unwrap()[0], OK_PACKET);
+        assert!(server.join().unwrap().is_ok());
+
+        let (server, mut client) = login(|_| {}, "bob", "password");
+        let data = client.read_ephemeral_packet_direct().unwrap();
+        assert_eq!(data[0], ERR_PACKET);
+        assert!(
+            String::from_utf8_lossy(&data).contains("Access denied for user 'bob'@'127.0.0.1'")
+        );
+        match server.join().unwrap() {
+            Err(ProtoError::AccessDenied(user)) => assert_eq!(user, "bob"),
+            _ => panic!("Unexpected result"),
+        }
+
+        // mysql_clear_password is refused over an insecure connection.
+        let (server, mut client) = login(
+            |conn| conn.set_auth_plugin_name(MYSQL_CLEAR_PASSWORD),
+            "root",
+            "password",
+        );
+        assert_eq!(
+            client.read_ephemeral_packet_direct().unwrap()[0],
+            ERR_PACKET
+        );
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_dialog_auth() {
+        let setup = |conn: &mut AsyncConnection| {
+            conn.set_auth_plugin_name(MYSQL_DIALOG);
+            conn.set_allow_cleartext_passwords(true);
+        };
+        let (server, mut client) = login(setup, "root", "password");
+        let data = client.read_ephemeral_packet_direct().unwrap();
+        let (auth_plugin, question) = parse_auth_switch_request(&data).unwrap();
+        assert_eq!(auth_plugin, MYSQL_DIALOG);
+        assert_eq!(question, b"\x04Password: ".to_vec());
+        client.write_packet(b"password\0").unwrap();
+        let data = client.read_ephemeral_packet_direct().unwrap();
+        assert_eq!(parse_auth_more_data(&data).unwrap(), b"\x03OTP: \0");
+        client.write_packet(b"123456\0").unwrap();
+        assert_eq!(client.read_ephemeral_packet_direct().unwrap()[0], OK_PACKET);
+        assert!(server.join().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,489 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::constants::{MYSQL_CLEAR_PASSWORD, MYSQL_NATIVE_PASSWORD};
+use crate::errors::ProtoResult;
+use crate::proto::listener::check_auth_plugin_name;
+use crate::proto::{
+    AsyncConnection, Auth, Dialog, PrepareData, ProcessInfo, Registry, ResultWriter, Session,
+    Sha2Cache, ShutdownHandle, TlsConfig,
+};
+use crate::sql_type::Field;
+
+use async_trait::async_trait;
+use dakv_logger::prelude::*;
+use native_tls::TlsAcceptor;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// AsyncHandler is the async counterpart of Handler, implement it with the
+/// async_trait attribute.
+#[async_trait]
+pub trait AsyncHandler: Send + Sync {
+    // new_connection is called once a connection is authenticated, with the
+    // session of the connection, see Handler::new_connection.
+    async fn new_connection(&self, session: &mut Session);
+    // close_connection is called when a connection is closed.
+    async fn close_connection(&self, session: &mut Session);
+    // com_query is called when a connection receives a query, the results
+    // are sent with results.write.
+    async fn com_query(
+        &self,
+        session: &mut Session,
+        sql: &str,
+        results: &mut ResultWriter<'_>,
+    ) -> io::Result<()>;
+    // com_prepare is called when a connection receives a prepared statement.
+    // It returns the column definitions of the statement result, which is
+    // empty if the statement returns no rows.
+    async fn com_prepare(
+        &self,
+        _session: &mut Session,
+        _sql: &str,
+        _params_count: u16,
+    ) -> io::Result<Vec<Field>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Prepared statements are not supported",
+        ))
+    }
+    // com_stmt_execute is called when a connection executes a prepared
+    // statement, with the parameters bound in prepare.params.
+    async fn com_stmt_execute(
+        &self,
+        _session: &mut Session,
+        _prepare: &PrepareData,
+        _results: &mut ResultWriter<'_>,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Prepared statements are not supported",
+        ))
+    }
+    // com_field_list is called when a connection receives COM_FIELD_LIST,
+    // see Handler::com_field_list.
+    async fn com_field_list(
+        &self,
+        _session: &mut Session,
+        _table: &str,
+        _wildcard: &str,
+    ) -> io::Result<Vec<Field>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "COM_FIELD_LIST is not supported",
+        ))
+    }
+    // com_init_db is called when the client selects the default database,
+    // see Handler::com_init_db.
+    async fn com_init_db(&self, _session: &mut Session, _schema: &str) -> bool {
+        true
+    }
+    // com_change_user is called once COM_CHANGE_USER authenticated the
+    // connection as another user, see Handler::com_change_user.
+    async fn com_change_user(&self, _session: &mut Session) {}
+    // com_reset_connection is called when a connection receives
+    // COM_RESET_CONNECTION, see Handler::com_reset_connection.
+    async fn com_reset_connection(&self, _session: &mut Session) {}
+    // check_kill is called when a connection runs KILL or COM_PROCESS_KILL,
+    // see Handler::check_kill.
+    async fn check_kill(&self, session: &mut Session, _connection_id: u32, user: &str) -> bool {
+        session.user() == user
+    }
+    // processlist is called on SHOW [FULL] PROCESSLIST and COM_PROCESS_INFO,
+    // see Handler::processlist.
+    async fn processlist(
+        &self,
+        session: &mut Session,
+        processes: Vec<ProcessInfo>,
+    ) -> io::Result<Vec<ProcessInfo>> {
+        Ok(processes
+            .into_iter()
+            .filter(|process| process.user == session.user())
+            .collect())
+    }
+
+    // check_auth is called once the client handshake response is parsed,
+    // see Handler::check_auth.
+    async fn check_auth(&self, auth: &Auth, salt: &[u8], addr: &SocketAddr) -> bool;
+    // auth_dialog is called when the server authenticates with the dialog
+    // plugin, the questions are sent with dialog.ask, see Handler::auth_dialog.
+    // By default it asks for the password and calls check_auth with it.
+    async fn auth_dialog(
+        &self,
+        auth: &Auth,
+        salt: &[u8],
+        addr: &SocketAddr,
+        dialog: &mut Dialog<'_>,
+    ) -> bool {
+        let password = match dialog.ask("", true, true).await {
+            Ok(password) => password,
+            Err(_) => return false,
+        };
+        let mut auth = auth.clone();
+        auth.set_auth_response(MYSQL_CLEAR_PASSWORD, password.into_bytes());
+        self.check_auth(&auth, salt, addr).await
+    }
+}
+
+/// AsyncListener accepts the connections on a tokio runtime, every connection
+/// is handled by a task instead of a thread.
+pub struct AsyncListener {
+    listener: TcpListener,
+    connection_id: u32,
+    server_version: String,
+    // registry tracks the connections to kill them and to drain them on shutdown.
+    registry: Arc<Registry>,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    // Reject the clients that do not upgrade the connection to TLS.
+    require_secure_transport: bool,
+    // auth_plugin_name is the auth plugin advertised in the greeting.
+    auth_plugin_name: String,
+    allow_cleartext_passwords: bool,
+    auth_cache: Arc<Sha2Cache>,
+}
+
+impl AsyncListener {
+    pub async fn new_tcp_listener<Addr: ToSocketAddrs>(addr: Addr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(AsyncListener {
+            listener,
+            connection_id: 0,
+            server_version: "5.7.0".to_string(),
+            registry: Arc::new(Registry::new()),
+            tls_acceptor: None,
+            require_secure_transport: false,
+            auth_plugin_name: MYSQL_NATIVE_PASSWORD.to_string(),
+            allow_cleartext_passwords: false,
+            auth_cache: Arc::new(Sha2Cache::new()),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Enable TLS, clients sending an SSLRequest are upgraded with the
+    /// certificate of config.
+    pub fn set_tls_config(&mut self, config: &TlsConfig) -> ProtoResult<()> {
+        self.tls_acceptor = Some(Arc::new(config.build_acceptor()?));
+        Ok(())
+    }
+
+    pub fn set_require_secure_transport(&mut self, require: bool) {
+        self.require_secure_transport = require;
+    }
+
+    /// Set the auth plugin the clients authenticate with, see
+    /// Listener::set_auth_plugin_name.
+    pub fn set_auth_plugin_name(&mut self, auth_plugin_name: &str) -> ProtoResult<()> {
+        check_auth_plugin_name(auth_plugin_name)?;
+        self.auth_plugin_name = auth_plugin_name.to_string();
+        Ok(())
+    }
+
+    /// Let mysql_clear_password and dialog run over connections not upgraded
+    /// to TLS, they are refused by default.
+    pub fn set_allow_cleartext_passwords(&mut self, allow: bool) {
+        self.allow_cleartext_passwords = allow;
+    }
+
+    /// Return the cache of the caching_sha2_password authentications, users
+    /// should be removed from it when their password changes.
+    pub fn auth_cache(&self) -> Arc<Sha2Cache> {
+        self.auth_cache.clone()
+    }
+
+    /// Return a handle to shut the listener down from another thread.
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        Ok(ShutdownHandle::new(
+            self.registry.clone(),
+            self.local_addr()?,
+        ))
+    }
+
+    /// Serve the connections until the listener is shut down, the connections
+    /// are drained before it returns, see Listener::accept.
+    pub async fn accept(&mut self, handler: Arc<dyn AsyncHandler>) {
+        debug!("Start server ...");
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Accept connection failed: {}", e);
+                    continue;
+                }
+            };
+            if self.registry.is_shutdown() {
+                break;
+            }
+            let connection_id = self.connection_id;
+            self.connection_id += 1;
+            // Registered before the task starts, so that a shutdown right
+            // after waits for it.
+            let stream = match register(&self.registry, connection_id, stream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Clone stream failed: {}", e);
+                    continue;
+                }
+            };
+            let mut conn = AsyncConnection::new(connection_id, self.server_version.clone());
+            conn.set_tls_acceptor(self.tls_acceptor.clone());
+            conn.set_require_secure_transport(self.require_secure_transport);
+            conn.set_auth_plugin_name(&self.auth_plugin_name);
+            conn.set_allow_cleartext_passwords(self.allow_cleartext_passwords);
+            conn.set_auth_cache(self.auth_cache.clone());
+            conn.set_registry(self.registry.clone());
+            let handler = handler.clone();
+            let registry = self.registry.clone();
+            tokio::spawn(async move {
+                conn.handle(stream, handler).await;
+                registry.unregister(connection_id);
+            });
+        }
+        let registry = self.registry.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || registry.drain()).await {
+            error!("Drain connections failed: {}", e);
+        }
+        debug!("Server stopped");
+    }
+}
+
+/// Register a connection with a blocking clone of its socket, the registry
+/// closes it to wake the connection up.
+fn register(registry: &Registry, id: u32, stream: TcpStream) -> io::Result<TcpStream> {
+    let stream = stream.into_std()?;
+    registry.register(id, stream.try_clone()?);
+    TcpStream::from_std(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use crate::constants::{DEFAULT_CLIENT_CAPABILITY, ERR_PACKET, OK_PACKET};
+    use crate::proto::packets::Packets;
+    use crate::proto::{
+        AsyncHandler, AsyncListener, Auth, Greeting, ResultWriter, Session, ShutdownHandle,
+    };
+    use crate::sql_type::SqlResult;
+
+    use async_trait::async_trait;
+
+    #[derive(Default)]
+    struct CountHandler {
+        closed: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl AsyncHandler for CountHandler {
+        async fn new_connection(&self, _session: &mut Session) {}
+        async fn close_connection(&self, _session: &mut Session) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+        async fn com_query(
+            &self,
+            _session: &mut Session,
+            _sql: &str,
+            results: &mut ResultWriter<'_>,
+        ) -> io::Result<()> {
+            results
+                .write(SqlResult {
+                    affected_rows: 3,
+                    ..Default::default()
+                })
+                .await
+        }
+        async fn check_auth(&self, _auth: &Auth, _salt: &[u8], _addr: &SocketAddr) -> bool {
+            true
+        }
+    }
+
+    struct KillHandler {
+        started: Mutex<mpsc::Sender<()>>,
+        closed: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl AsyncHandler for KillHandler {
+        async fn new_connection(&self, _session: &mut Session) {}
+        async fn close_connection(&self, _session: &mut Session) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+        async fn com_query(
+            &self,
+            session: &mut Session,
+            sql: &str,
+            results: &mut ResultWriter<'_>,
+        ) -> io::Result<()> {
+            if sql == "SLEEP" {
+                self.started.lock().unwrap().send(()).unwrap();
+                while !session.cancel_token().is_cancelled() {
+                    tokio::task::spawn_blocking(|| thread::sleep(Duration::from_millis(10)))
+                        .await
+                        .unwrap();
+                }
+                return Err(io::Error::new(io::ErrorKind::Other, "Cancelled"));
+            }
+            results
+                .write(SqlResult {
+                    affected_rows: 1,
+                    ..Default::default()
+                })
+                .await
+        }
+        async fn check_auth(&self, _auth: &Auth, _salt: &[u8], _addr: &SocketAddr) -> bool {
+            true
+        }
+    }
+
+    /// Run a listener on a tokio runtime in a thread, return its address and
+    /// its shutdown handle.
+    fn serve(handler: Arc<dyn AsyncHandler>) -> (SocketAddr, ShutdownHandle, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let mut listener = AsyncListener::new_tcp_listener("127.0.0.1:0")
+                    .await
+                    .unwrap();
+                tx.send((
+                    listener.local_addr().unwrap(),
+                    listener.shutdown_handle().unwrap(),
+                ))
+                .unwrap();
+                listener.accept(handler).await
+            })
+        });
+        let (addr, shutdown) = rx.recv().unwrap();
+        (addr, shutdown, server)
+    }
+
+    /// Log in with a blocking client.
+    fn login(addr: SocketAddr, user: &str) -> Packets {
+        let mut client = Packets::new();
+        client.set_stream(Box::new(TcpStream::connect(addr).unwrap()));
+        let mut greeting = Greeting::default();
+        greeting
+            .parse_client_handshake_packet(&client.read_ephemeral_packet_direct().unwrap())
+            .unwrap();
+        let resp = Auth::write_handshake_resp(
+            DEFAULT_CLIENT_CAPABILITY,
+            33,
+            user.to_string(),
+            "".to_string(),
+            greeting.salt(),
+            greeting.auth_plugin_name(),
+            "".to_string(),
+            &HashMap::new(),
+        )
+        .unwrap();
+        client.write_packet(&resp).unwrap();
+        assert_eq!(client.read_ephemeral_packet_direct().unwrap()[0], OK_PACKET);
+        client
+    }
+
+    /// Send a command and return the first packet of the answer.
+    fn command(client: &mut Packets, data: &[u8]) -> Vec<u8> {
+        client.reset_sequence_id();
+        client.write_packet(data).unwrap();
+        client.read_ephemeral_packet_direct().unwrap()
+    }
+
+    /// Log in with a blocking client and run a query.
+    fn query(addr: SocketAddr) {
+        let mut client = login(addr, "root");
+        let data = command(&mut client, b"\x03UPDATE t SET a = 1");
+        assert_eq!(data[..2], [OK_PACKET, 3]);
+        client.reset_sequence_id();
+        client.write_packet(&[0x01]).unwrap();
+    }
+
+    #[test]
+    fn test_accept() {
+        let handler = Arc::new(CountHandler::default());
+        let (tx, rx) = mpsc::channel();
+        let server_handler = handler.clone();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let mut listener = AsyncListener::new_tcp_listener("127.0.0.1:0")
+                    .await
+                    .unwrap();
+                tx.send(listener.local_addr().unwrap()).unwrap();
+                listener.accept(server_handler).await
+            })
+        });
+        let addr = rx.recv().unwrap();
+
+        let clients: Vec<_> = (0..4).map(|_| thread::spawn(move || query(addr))).collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        for _ in 0..100 {
+            if handler.closed.load(Ordering::SeqCst) == 4 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Connections are not closed");
+    }
+
+    #[test]
+    fn test_kill() {
+        let (tx, rx) = mpsc::channel();
+        let handler = Arc::new(KillHandler {
+            started: Mutex::new(tx),
+            closed: AtomicUsize::new(0),
+        });
+        let (addr, shutdown, server) = serve(handler.clone());
+
+        // Connection ids are given in order.
+        let mut busy = login(addr, "root");
+        let mut killer = login(addr, "root");
+        let mut idle = login(addr, "root");
+        let mut other = login(addr, "bob");
+
+        // KILL QUERY interrupts the query, the connection stays.
+        busy.reset_sequence_id();
+        busy.write_packet(b"\x03SLEEP").unwrap();
+        rx.recv().unwrap();
+        assert_eq!(command(&mut killer, b"\x03KILL QUERY 0")[0], OK_PACKET);
+        let data = busy.read_ephemeral_packet_direct().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0x25, 0x05]);
+        assert_eq!(command(&mut busy, b"\x03UPDATE t SET a = 1")[..2], [
+            OK_PACKET, 1
+        ]);
+
+        // Users may only kill their own connections.
+        let data = command(&mut other, &[0x0c, 0, 0, 0, 0]);
+        assert_eq!(data[..3], [ERR_PACKET, 0x47, 0x04]);
+        // COM_PROCESS_INFO lists the connections of the user.
+        assert_eq!(command(&mut other, &[0x0a]), vec![8]);
+
+        // An idle connection is closed right away.
+        assert_eq!(command(&mut killer, &[0x0c, 2, 0, 0, 0])[0], OK_PACKET);
+        assert!(idle.read_ephemeral_packet_direct().is_err());
+
+        // On shutdown the idle clients are told that the server shuts down.
+        shutdown.shutdown(Duration::from_secs(5));
+        busy.reset_sequence_id();
+        let data = busy.read_ephemeral_packet_direct().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0x1d, 0x04]);
+        server.join().unwrap();
+        assert_eq!(handler.closed.load(Ordering::SeqCst), 4);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,524 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::constants::{MAX_PACKET_SIZE, OK_PACKET};
+use crate::errors::{ProtoError, ProtoResult, SqlError};
+use crate::proto::command::{Command, Commands, QueryStatement};
+use crate::proto::packets::{
+    bad_db_packet, end_result_packet, eof_packet, err_packet, exec_err_packet, frame_packet,
+    interrupted, ok_packet_with_header, parse_packet_header, processlist_result, sql_err_packet,
+    ResultEncoder,
+};
+use crate::proto::prepare::PrepareData;
+use crate::proto::registry::Registry;
+use crate::proto::{AsyncHandler, Session};
+use crate::sql_type::SqlResult;
+
+use dakv_logger::prelude::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub trait AsyncReadAndWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> AsyncReadAndWrite for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// AsyncPackets is the async counterpart of Packets, the packets are encoded
+/// by the same functions.
+pub struct AsyncPackets {
+    sequence_id: u8,
+    stream: Option<Box<dyn AsyncReadAndWrite>>,
+    // State of the commands, shared with the blocking connections.
+    commands: Commands,
+}
+
+impl Default for AsyncPackets {
+    fn default() -> Self {
+        AsyncPackets::new()
+    }
+}
+
+impl AsyncPackets {
+    pub fn new() -> Self {
+        AsyncPackets {
+            sequence_id: 0,
+            stream: None,
+            commands: Commands::new(),
+        }
+    }
+
+    pub fn set_stream(&mut self, stream: Box<dyn AsyncReadAndWrite>) {
+        self.stream = Some(stream);
+    }
+
+    /// Let the connection kill the other connections of the registry.
+    pub fn set_registry(&mut self, registry: Arc<Registry>) {
+        self.commands.set_registry(registry);
+    }
+
+    pub fn take_stream(&mut self) -> Option<Box<dyn AsyncReadAndWrite>> {
+        self.stream.take()
+    }
+
+    /// Set the capabilities negotiated during the handshake, COM_SET_OPTION
+    /// may toggle multi statements afterwards.
+    pub fn set_capability(&mut self, capability: u32) {
+        self.commands.set_capability(capability);
+    }
+
+    /// Restart the sequence id, every command starts a new sequence.
+    pub fn reset_sequence_id(&mut self) {
+        self.sequence_id = 0;
+    }
+
+    fn stream(&mut self) -> &mut Box<dyn AsyncReadAndWrite> {
+        self.stream.as_mut().expect("Stream is empty")
+    }
+
+    /// Read a packet, the payloads larger than MAX_PACKET_SIZE are joined.
+    pub async fn read_packet(&mut self) -> ProtoResult<Vec<u8>> {
+        let mut data = self.read_one_packet().await?;
+        if data.len() < MAX_PACKET_SIZE {
+            return Ok(data);
+        }
+        loop {
+            let next = self.read_one_packet().await?;
+            let length = next.len();
+            data.extend_from_slice(next.as_slice());
+            if length < MAX_PACKET_SIZE {
+                break;
+            }
+        }
+        Ok(data)
+    }
+
+    async fn read_one_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut header = [0; 4];
+        self.stream().read_exact(&mut header).await?;
+        let length = parse_packet_header(&header, &mut self.sequence_id)?;
+        let mut data = vec![0; length];
+        self.stream().read_exact(data.as_mut_slice()).await?;
+        Ok(data)
+    }
+
+    pub async fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let buf = frame_packet(data, &mut self.sequence_id);
+        let stream = self.stream();
+        stream.write_all(buf.as_slice()).await?;
+        stream.flush().await
+    }
+
+    async fn write_packets(&mut self, packets: Vec<Vec<u8>>) -> io::Result<()> {
+        for pkg in packets {
+            self.write_packet(pkg.as_slice()).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn write_ok_packet(
+        &mut self,
+        affected_rows: u64,
+        last_insert_id: u64,
+        flags: u16,
+        warnings: u16,
+    ) -> io::Result<()> {
+        let pkg =
+            ok_packet_with_header(OK_PACKET, affected_rows, last_insert_id, flags, warnings)?;
+        self.write_packet(pkg.as_slice()).await
+    }
+
+    pub async fn write_eof_packet(&mut self, flags: u16, warnings: u16) -> io::Result<()> {
+        let pkg = eof_packet(flags, warnings)?;
+        self.write_packet(pkg.as_slice()).await
+    }
+
+    pub async fn write_end_result(
+        &mut self,
+        affected_rows: u64,
+        last_insert_id: u64,
+        warnings: u16,
+    ) -> io::Result<()> {
+        let pkg = end_result_packet(
+            self.commands.capability(),
+            self.commands.status_flags(),
+            affected_rows,
+            last_insert_id,
+            warnings,
+        )?;
+        self.write_packet(pkg.as_slice()).await
+    }
+
+    /// Report err to the client, see SqlError for the code and the state of
+    /// each error.
+    pub async fn write_err_packet_from_err(&mut self, err: &ProtoError) -> io::Result<()> {
+        let pkg = sql_err_packet(&SqlError::from(err))?;
+        self.write_packet(pkg.as_slice()).await
+    }
+
+    pub async fn write_err_packet(
+        &mut self,
+        err_code: u16,
+        sql_state: String,
+        err_msg: String,
+    ) -> io::Result<()> {
+        let pkg = err_packet(err_code, sql_state, err_msg)?;
+        self.write_packet(pkg.as_slice()).await
+    }
+
+    pub async fn handle_next_command(
+        &mut self,
+        handler: &Arc<dyn AsyncHandler>,
+        session: &mut Session,
+        status_flags: u16,
+    ) -> ProtoResult<()> {
+        let data = self.read_command().await?;
+        self.handle_command(handler, session, status_flags, data.as_slice())
+            .await
+    }
+
+    /// Read the next command, which starts a new sequence.
+    pub async fn read_command(&mut self) -> ProtoResult<Vec<u8>> {
+        self.sequence_id = 0;
+        self.read_packet().await
+    }
+
+    /// Drop the prepared statements of the connection.
+    pub fn clear_prepares(&mut self) {
+        self.commands.clear_prepares();
+    }
+
+    pub async fn handle_command(
+        &mut self,
+        handler: &Arc<dyn AsyncHandler>,
+        session: &mut Session,
+        status_flags: u16,
+        data: &[u8],
+    ) -> ProtoResult<()> {
+        self.commands.set_status_flags(status_flags);
+        match self.commands.decode(data)? {
+            Command::Quit => return Err(ProtoError::ComQuit),
+            Command::InitDb(db) => {
+                let pkg = if handler.com_init_db(session, &db).await {
+                    session.set_schema(&db);
+                    self.commands.ok_packet()?
+                } else {
+                    bad_db_packet(&db)?
+                };
+                self.write_packet(pkg.as_slice()).await?;
+            }
+            Command::ProcessInfo => {
+                if let Err(e) = self.processlist(handler, session, false, false).await {
+                    self.write_exec_err(e).await?;
+                }
+            }
+            Command::ProcessKill(id) => {
+                if let Err(e) = self.kill(handler, session, id, false, false).await {
+                    self.write_exec_err(e).await?;
+                }
+            }
+            Command::FieldList(table, wildcard) => {
+                match handler.com_field_list(session, &table, &wildcard).await {
+                    Ok(fields) => {
+                        let packets = self.commands.field_list(&fields)?;
+                        self.write_packets(packets).await?;
+                    }
+                    Err(e) => self.write_exec_err(e.into()).await?,
+                }
+            }
+            Command::ResetConnection => {
+                // The schema and the user stay, the other state is dropped.
+                session.extensions_mut().clear();
+                handler.com_reset_connection(session).await;
+                let pkg = self.commands.ok_packet()?;
+                self.write_packet(pkg.as_slice()).await?;
+            }
+            Command::Query(statements) => {
+                let length = statements.len();
+                for (index, sql) in statements.iter().enumerate() {
+                    debug!("sql:{}", sql);
+                    let more = index != length - 1;
+                    // An error ends the batch, the remaining statements are not run.
+                    if let Err(e) = self.exec_statement(handler, session, sql, more).await {
+                        self.write_exec_err(e).await?;
+                        break;
+                    }
+                }
+            }
+            Command::Prepare(prepare) => {
+                match handler
+                    .com_prepare(session, &prepare.prepare_stmt, prepare.params_count)
+                    .await
+                {
+                    Ok(fields) => {
+                        let packets = self.commands.prepared(prepare, &fields)?;
+                        self.write_packets(packets).await?;
+                    }
+                    Err(e) => self.write_exec_err(e.into()).await?,
+                }
+            }
+            Command::Execute(prepare) => {
+                if let Err(e) = self.exec_stmt(handler, session, &prepare).await {
+                    self.write_exec_err(e).await?;
+                }
+            }
+            Command::Reply(packets) => self.write_packets(packets).await?,
+        }
+        Ok(())
+    }
+
+    /// Run a statement of a query, see Packets::exec_statement.
+    async fn exec_statement(
+        &mut self,
+        handler: &Arc<dyn AsyncHandler>,
+        session: &mut Session,
+        sql: &str,
+        more: bool,
+    ) -> ProtoResult<()> {
+        match self.commands.statement(sql) {
+            QueryStatement::Kill(id, query_only) => {
+                self.kill(handler, session, id, query_only, more).await
+            }
+            QueryStatement::Processlist(full) => {
+                self.processlist(handler, session, full, more).await
+            }
+            QueryStatement::Query => self.exec_query(handler, session, sql, more).await,
+        }
+    }
+
+    async fn exec_query(
+        &mut self,
+        handler: &Arc<dyn AsyncHandler>,
+        session: &mut Session,
+        sql: &str,
+        more: bool,
+    ) -> ProtoResult<()> {
+        let mut results = ResultWriter::new(self, more, false);
+        let result = match handler.com_query(session, sql, &mut results).await {
+            Ok(()) => results.finish().await,
+            Err(e) => Err(e.into()),
+        };
+        interrupted(result, session)
+    }
+
+    async fn exec_stmt(
+        &mut self,
+        handler: &Arc<dyn AsyncHandler>,
+        session: &mut Session,
+        prepare: &PrepareData,
+    ) -> ProtoResult<()> {
+        let mut results = ResultWriter::new(self, false, true);
+        let result = match handler
+            .com_stmt_execute(session, prepare, &mut results)
+            .await
+        {
+            Ok(()) => results.finish().await,
+            Err(e) => Err(e.into()),
+        };
+        interrupted(result, session)
+    }
+
+    /// SHOW [FULL] PROCESSLIST, see Packets::processlist.
+    async fn processlist(
+        &mut self,
+        handler: &Arc<dyn AsyncHandler>,
+        session: &mut Session,
+        full: bool,
+        more: bool,
+    ) -> ProtoResult<()> {
+        let processes = handler
+            .processlist(session, self.commands.processes())
+            .await?;
+        let mut results = ResultWriter::new(self, more, false);
+        results.write(processlist_result(&processes, full)).await?;
+        results.finish().await
+    }
+
+    /// KILL [CONNECTION | QUERY] id, see Packets::kill.
+    async fn kill(
+        &mut self,
+        handler: &Arc<dyn AsyncHandler>,
+        session: &mut Session,
+        id: u32,
+        query_only: bool,
+        more: bool,
+    ) -> ProtoResult<()> {
+        let user = self.commands.kill_target(id)?;
+        if !handler.check_kill(session, id, &user).await {
+            return Err(ProtoError::KillDenied(id));
+        }
+        self.commands.kill(id, query_only);
+        ResultWriter::new(self, more, false).finish().await
+    }
+
+    /// Answer the error returned by a handler with an ERR packet,
+    /// other errors close the connection.
+    async fn write_exec_err(&mut self, err: ProtoError) -> ProtoResult<()> {
+        let pkg = exec_err_packet(err)?;
+        self.write_packet(pkg.as_slice()).await?;
+        Ok(())
+    }
+}
+
+/// ResultWriter streams the results of a statement to the client, it takes
+/// the place of the callback of the blocking Handler.
+pub struct ResultWriter<'a> {
+    packets: &'a mut AsyncPackets,
+    encoder: ResultEncoder,
+}
+
+impl<'a> ResultWriter<'a> {
+    fn new(packets: &'a mut AsyncPackets, more: bool, binary: bool) -> Self {
+        let encoder = packets.commands.encoder(more, binary);
+        ResultWriter { packets, encoder }
+    }
+
+    /// Send a result, the first one is either an OK packet or the fields of
+    /// a result set, the next ones only add rows.
+    pub async fn write(&mut self, qr: SqlResult) -> io::Result<()> {
+        let packets = self.encoder.encode(qr)?;
+        self.packets.write_packets(packets).await
+    }
+
+    async fn finish(self) -> ProtoResult<()> {
+        if let Some(pkg) = self.encoder.finish()? {
+            self.packets.write_packet(pkg.as_slice()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::{
+        DEFAULT_SERVER_CAPABILITY, EOF_PACKET, ERR_PACKET, OK_PACKET, SERVER_MORE_RESULTS_EXISTS,
+    };
+    use crate::errors::ProtoError;
+    use crate::proto::async_packets::{AsyncPackets, ResultWriter};
+    use crate::proto::{AsyncHandler, Auth, Session};
+    use crate::sql_type::{Field, MysqlType, SqlResult, Type, Value};
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+
+    struct MockHandler {}
+
+    #[async_trait]
+    impl AsyncHandler for MockHandler {
+        async fn new_connection(&self, _session: &mut Session) {}
+        async fn close_connection(&self, _session: &mut Session) {}
+        async fn com_query(
+            &self,
+            _session: &mut Session,
+            sql: &str,
+            results: &mut ResultWriter<'_>,
+        ) -> io::Result<()> {
+            match sql {
+                "SELECT 1" => {
+                    results
+                        .write(SqlResult {
+                            fields: vec![Field {
+                                name: "1".to_string(),
+                                typ: MysqlType::Int64 as Type,
+                                ..Default::default()
+                            }],
+                            rows: vec![vec![Value {
+                                typ: MysqlType::Int64 as Type,
+                                val: b"1".to_vec(),
+                            }]],
+                            ..Default::default()
+                        })
+                        .await
+                }
+                "UPDATE t SET a = 1" => {
+                    results
+                        .write(SqlResult {
+                            affected_rows: 1,
+                            ..Default::default()
+                        })
+                        .await
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Unknown statement: {}", sql),
+                )),
+            }
+        }
+        async fn check_auth(&self, _auth: &Auth, _salt: &[u8], _addr: &SocketAddr) -> bool {
+            true
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Return a client and a server connected by an in-memory pipe.
+    fn pair() -> (AsyncPackets, AsyncPackets) {
+        let (client_stream, server_stream) = tokio::io::duplex(1 << 25);
+        let mut client = AsyncPackets::new();
+        client.set_stream(Box::new(client_stream));
+        let mut server = AsyncPackets::new();
+        server.set_stream(Box::new(server_stream));
+        (client, server)
+    }
+
+    #[test]
+    fn test_basic() {
+        block_on(async {
+            let (mut client, mut server) = pair();
+            server.write_ok_packet(12, 34, 56, 78).await.unwrap();
+            let data = client.read_packet().await.unwrap();
+            assert_eq!(data, vec![OK_PACKET, 12, 34, 56, 0, 78, 0]);
+
+            // A payload of MAX_PACKET_SIZE is followed by an empty packet.
+            let payload = vec![1; (1 << 24) - 1];
+            server.write_packet(&payload).await.unwrap();
+            assert_eq!(client.read_packet().await.unwrap(), payload);
+        });
+    }
+
+    #[test]
+    fn test_multi_statements() {
+        block_on(async {
+            let (mut client, mut server) = pair();
+            server.set_capability(DEFAULT_SERVER_CAPABILITY);
+            let handler: Arc<dyn AsyncHandler> = Arc::new(MockHandler {});
+            let mut session = Session::new(1, "127.0.0.1:3306".parse().unwrap());
+
+            client
+                .write_packet(b"\x03SELECT 1; UPDATE t SET a = 1; BAD")
+                .await
+                .unwrap();
+            server
+                .handle_next_command(&handler, &mut session, 0)
+                .await
+                .unwrap();
+            assert_eq!(client.read_packet().await.unwrap(), vec![1]);
+            client.read_packet().await.unwrap();
+            assert_eq!(client.read_packet().await.unwrap(), vec![1, b'1']);
+            let data = client.read_packet().await.unwrap();
+            assert_eq!(data[0], EOF_PACKET);
+            assert_eq!(
+                u16::from_le_bytes([data[3], data[4]]),
+                SERVER_MORE_RESULTS_EXISTS
+            );
+            let data = client.read_packet().await.unwrap();
+            assert_eq!(data[..2], [OK_PACKET, 1]);
+            let data = client.read_packet().await.unwrap();
+            assert_eq!(data[0], ERR_PACKET);
+            assert!(String::from_utf8_lossy(&data).ends_with("Unknown statement: BAD"));
+
+            // COM_QUIT ends the connection.
+            client.sequence_id = 0;
+            client.write_packet(&[0x01]).await.unwrap();
+            match server.handle_next_command(&handler, &mut session, 0).await {
+                Err(ProtoError::ComQuit) => {}
+                _ => panic!("Unexpected result"),
+            }
+        });
+    }
+}
@@ -1,10 +1,16 @@
+use std::collections::HashMap;
 use std::fmt::{Display, Error, Formatter};
 use std::io::{BufRead, Cursor, Read, Write};
 use std::{cmp, convert, io};
 
-use crate::constants::CapabilityFlag;
-use crate::constants::MYSQL_NATIVE_PASSWORD;
+use crate::constants::{CapabilityFlag, PacketType};
+use crate::constants::{
+    AUTH_MORE_DATA_PACKET, CACHING_SHA2_PASSWORD, EOF_PACKET, MYSQL_CLEAR_PASSWORD,
+    MYSQL_NATIVE_PASSWORD,
+};
 use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::caching_sha2::gen_caching_sha2_password;
+use crate::proto::packets::{ReadLenEncode, WriteLenEncode};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use sha1::{Digest, Sha1};
@@ -20,6 +26,7 @@ use sha1::{Digest, Sha1};
 /// unknown     unknown     (auth response length) auth response
 /// unknown     unknown     database
 /// unknown     unknown     plugin name
+/// unknown     unknown     (attributes length) connection attributes
 
 #[derive(Debug, Clone, Default)]
 pub struct Auth {
@@ -30,6 +37,12 @@ pub struct Auth {
     auth_method: String,
     database: String,
     user: String,
+    // connection_attrs are the key/value pairs sent by clients supporting
+    // CLIENT_CONNECT_ATTRS, e.g. _client_name, _client_version or program_name.
+    connection_attrs: HashMap<String, String>,
+    // verified_from_cache is set when the caching_sha2_password scramble was
+    // verified against the cache of the listener, before check_auth is called.
+    verified_from_cache: bool,
 }
 
 /// Remove the boundary value that we don't want.
@@ -58,6 +71,8 @@ impl Auth {
             auth_method: "".to_string(),
             database: "".to_string(),
             user: "".to_string(),
+            connection_attrs: HashMap::new(),
+            verified_from_cache: false,
         }
     }
 
@@ -65,10 +80,18 @@ impl Auth {
         self.character_set
     }
 
+    pub fn capability_flags(&self) -> u32 {
+        self.capability_flags
+    }
+
     pub fn auth_response(&self) -> &Vec<u8> {
         &self.auth_response
     }
 
+    pub fn auth_method(&self) -> &String {
+        &self.auth_method
+    }
+
     pub fn database(&self) -> &String {
         &self.database
     }
@@ -77,23 +100,52 @@ impl Auth {
         &self.user
     }
 
+    pub fn connection_attrs(&self) -> &HashMap<String, String> {
+        &self.connection_attrs
+    }
+
+    /// Whether the scramble was verified against the caching_sha2_password
+    /// cache, the handler still checks the user and its address.
+    pub fn verified_from_cache(&self) -> bool {
+        self.verified_from_cache
+    }
+
+    pub fn set_verified_from_cache(&mut self, verified: bool) {
+        self.verified_from_cache = verified;
+    }
+
     pub fn clean_resp(&mut self) {
         self.auth_response.clear()
     }
 
+    /// Replace the auth response, e.g. by the clear text password received
+    /// during a full authentication.
+    pub fn set_auth_response(&mut self, auth_method: &str, auth_response: Vec<u8>) {
+        self.auth_method = auth_method.to_string();
+        self.auth_response = auth_response;
+        self.verified_from_cache = false;
+    }
+
     pub fn write_handshake_resp(
         mut capability_flag: u32,
         charset: u8,
         username: String,
         password: String,
         salt: &[u8],
+        auth_plugin: &str,
         database: String,
+        connection_attrs: &HashMap<String, String>,
     ) -> ProtoResult<Vec<u8>> {
         if !database.is_empty() {
             capability_flag |= CapabilityFlag::CapabilityClientConnectWithDB as u32;
         } else {
             capability_flag &= !(CapabilityFlag::CapabilityClientConnectWithDB as u32);
         }
+        if !connection_attrs.is_empty() {
+            capability_flag |= CapabilityFlag::CapabilityClientConnAttr as u32;
+        } else {
+            capability_flag &= !(CapabilityFlag::CapabilityClientConnAttr as u32);
+        }
         let mut buf = vec![];
         buf.write_u32::<LittleEndian>(capability_flag)
             .expect("Unable to write");
@@ -106,7 +158,7 @@ impl Auth {
         buf.write_all(username.as_bytes()).expect("Unable to write");
         buf.write_all(&[0; 1]).expect("Unable to write");
 
-        let auth_resp = gen_native_password(password, &salt);
+        let auth_resp = gen_auth_response(auth_plugin, &password, salt)?;
         if (capability_flag & CapabilityFlag::CapabilityClientSecureConnection as u32) > 0 {
             buf.write_u8(auth_resp.len() as u8)?;
             buf.write_all(auth_resp.as_slice())?;
@@ -119,8 +171,16 @@ impl Auth {
             buf.write_all(database.as_bytes())?;
             buf.write_u8(0).expect("Unable to write");
         }
-        buf.write_all(MYSQL_NATIVE_PASSWORD.as_bytes())?;
+        buf.write_all(auth_plugin.as_bytes())?;
         buf.write_u8(0).expect("Unable to write");
+        if (capability_flag & CapabilityFlag::CapabilityClientConnAttr as u32) > 0 {
+            let mut attrs = vec![];
+            for (key, value) in connection_attrs {
+                attrs.write_len_str(key.as_bytes())?;
+                attrs.write_len_str(value.as_bytes())?;
+            }
+            buf.write_len_str(attrs.as_slice())?;
+        }
         Ok(buf)
     }
 
@@ -170,7 +230,6 @@ impl Auth {
         {
             return Err(ProtoError::ReadZeroError);
         }
-        // todo tls server
         unsafe {
             // Parse user name
             payload
@@ -233,15 +292,215 @@ impl Auth {
             if self.auth_method.is_empty() {
                 self.auth_method = String::from(MYSQL_NATIVE_PASSWORD);
             }
-            // Decode connection attributes
-            if self.capability_flags & CapabilityFlag::CapabilityClientConnAttr as u32 != 0 {
-                // todo decode connection attributes
+            // Decode connection attributes, some clients set the flag without sending any.
+            if self.capability_flags & CapabilityFlag::CapabilityClientConnAttr as u32 != 0
+                && (payload.position() as usize) < payload.get_ref().len()
+            {
+                let attrs = payload
+                    .read_len_str()
+                    .map_err(|_| ProtoError::ReadConnAttrsError)?;
+                self.connection_attrs = parse_connection_attrs(attrs.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// COM_CHANGE_USER logs the connection in as another user, the auth
+    /// response is computed with the last salt sent by the server.
+    /// https://dev.mysql.com/doc/internals/en/com-change-user.html
+    pub fn write_change_user_packet(
+        capability_flag: u32,
+        charset: u8,
+        username: String,
+        password: String,
+        salt: &[u8],
+        auth_plugin: &str,
+        database: String,
+        connection_attrs: &HashMap<String, String>,
+    ) -> ProtoResult<Vec<u8>> {
+        let mut buf = vec![];
+        let cmd: u16 = PacketType::ComChangeUser.into();
+        buf.write_u8(cmd as u8)?;
+        buf.write_all(username.as_bytes())?;
+        buf.write_u8(0)?;
+        let auth_resp = gen_auth_response(auth_plugin, &password, salt)?;
+        if (capability_flag & CapabilityFlag::CapabilityClientSecureConnection as u32) > 0 {
+            buf.write_u8(auth_resp.len() as u8)?;
+            buf.write_all(auth_resp.as_slice())?;
+        } else {
+            buf.write_all(auth_resp.as_slice())?;
+            buf.write_u8(0)?;
+        }
+        buf.write_all(database.as_bytes())?;
+        buf.write_u8(0)?;
+        buf.write_u16::<LittleEndian>(charset as u16)?;
+        if (capability_flag & CapabilityFlag::CapabilityClientPluginAuth as u32) > 0 {
+            buf.write_all(auth_plugin.as_bytes())?;
+            buf.write_u8(0)?;
+        }
+        if (capability_flag & CapabilityFlag::CapabilityClientConnAttr as u32) > 0 {
+            let mut attrs = vec![];
+            for (key, value) in connection_attrs {
+                attrs.write_len_str(key.as_bytes())?;
+                attrs.write_len_str(value.as_bytes())?;
+            }
+            buf.write_len_str(attrs.as_slice())?;
+        }
+        Ok(buf)
+    }
+
+    /// Parse a COM_CHANGE_USER packet, it carries no capability flags so the
+    /// ones of the handshake response are kept.
+    pub fn parse_change_user_packet(
+        &mut self,
+        payload: &[u8],
+        capability_flags: u32,
+    ) -> ProtoResult<()> {
+        *self = Auth::new();
+        self.capability_flags = capability_flags;
+        let mut payload = Cursor::new(payload);
+        // Skip the command
+        payload.set_position(1);
+        let mut user = vec![];
+        payload
+            .real_read_until(0x00, &mut user)
+            .map_err(|_| ProtoError::ReadUserError)?;
+        self.user = String::from_utf8_lossy(&user).into_owned();
+        if capability_flags & CapabilityFlag::CapabilityClientSecureConnection as u32 != 0 {
+            let auth_resp_len = payload
+                .read_u8()
+                .map_err(|_| ProtoError::ReadAuthResponseLengthError)?
+                as usize;
+            self.auth_response = vec![0; auth_resp_len];
+            payload
+                .read_exact(&mut self.auth_response)
+                .map_err(|_| ProtoError::ReadAuthResponseError)?;
+        } else {
+            payload
+                .real_read_until(0x00, &mut self.auth_response)
+                .map_err(|_| ProtoError::ReadAuthResponseError)?;
+        }
+        let mut database = vec![];
+        payload
+            .real_read_until(0x00, &mut database)
+            .map_err(|_| ProtoError::ReadDatabaseError)?;
+        self.database = String::from_utf8_lossy(&database).into_owned();
+        // Old clients stop after the database.
+        if (payload.position() as usize) < payload.get_ref().len() {
+            self.character_set = payload
+                .read_u16::<LittleEndian>()
+                .map_err(|_| ProtoError::ReadCharsetError)? as u8;
+            if capability_flags & CapabilityFlag::CapabilityClientPluginAuth as u32 != 0 {
+                let mut auth_method = vec![];
+                payload
+                    .real_read_until(0x00, &mut auth_method)
+                    .map_err(|_| ProtoError::ReadPluginError)?;
+                self.auth_method = String::from_utf8_lossy(&auth_method).into_owned();
+            }
+            if capability_flags & CapabilityFlag::CapabilityClientConnAttr as u32 != 0
+                && (payload.position() as usize) < payload.get_ref().len()
+            {
+                let attrs = payload
+                    .read_len_str()
+                    .map_err(|_| ProtoError::ReadConnAttrsError)?;
+                self.connection_attrs = parse_connection_attrs(attrs.as_slice())?;
             }
         }
+        if self.auth_method.is_empty() {
+            self.auth_method = String::from(MYSQL_NATIVE_PASSWORD);
+        }
         Ok(())
     }
 }
 
+/// Decode the length encoded key/value pairs of the connection attributes.
+fn parse_connection_attrs(mut data: &[u8]) -> ProtoResult<HashMap<String, String>> {
+    let mut attrs = HashMap::new();
+    while !data.is_empty() {
+        let key = data
+            .read_len_str()
+            .map_err(|_| ProtoError::ReadConnAttrsError)?;
+        let value = data
+            .read_len_str()
+            .map_err(|_| ProtoError::ReadConnAttrsError)?;
+        attrs.insert(
+            String::from_utf8_lossy(&key).into_owned(),
+            String::from_utf8_lossy(&value).into_owned(),
+        );
+    }
+    Ok(attrs)
+}
+
+/// SSLRequest is the 32 bytes prefix of HandshakeResponse41 with CapabilityClientSSL set,
+/// the client sends it before switching to TLS.
+/// https://dev.mysql.com/doc/internals/en/connection-phase-packets.html#packet-Protocol::SSLRequest
+pub fn is_ssl_request(payload: &[u8]) -> bool {
+    if payload.len() != 32 {
+        return false;
+    }
+    let mut payload = payload;
+    match payload.read_u32::<LittleEndian>() {
+        Ok(flags) => flags & CapabilityFlag::CapabilityClientSSL as u32 != 0,
+        Err(_) => false,
+    }
+}
+
+/// AuthSwitchRequest asks the client to authenticate with another plugin and
+/// a fresh salt.
+/// https://dev.mysql.com/doc/internals/en/connection-phase-packets.html#packet-Protocol::AuthSwitchRequest
+pub fn write_auth_switch_request(auth_plugin: &str, salt: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    buf.write_u8(EOF_PACKET)?;
+    buf.write_all(auth_plugin.as_bytes())?;
+    buf.write_u8(0)?;
+    buf.write_all(salt)?;
+    buf.write_u8(0)?;
+    Ok(buf)
+}
+
+/// Parse an AuthSwitchRequest, return the plugin name and the salt.
+pub fn parse_auth_switch_request(payload: &[u8]) -> ProtoResult<(String, Vec<u8>)> {
+    if payload.first() != Some(&EOF_PACKET) {
+        return Err(ProtoError::MalformedAuthSwitchRequest);
+    }
+    let mut payload = Cursor::new(&payload[1..]);
+    let mut auth_plugin = vec![];
+    payload
+        .real_read_until(0x00, &mut auth_plugin)
+        .map_err(|_| ProtoError::ReadPluginError)?;
+    let auth_plugin =
+        String::from_utf8(auth_plugin).map_err(|_| ProtoError::MalformedAuthSwitchRequest)?;
+    let mut salt = vec![];
+    payload.read_to_end(&mut salt)?;
+    if salt.last() == Some(&0) {
+        salt.pop();
+    }
+    Ok((auth_plugin, salt))
+}
+
+/// Return the data carried by an AuthMoreData packet.
+pub fn parse_auth_more_data(payload: &[u8]) -> ProtoResult<&[u8]> {
+    match payload.split_first() {
+        Some((&AUTH_MORE_DATA_PACKET, data)) => Ok(data),
+        _ => Err(ProtoError::MalformedAuthMoreData),
+    }
+}
+
+/// Compute the auth response of password for a plugin, it is sent in the
+/// handshake response or in the AuthSwitchResponse.
+pub fn gen_auth_response(auth_plugin: &str, password: &str, salt: &[u8]) -> ProtoResult<Vec<u8>> {
+    match auth_plugin {
+        MYSQL_NATIVE_PASSWORD => Ok(gen_native_password(password.to_string(), salt)),
+        CACHING_SHA2_PASSWORD => Ok(gen_caching_sha2_password(password, salt)),
+        MYSQL_CLEAR_PASSWORD => {
+            let mut buf = password.as_bytes().to_vec();
+            buf.push(0);
+            Ok(buf)
+        }
+        _ => Err(ProtoError::InvalidPluginError(auth_plugin.to_string())),
+    }
+}
+
 /// https://dev.mysql.com/doc/internals/en/secure-password-authentication.html#packet-Authentication::Native41
 fn gen_native_password(password: String, salt: &[u8]) -> Vec<u8> {
     if password.is_empty() {
@@ -265,6 +524,87 @@ fn gen_native_password(password: String, salt: &[u8]) -> Vec<u8> {
     scramble
 }
 
+/// Return the double SHA1 hash of password in the format of
+/// mysql.user.authentication_string, e.g. `*2470C0C06DEE42FD1618BB99005ADCA2EC9D1E19`.
+pub fn native_password_hash(password: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input(password.as_bytes());
+    let stage1 = hasher.result_reset();
+    hasher.input(stage1);
+    let stage2 = hasher.result();
+
+    let mut hash = String::with_capacity(1 + stage2.len() * 2);
+    hash.push('*');
+    for b in stage2.iter() {
+        hash.push_str(&format!("{:02X}", b));
+    }
+    hash
+}
+
+/// Verify the mysql_native_password scramble sent by a client against the salt
+/// of the greeting and the plain text password of the user.
+pub fn verify_native_password(auth_response: &[u8], salt: &[u8], password: &str) -> bool {
+    if password.is_empty() {
+        return auth_response.is_empty();
+    }
+    let mut hasher = Sha1::new();
+    hasher.input(password.as_bytes());
+    let stage1 = hasher.result_reset();
+    hasher.input(stage1);
+    verify_native_scramble(auth_response, salt, &hasher.result())
+}
+
+/// Verify the mysql_native_password scramble sent by a client against the salt
+/// of the greeting and the double SHA1 hash of the password, as returned by
+/// native_password_hash. An empty hash stands for an empty password, an
+/// invalid one matches no scramble.
+pub fn verify_native_password_hash(auth_response: &[u8], salt: &[u8], hash: &str) -> bool {
+    if hash.is_empty() {
+        return auth_response.is_empty();
+    }
+    match parse_native_password_hash(hash) {
+        Some(stored) => verify_native_scramble(auth_response, salt, &stored),
+        None => false,
+    }
+}
+
+fn verify_native_scramble(auth_response: &[u8], salt: &[u8], stored: &[u8]) -> bool {
+    if auth_response.len() != stored.len() {
+        return false;
+    }
+    // scramble = SHA1(password) XOR SHA1(salt + stored), recover SHA1(password)
+    // and check that its hash is the stored one.
+    let mut hasher = Sha1::new();
+    hasher.input(salt);
+    hasher.input(stored);
+    let mask = hasher.result_reset();
+    let stage1: Vec<u8> = auth_response
+        .iter()
+        .zip(mask.iter())
+        .map(|(a, b)| a ^ b)
+        .collect();
+    hasher.input(&stage1);
+    let candidate = hasher.result();
+    // Compare in constant time.
+    candidate
+        .iter()
+        .zip(stored.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+/// Decode `*` followed by 40 hex digits.
+fn parse_native_password_hash(hash: &str) -> Option<Vec<u8>> {
+    let hex = hash.strip_prefix('*')?;
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
 impl cmp::PartialEq for Auth {
     fn eq(&self, other: &Self) -> bool {
         self.auth_method == other.auth_method
@@ -274,6 +614,7 @@ impl cmp::PartialEq for Auth {
             && self.max_packet_size == other.max_packet_size
             && self.auth_response == other.auth_response
             && self.user == other.user
+            && self.connection_attrs == other.connection_attrs
     }
 }
 
@@ -295,10 +636,20 @@ impl Display for Auth {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use crate::constants::CapabilityFlag;
-    use crate::constants::{DEFAULT_CLIENT_CAPABILITY, DEFAULT_SALT, MYSQL_NATIVE_PASSWORD};
+    use crate::constants::{
+        CACHING_SHA2_PASSWORD, DEFAULT_CLIENT_CAPABILITY, DEFAULT_SALT, MYSQL_CLEAR_PASSWORD,
+        MYSQL_NATIVE_PASSWORD,
+    };
     use crate::errors::ProtoError;
-    use crate::proto::auth::gen_native_password;
+    use crate::proto::auth::{
+        gen_auth_response, gen_native_password, is_ssl_request, native_password_hash,
+        parse_auth_more_data, parse_auth_switch_request, parse_connection_attrs,
+        verify_native_password, verify_native_password_hash, write_auth_switch_request,
+    };
+    use crate::proto::gen_caching_sha2_password;
     use crate::proto::Auth;
 
     #[test]
@@ -325,6 +676,61 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_verify_native_password() {
+        let hash = native_password_hash("password");
+        assert_eq!(hash, "*2470C0C06DEE42FD1618BB99005ADCA2EC9D1E19");
+        let scramble = gen_native_password(String::from("password"), DEFAULT_SALT);
+        assert!(verify_native_password(&scramble, DEFAULT_SALT, "password"));
+        assert!(!verify_native_password(&scramble, DEFAULT_SALT, "passw0rd"));
+        assert!(!verify_native_password(&scramble, &[0; 20], "password"));
+        assert!(!verify_native_password(&scramble, DEFAULT_SALT, ""));
+        assert!(verify_native_password(&[], DEFAULT_SALT, ""));
+        assert!(!verify_native_password(&[], DEFAULT_SALT, "password"));
+        // A password starting with * is not read as a hash.
+        let scramble = gen_native_password(hash.clone(), DEFAULT_SALT);
+        assert!(verify_native_password(&scramble, DEFAULT_SALT, &hash));
+    }
+
+    #[test]
+    fn test_verify_native_password_hash() {
+        let hash = native_password_hash("password");
+        let scramble = gen_native_password(String::from("password"), DEFAULT_SALT);
+        assert!(verify_native_password_hash(&scramble, DEFAULT_SALT, &hash));
+        assert!(verify_native_password_hash(
+            &scramble,
+            DEFAULT_SALT,
+            &hash.to_lowercase()
+        ));
+        assert!(!verify_native_password_hash(&scramble, &[0; 20], &hash));
+        assert!(!verify_native_password_hash(
+            &scramble[1..],
+            DEFAULT_SALT,
+            &hash
+        ));
+        // The plain text password is not a hash.
+        assert!(!verify_native_password_hash(
+            &scramble,
+            DEFAULT_SALT,
+            "password"
+        ));
+        assert!(!verify_native_password_hash(&scramble, DEFAULT_SALT, ""));
+        assert!(verify_native_password_hash(&[], DEFAULT_SALT, ""));
+    }
+
+    #[test]
+    fn test_ssl_request() {
+        let mut data = vec![0; 32];
+        data[..4].copy_from_slice(
+            &(DEFAULT_CLIENT_CAPABILITY | CapabilityFlag::CapabilityClientSSL as u32)
+                .to_le_bytes(),
+        );
+        assert!(is_ssl_request(&data));
+        assert!(!is_ssl_request(&data[..31]));
+        data[..4].copy_from_slice(&DEFAULT_CLIENT_CAPABILITY.to_le_bytes());
+        assert!(!is_ssl_request(&data));
+    }
+
     #[test]
     fn test_error() {
         let data = &[0x8d, 0xa6, 0xff];
@@ -381,7 +787,9 @@ mod tests {
             "root".to_string(),
             "password".to_string(),
             DEFAULT_SALT,
+            MYSQL_NATIVE_PASSWORD,
             "test_db".to_string(),
+            &HashMap::new(),
         );
         actual
             .parse_client_handshake_packet(tmp.unwrap().as_slice(), false)
@@ -406,7 +814,9 @@ mod tests {
             "root".to_string(),
             "password".to_string(),
             DEFAULT_SALT,
+            MYSQL_NATIVE_PASSWORD,
             "".to_string(),
+            &HashMap::new(),
         );
         actual
             .parse_client_handshake_packet(tmp.unwrap().as_slice(), false)
@@ -432,7 +842,9 @@ mod tests {
             "root".to_string(),
             "".to_string(),
             DEFAULT_SALT,
+            MYSQL_NATIVE_PASSWORD,
             "db".to_string(),
+            &HashMap::new(),
         );
         actual
             .parse_client_handshake_packet(tmp.unwrap().as_slice(), false)
@@ -460,11 +872,164 @@ mod tests {
             "root".to_string(),
             "password".to_string(),
             DEFAULT_SALT,
+            MYSQL_NATIVE_PASSWORD,
             "test_db".to_string(),
+            &HashMap::new(),
         );
         actual
             .parse_client_handshake_packet(tmp.unwrap().as_slice(), false)
             .unwrap();
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_unpack_caching_sha2() {
+        let mut expected = Auth::new();
+        expected.character_set = 0x02;
+        expected.capability_flags = DEFAULT_CLIENT_CAPABILITY;
+        expected.auth_response = gen_caching_sha2_password("password", DEFAULT_SALT);
+        expected.user = "root".to_string();
+        expected.auth_method = CACHING_SHA2_PASSWORD.to_string();
+
+        let mut actual = Auth::new();
+        let tmp = Auth::write_handshake_resp(
+            DEFAULT_CLIENT_CAPABILITY,
+            0x02,
+            "root".to_string(),
+            "password".to_string(),
+            DEFAULT_SALT,
+            CACHING_SHA2_PASSWORD,
+            "".to_string(),
+            &HashMap::new(),
+        );
+        actual
+            .parse_client_handshake_packet(tmp.unwrap().as_slice(), false)
+            .unwrap();
+        assert_eq!(actual, expected);
+
+        match Auth::write_handshake_resp(
+            DEFAULT_CLIENT_CAPABILITY,
+            0x02,
+            "root".to_string(),
+            "password".to_string(),
+            DEFAULT_SALT,
+            "unknown",
+            "".to_string(),
+            &HashMap::new(),
+        ) {
+            Err(ProtoError::InvalidPluginError(_)) => {}
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[test]
+    fn test_auth_switch_request() {
+        let data = write_auth_switch_request(MYSQL_NATIVE_PASSWORD, DEFAULT_SALT).unwrap();
+        assert_eq!(data[0], 0xfe);
+        let (auth_plugin, salt) = parse_auth_switch_request(&data).unwrap();
+        assert_eq!(auth_plugin, MYSQL_NATIVE_PASSWORD);
+        assert_eq!(salt, DEFAULT_SALT.to_vec());
+        match parse_auth_switch_request(&[0x00]) {
+            Err(ProtoError::MalformedAuthSwitchRequest) => {}
+            _ => panic!("Unexpected result"),
+        }
+
+        assert_eq!(
+            gen_auth_response(MYSQL_NATIVE_PASSWORD, "password", DEFAULT_SALT).unwrap(),
+            gen_native_password(String::from("password"), DEFAULT_SALT)
+        );
+        assert_eq!(
+            gen_auth_response(MYSQL_CLEAR_PASSWORD, "password", DEFAULT_SALT).unwrap(),
+            b"password\0".to_vec()
+        );
+        assert_eq!(parse_auth_more_data(&[0x01, 0x03]).unwrap(), &[0x03]);
+        assert!(parse_auth_more_data(&[0xfe]).is_err());
+    }
+
+    #[test]
+    fn test_unpack_with_conn_attrs() {
+        let mut attrs = HashMap::new();
+        attrs.insert("_client_name".to_string(), "libmysql".to_string());
+        attrs.insert("_pid".to_string(), "1234".to_string());
+        attrs.insert("program_name".to_string(), "mysql".to_string());
+
+        let mut expected = Auth::new();
+        expected.character_set = 0x02;
+        expected.capability_flags =
+            DEFAULT_CLIENT_CAPABILITY | CapabilityFlag::CapabilityClientConnAttr as u32;
+        expected.auth_response = gen_native_password(String::from("password"), DEFAULT_SALT);
+        expected.user = "root".to_string();
+        expected.auth_method = MYSQL_NATIVE_PASSWORD.to_string();
+        expected.connection_attrs = attrs.clone();
+
+        let mut actual = Auth::new();
+        let tmp = Auth::write_handshake_resp(
+            DEFAULT_CLIENT_CAPABILITY,
+            0x02,
+            "root".to_string(),
+            "password".to_string(),
+            DEFAULT_SALT,
+            MYSQL_NATIVE_PASSWORD,
+            "".to_string(),
+            &attrs,
+        );
+        actual
+            .parse_client_handshake_packet(tmp.unwrap().as_slice(), false)
+            .unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(actual.connection_attrs()["program_name"], "mysql");
+
+        // A truncated pair
+        match parse_connection_attrs(&[0x04, b'_', b'p', b'i', b'd', 0x04, b'1']) {
+            Err(ProtoError::ReadConnAttrsError) => {}
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[test]
+    fn test_change_user() {
+        let mut attrs = HashMap::new();
+        attrs.insert("program_name".to_string(), "mysql".to_string());
+        let capability =
+            DEFAULT_CLIENT_CAPABILITY | CapabilityFlag::CapabilityClientConnAttr as u32;
+
+        let mut expected = Auth::new();
+        expected.character_set = 0x21;
+        expected.capability_flags = capability;
+        expected.auth_response = gen_native_password(String::from("password"), DEFAULT_SALT);
+        expected.user = "bob".to_string();
+        expected.database = "test".to_string();
+        expected.auth_method = MYSQL_NATIVE_PASSWORD.to_string();
+        expected.connection_attrs = attrs.clone();
+
+        let data = Auth::write_change_user_packet(
+            capability,
+            0x21,
+            "bob".to_string(),
+            "password".to_string(),
+            DEFAULT_SALT,
+            MYSQL_NATIVE_PASSWORD,
+            "test".to_string(),
+            &attrs,
+        )
+        .unwrap();
+        assert_eq!(data[..5], [0x11, b'b', b'o', b'b', 0x00]);
+        let mut actual = Auth::new();
+        actual.parse_change_user_packet(&data, capability).unwrap();
+        assert_eq!(actual, expected);
+
+        // Old clients send neither the charset nor the plugin.
+        let mut data = vec![0x11];
+        data.extend_from_slice(b"bob\0\0\0");
+        actual.parse_change_user_packet(&data, capability).unwrap();
+        assert_eq!(actual.user(), "bob");
+        assert!(actual.auth_response().is_empty());
+        assert!(actual.database().is_empty());
+        assert_eq!(actual.auth_method(), MYSQL_NATIVE_PASSWORD);
+
+        match actual.parse_change_user_packet(&[0x11, b'b', 0x00, 0x14, 0x01], capability) {
+            Err(ProtoError::ReadAuthResponseError) => {}
+            _ => panic!("Unexpected result"),
+        }
+    }
 }
@@ -0,0 +1,489 @@
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::packets::{ReadLenEncode, WriteLenEncode};
+use crate::sql_type::{mysql_to_type, type_to_mysql, Field, MysqlFlag, MysqlType, Type, Value};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+// The unsigned flag sent with each parameter type in COM_STMT_EXECUTE.
+pub const PARAM_UNSIGNED: u8 = 0x80;
+
+pub fn param_type(typ: u8, flag: u8) -> ProtoResult<Type> {
+    let flags = if flag & PARAM_UNSIGNED != 0 {
+        MysqlFlag::MysqlUnsigned as i64
+    } else {
+        0
+    };
+    mysql_to_type(typ as i64, flags).ok_or(ProtoError::ParseComStmtExecuteError)
+}
+
+/// Read one binary protocol value and convert it into its text representation.
+/// See https://dev.mysql.com/doc/internals/en/binary-protocol-value.html
+pub fn read_binary_value(data: &mut &[u8], typ: u8, flag: u8) -> io::Result<Value> {
+    let unsigned = flag & PARAM_UNSIGNED != 0;
+    let val = match typ {
+        // NULL
+        0x06 => vec![],
+        // TINY
+        0x01 => {
+            if unsigned {
+                data.read_u8()?.to_string().into_bytes()
+            } else {
+                data.read_i8()?.to_string().into_bytes()
+            }
+        }
+        // SHORT, YEAR
+        0x02 | 0x0d => {
+            if unsigned {
+                data.read_u16::<LittleEndian>()?.to_string().into_bytes()
+            } else {
+                data.read_i16::<LittleEndian>()?.to_string().into_bytes()
+            }
+        }
+        // LONG, INT24
+        0x03 | 0x09 => {
+            if unsigned {
+                data.read_u32::<LittleEndian>()?.to_string().into_bytes()
+            } else {
+                data.read_i32::<LittleEndian>()?.to_string().into_bytes()
+            }
+        }
+        // LONGLONG
+        0x08 => {
+            if unsigned {
+                data.read_u64::<LittleEndian>()?.to_string().into_bytes()
+            } else {
+                data.read_i64::<LittleEndian>()?.to_string().into_bytes()
+            }
+        }
+        // FLOAT
+        0x04 => data.read_f32::<LittleEndian>()?.to_string().into_bytes(),
+        // DOUBLE
+        0x05 => data.read_f64::<LittleEndian>()?.to_string().into_bytes(),
+        // DATE, DATETIME, TIMESTAMP
+        0x0a | 0x0c | 0x07 => read_binary_datetime(data, typ == 0x0a)?.into_bytes(),
+        // TIME
+        0x0b => read_binary_time(data)?.into_bytes(),
+        // Everything else is sent as a length encoded string.
+        _ => data.read_len_str()?,
+    };
+    let typ = param_type(typ, flag)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Unknown parameter type"))?;
+    Ok(Value { typ, val })
+}
+
+fn read_binary_datetime(data: &mut &[u8], date_only: bool) -> io::Result<String> {
+    let len = data.read_u8()?;
+    let (mut year, mut month, mut day) = (0, 0, 0);
+    let (mut hour, mut minute, mut second, mut micro) = (0, 0, 0, 0);
+    if len >= 4 {
+        year = data.read_u16::<LittleEndian>()?;
+        month = data.read_u8()?;
+        day = data.read_u8()?;
+    }
+    if len >= 7 {
+        hour = data.read_u8()?;
+        minute = data.read_u8()?;
+        second = data.read_u8()?;
+    }
+    if len >= 11 {
+        micro = data.read_u32::<LittleEndian>()?;
+    }
+    let date = format!("{:04}-{:02}-{:02}", year, month, day);
+    if date_only {
+        return Ok(date);
+    }
+    let mut datetime = format!("{} {:02}:{:02}:{:02}", date, hour, minute, second);
+    if micro > 0 {
+        datetime.push_str(&format!(".{:06}", micro));
+    }
+    Ok(datetime)
+}
+
+fn read_binary_time(data: &mut &[u8]) -> io::Result<String> {
+    let len = data.read_u8()?;
+    if len == 0 {
+        return Ok("00:00:00".to_string());
+    }
+    let negative = data.read_u8()? == 1;
+    let days = data.read_u32::<LittleEndian>()?;
+    let hour = data.read_u8()? as u32;
+    let minute = data.read_u8()?;
+    let second = data.read_u8()?;
+    let micro = if len >= 12 {
+        data.read_u32::<LittleEndian>()?
+    } else {
+        0
+    };
+    let mut time = format!(
+        "{}{:02}:{:02}:{:02}",
+        if negative { "-" } else { "" },
+        days * 24 + hour,
+        minute,
+        second
+    );
+    if micro > 0 {
+        time.push_str(&format!(".{:06}", micro));
+    }
+    Ok(time)
+}
+
+/// Encode a row of a binary protocol result set, the type of each value is
+/// taken from its field.
+/// See https://dev.mysql.com/doc/internals/en/binary-protocol-resultset-row.html
+pub fn write_binary_row(row: &[Value], fields: &[Field]) -> io::Result<Vec<u8>> {
+    if row.len() != fields.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Row length does not match fields length",
+        ));
+    }
+    // The NULL bitmap of result set rows is offset by 2 bits.
+    let bitmap_len = (row.len() + 7 + 2) / 8;
+    let mut data = vec![0; 1 + bitmap_len];
+    for (i, (val, field)) in row.iter().zip(fields).enumerate() {
+        if val.is_null() {
+            data[1 + (i + 2) / 8] |= 1 << ((i + 2) % 8);
+            continue;
+        }
+        let (typ, flag) = binary_type(field.typ);
+        write_binary_value(&mut data, val, typ, flag)?;
+    }
+    Ok(data)
+}
+
+/// Decode a row of a binary protocol result set, see write_binary_row.
+pub fn read_binary_row(data: &[u8], fields: &[Field]) -> io::Result<Vec<Value>> {
+    let bitmap_len = (fields.len() + 7 + 2) / 8;
+    if data.len() < 1 + bitmap_len || data[0] != 0x00 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed binary row",
+        ));
+    }
+    let (null_bitmap, mut data) = data[1..].split_at(bitmap_len);
+    let mut row = Vec::with_capacity(fields.len());
+    for (i, field) in fields.iter().enumerate() {
+        if null_bitmap[(i + 2) / 8] & (1 << ((i + 2) % 8)) != 0 {
+            row.push(Value {
+                typ: MysqlType::NullType as Type,
+                val: vec![],
+            });
+            continue;
+        }
+        let (typ, flag) = binary_type(field.typ);
+        let mut value = read_binary_value(&mut data, typ, flag)?;
+        // Keep the binary and text variants of the column type.
+        value.typ = field.typ;
+        row.push(value);
+    }
+    Ok(row)
+}
+
+/// Return the wire type and the unsigned flag values of typ are encoded with.
+pub fn binary_type(typ: Type) -> (u8, u8) {
+    let (typ, flags) = type_to_mysql(typ);
+    if flags & MysqlFlag::MysqlUnsigned as i64 != 0 {
+        (typ as u8, PARAM_UNSIGNED)
+    } else {
+        (typ as u8, 0)
+    }
+}
+
+/// Write the text representation of a value with the binary encoding of typ,
+/// integers out of the signed or the unsigned range flag selects are rejected.
+pub fn write_binary_value(data: &mut Vec<u8>, val: &Value, typ: u8, flag: u8) -> io::Result<()> {
+    let unsigned = flag & PARAM_UNSIGNED != 0;
+    let text = || {
+        std::str::from_utf8(val.val.as_slice())
+            .map_err(|_| invalid_value(val))
+            .map(str::trim)
+    };
+    match typ {
+        // TINY
+        0x01 if unsigned => data.write_u8(parse_int(val)?),
+        0x01 => data.write_i8(parse_int(val)?),
+        // SHORT, YEAR
+        0x02 | 0x0d if unsigned => data.write_u16::<LittleEndian>(parse_int(val)?),
+        0x02 | 0x0d => data.write_i16::<LittleEndian>(parse_int(val)?),
+        // LONG, INT24
+        0x03 | 0x09 if unsigned => data.write_u32::<LittleEndian>(parse_int(val)?),
+        0x03 | 0x09 => data.write_i32::<LittleEndian>(parse_int(val)?),
+        // LONGLONG
+        0x08 if unsigned => data.write_u64::<LittleEndian>(parse_int(val)?),
+        0x08 => data.write_i64::<LittleEndian>(parse_int(val)?),
+        // FLOAT
+        0x04 => data.write_f32::<LittleEndian>(text()?.parse().map_err(|_| invalid_value(val))?),
+        // DOUBLE
+        0x05 => data.write_f64::<LittleEndian>(text()?.parse().map_err(|_| invalid_value(val))?),
+        // DATE, DATETIME, TIMESTAMP
+        0x0a | 0x0c | 0x07 => {
+            write_binary_datetime(data, text()?).ok_or_else(|| invalid_value(val))?
+        }
+        // TIME
+        0x0b => write_binary_time(data, text()?).ok_or_else(|| invalid_value(val))?,
+        // Everything else is sent as a length encoded string.
+        _ => data.write_len_str(val.val.as_slice()),
+    }
+}
+
+fn invalid_value(val: &Value) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Invalid value {:?} for type {}",
+            String::from_utf8_lossy(val.val.as_slice()),
+            val.typ
+        ),
+    )
+}
+
+/// Parse the text of an integer value into T, out of range values are
+/// rejected instead of wrapped.
+fn parse_int<T: FromStr>(val: &Value) -> io::Result<T> {
+    std::str::from_utf8(val.val.as_slice())
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .ok_or_else(|| invalid_value(val))
+}
+
+/// Parse the fractional part of seconds into microseconds.
+fn parse_micro(s: &str) -> Option<u32> {
+    if s.is_empty() || s.len() > 6 {
+        return None;
+    }
+    let n: u32 = s.parse().ok()?;
+    Some(n * 10u32.pow(6 - s.len() as u32))
+}
+
+/// Parse `HH:MM:SS[.ffffff]`, hours may be larger than 23.
+fn parse_time(s: &str) -> Option<(u32, u8, u8, u32)> {
+    let (hms, micro) = match s.find('.') {
+        Some(n) => (&s[..n], parse_micro(&s[n + 1..])?),
+        None => (s, 0),
+    };
+    let mut parts = hms.split(':');
+    let hour = parts.next()?.parse().ok()?;
+    let minute = parts.next()?.parse().ok()?;
+    let second = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((hour, minute, second, micro))
+}
+
+/// Write `YYYY-MM-DD[ HH:MM:SS[.ffffff]]` with the shortest binary encoding.
+fn write_binary_datetime(data: &mut Vec<u8>, s: &str) -> Option<io::Result<()>> {
+    let (date, time) = match s.find(' ') {
+        Some(n) => (&s[..n], Some(parse_time(s[n + 1..].trim())?)),
+        None => (s, None),
+    };
+    let mut parts = date.split('-');
+    let year: u16 = parts.next()?.parse().ok()?;
+    let month: u8 = parts.next()?.parse().ok()?;
+    let day: u8 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    let (hour, minute, second, micro) = time.unwrap_or((0, 0, 0, 0));
+    if hour > 23 {
+        return None;
+    }
+    let len = if micro > 0 {
+        11
+    } else if hour > 0 || minute > 0 || second > 0 {
+        7
+    } else if year > 0 || month > 0 || day > 0 {
+        4
+    } else {
+        0
+    };
+    let mut buf = Vec::with_capacity(1 + len);
+    buf.push(len as u8);
+    if len >= 4 {
+        buf.extend_from_slice(&year.to_le_bytes());
+        buf.push(month);
+        buf.push(day);
+    }
+    if len >= 7 {
+        buf.push(hour as u8);
+        buf.push(minute);
+        buf.push(second);
+    }
+    if len == 11 {
+        buf.extend_from_slice(&micro.to_le_bytes());
+    }
+    Some(data.write_all(buf.as_slice()))
+}
+
+/// Write `[-]HH:MM:SS[.ffffff]` with the shortest binary encoding.
+fn write_binary_time(data: &mut Vec<u8>, s: &str) -> Option<io::Result<()>> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let (hours, minute, second, micro) = parse_time(s)?;
+    let len = if micro > 0 {
+        12
+    } else if hours > 0 || minute > 0 || second > 0 {
+        8
+    } else {
+        0
+    };
+    let mut buf = Vec::with_capacity(1 + len);
+    buf.push(len as u8);
+    if len >= 8 {
+        buf.push(negative as u8);
+        buf.extend_from_slice(&(hours / 24).to_le_bytes());
+        buf.push((hours % 24) as u8);
+        buf.push(minute);
+        buf.push(second);
+    }
+    if len == 12 {
+        buf.extend_from_slice(&micro.to_le_bytes());
+    }
+    Some(data.write_all(buf.as_slice()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::binary::{
+        read_binary_row, read_binary_value, write_binary_row, PARAM_UNSIGNED,
+    };
+    use crate::sql_type::{type_to_mysql, Field, MysqlFlag, MysqlType, Type, Value};
+
+    fn value(typ: MysqlType, val: &str) -> Value {
+        Value {
+            typ: typ as Type,
+            val: val.as_bytes().to_vec(),
+        }
+    }
+
+    fn field(typ: MysqlType) -> Field {
+        Field {
+            typ: typ as Type,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_write_binary_row() {
+        let fields = vec![
+            field(MysqlType::Int8),
+            field(MysqlType::Varchar),
+            field(MysqlType::Int32),
+            field(MysqlType::Float64),
+        ];
+        let row = vec![
+            value(MysqlType::Int8, "-1"),
+            Value::default(),
+            value(MysqlType::Int32, "258"),
+            value(MysqlType::Float64, "1.5"),
+        ];
+        let data = write_binary_row(&row, &fields).unwrap();
+        let mut expected = vec![0x00, 0x08, 0xff, 0x02, 0x01, 0x00, 0x00];
+        expected.extend_from_slice(&1.5f64.to_le_bytes());
+        assert_eq!(data, expected);
+
+        let row = vec![value(MysqlType::Int8, "abc")];
+        assert!(write_binary_row(&row, &fields[..1]).is_err());
+        // Out of range values are not wrapped.
+        for val in &["300", "-129"] {
+            let row = vec![value(MysqlType::Int8, val)];
+            assert!(write_binary_row(&row, &fields[..1]).is_err());
+        }
+        let row = vec![value(MysqlType::Int16, "70000")];
+        assert!(write_binary_row(&row, &[field(MysqlType::Int16)]).is_err());
+        let row = vec![value(MysqlType::Int32, "4294967296")];
+        assert!(write_binary_row(&row, &fields[2..3]).is_err());
+        assert!(write_binary_row(&row, &fields).is_err());
+        // Only the range of the signedness of the column is accepted.
+        let cases = vec![
+            (MysqlType::Int8, "200"),
+            (MysqlType::Uint8, "-1"),
+            (MysqlType::Int16, "40000"),
+            (MysqlType::Uint16, "-1"),
+            (MysqlType::Int32, "3000000000"),
+            (MysqlType::Uint32, "-1"),
+            (MysqlType::Int64, "18446744073709551615"),
+            (MysqlType::Uint64, "-1"),
+        ];
+        for (typ, val) in cases {
+            let row = vec![value(typ, val)];
+            assert!(write_binary_row(&row, &[field(typ)]).is_err());
+        }
+        let row = vec![value(MysqlType::Uint8, "200")];
+        let data = write_binary_row(&row, &[field(MysqlType::Uint8)]).unwrap();
+        assert_eq!(data, vec![0x00, 0x00, 0xc8]);
+    }
+
+    #[test]
+    fn test_read_binary_row() {
+        let fields = vec![
+            field(MysqlType::Uint64),
+            field(MysqlType::VarBinary),
+            field(MysqlType::Datetime),
+            field(MysqlType::Int16),
+        ];
+        let row = vec![
+            value(MysqlType::Uint64, "18446744073709551615"),
+            Value::default(),
+            value(MysqlType::Datetime, "2020-02-29 12:34:56"),
+            value(MysqlType::Int16, "-2"),
+        ];
+        let data = write_binary_row(&row, &fields).unwrap();
+        let actual = read_binary_row(&data, &fields).unwrap();
+        assert_eq!(actual.len(), 4);
+        assert_eq!(actual[0].typ, MysqlType::Uint64 as Type);
+        assert_eq!(actual[0].val, row[0].val);
+        assert!(actual[1].is_null());
+        assert_eq!(actual[2].val, row[2].val);
+        assert_eq!(actual[3].val, row[3].val);
+        assert!(read_binary_row(&data[..3], &fields).is_err());
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let cases = vec![
+            value(MysqlType::Uint8, "255"),
+            value(MysqlType::Int16, "-300"),
+            value(MysqlType::Uint32, "4294967295"),
+            value(MysqlType::Int64, "-9223372036854775808"),
+            value(MysqlType::Uint64, "18446744073709551615"),
+            value(MysqlType::Float32, "0.25"),
+            value(MysqlType::Year, "2020"),
+            value(MysqlType::Date, "2020-02-29"),
+            value(MysqlType::Datetime, "2020-02-29 00:00:00"),
+            value(MysqlType::Datetime, "2020-02-29 12:34:56"),
+            value(MysqlType::Timestamp, "2020-02-29 12:34:56.000789"),
+            value(MysqlType::Time, "00:00:00"),
+            value(MysqlType::Time, "-838:59:59"),
+            value(MysqlType::Time, "12:00:00.500000"),
+            value(MysqlType::Decimal, "3.14"),
+            value(MysqlType::Blob, "\x00\x01"),
+        ];
+        for case in cases {
+            let fields = vec![field(MysqlType::Int8), field(MysqlType::Int8), Field {
+                typ: case.typ,
+                ..Default::default()
+            }];
+            let row = vec![Value::default(), Value::default(), case.clone()];
+            let data = write_binary_row(&row, &fields).unwrap();
+            // header and bitmap
+            assert_eq!(data[..2], [0x00, 0x0c]);
+            let (typ, flags) = type_to_mysql(case.typ);
+            let flag = if flags & MysqlFlag::MysqlUnsigned as i64 != 0 {
+                PARAM_UNSIGNED
+            } else {
+                0
+            };
+            let mut rest = &data[2..];
+            let actual = read_binary_value(&mut rest, typ as u8, flag).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(actual.val, case.val);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::constants::{
+    AUTH_MORE_DATA_PACKET, CACHING_SHA2_FAST_AUTH_SUCCESS, CACHING_SHA2_PERFORM_FULL_AUTH,
+};
+use crate::errors::{ProtoError, ProtoResult};
+
+use sha2::{Digest, Sha256};
+
+/// caching_sha2_password scramble
+/// XOR(SHA256(password), SHA256(SHA256(SHA256(password)), salt))
+/// https://dev.mysql.com/doc/dev/mysql-server/latest/page_caching_sha2_authentication_exchanges.html
+pub fn gen_caching_sha2_password(password: &str, salt: &[u8]) -> Vec<u8> {
+    if password.is_empty() {
+        return vec![];
+    }
+    let mut hasher = Sha256::new();
+    hasher.input(password.as_bytes());
+    let stage1 = hasher.result_reset();
+    hasher.input(stage1);
+    let stage2 = hasher.result_reset();
+    hasher.input(stage2);
+    hasher.input(salt);
+    let stage3 = hasher.result();
+    stage1
+        .iter()
+        .zip(stage3.iter())
+        .map(|(a, b)| a ^ b)
+        .collect()
+}
+
+/// Answer the AuthMoreData packet sent by the server after the scramble.
+/// Return None on fast auth success, otherwise the packet carrying the clear
+/// text password, which is only sent over TLS.
+pub fn caching_sha2_more_data(
+    data: &[u8],
+    password: &str,
+    secure: bool,
+) -> ProtoResult<Option<Vec<u8>>> {
+    match data {
+        [AUTH_MORE_DATA_PACKET, CACHING_SHA2_FAST_AUTH_SUCCESS] => Ok(None),
+        [AUTH_MORE_DATA_PACKET, CACHING_SHA2_PERFORM_FULL_AUTH] => {
+            if !secure {
+                return Err(ProtoError::AuthRequiresSecureConnection);
+            }
+            let mut buf = password.as_bytes().to_vec();
+            buf.push(0);
+            Ok(Some(buf))
+        }
+        _ => Err(ProtoError::MalformedAuthMoreData),
+    }
+}
+
+/// Sha2Cache keeps SHA256(SHA256(password)) of the users that passed a full
+/// caching_sha2_password authentication, the next logins of these users are
+/// verified from the scramble alone.
+#[derive(Debug, Default)]
+pub struct Sha2Cache {
+    entries: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl Sha2Cache {
+    pub fn new() -> Self {
+        Sha2Cache::default()
+    }
+
+    pub fn insert(&self, user: &str, password: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.input(password);
+        let stage1 = hasher.result_reset();
+        hasher.input(stage1);
+        let digest = hasher.result().to_vec();
+        self.entries
+            .write()
+            .unwrap()
+            .insert(user.to_string(), digest);
+    }
+
+    /// Remove a user, e.g. once its password is changed.
+    pub fn remove(&self, user: &str) {
+        self.entries.write().unwrap().remove(user);
+    }
+
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+
+    /// Verify the scramble sent by user against the cached digest,
+    /// return false if the user is not cached.
+    pub fn verify(&self, user: &str, scramble: &[u8], salt: &[u8]) -> bool {
+        let entries = self.entries.read().unwrap();
+        let digest = match entries.get(user) {
+            Some(digest) => digest,
+            None => return false,
+        };
+        if scramble.len() != digest.len() {
+            return false;
+        }
+        // scramble = SHA256(password) XOR SHA256(digest + salt), recover
+        // SHA256(password) and check that its hash is the digest.
+        let mut hasher = Sha256::new();
+        hasher.input(digest);
+        hasher.input(salt);
+        let mask = hasher.result_reset();
+        let stage1: Vec<u8> = scramble
+            .iter()
+            .zip(mask.iter())
+            .map(|(a, b)| a ^ b)
+            .collect();
+        hasher.input(&stage1);
+        let candidate = hasher.result();
+        // Compare in constant time.
+        candidate
+            .iter()
+            .zip(digest.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::DEFAULT_SALT;
+    use crate::errors::ProtoError;
+    use crate::proto::caching_sha2::{
+        caching_sha2_more_data, gen_caching_sha2_password, Sha2Cache,
+    };
+
+    #[test]
+    fn test_gen_caching_sha2_password() {
+        assert!(gen_caching_sha2_password("", DEFAULT_SALT).is_empty());
+        assert_eq!(gen_caching_sha2_password("password", DEFAULT_SALT), vec![
+            0x63, 0xf0, 0xb4, 0x4d, 0xf5, 0x5b, 0xf5, 0xd2, 0x54, 0xd2, 0x20, 0xc3, 0x8c, 0xd0,
+            0x8b, 0xee, 0xbc, 0xa8, 0x0d, 0x40, 0xef, 0x2b, 0x14, 0x4a, 0xa2, 0x37, 0x3f, 0x12,
+            0x29, 0xfe, 0xcd, 0x43
+        ]);
+    }
+
+    #[test]
+    fn test_sha2_cache() {
+        let cache = Sha2Cache::new();
+        let scramble = gen_caching_sha2_password("password", DEFAULT_SALT);
+        assert!(!cache.verify("root", &scramble, DEFAULT_SALT));
+
+        cache.insert("root", b"password");
+        assert!(cache.verify("root", &scramble, DEFAULT_SALT));
+        assert!(!cache.verify("root", &scramble, &[0; 20]));
+        assert!(!cache.verify("bob", &scramble, DEFAULT_SALT));
+        let wrong = gen_caching_sha2_password("wrong", DEFAULT_SALT);
+        assert!(!cache.verify("root", &wrong, DEFAULT_SALT));
+
+        cache.remove("root");
+        assert!(!cache.verify("root", &scramble, DEFAULT_SALT));
+    }
+
+    #[test]
+    fn test_caching_sha2_more_data() {
+        assert_eq!(caching_sha2_more_data(&[1, 3], "pw", false).unwrap(), None);
+        assert_eq!(
+            caching_sha2_more_data(&[1, 4], "pw", true).unwrap(),
+            Some(b"pw\0".to_vec())
+        );
+        match caching_sha2_more_data(&[1, 4], "pw", false) {
+            Err(ProtoError::AuthRequiresSecureConnection) => {}
+            _ => panic!("Unexpected result"),
+        }
+        match caching_sha2_more_data(&[0xff, 4], "pw", true) {
+            Err(ProtoError::MalformedAuthMoreData) => {}
+            _ => panic!("Unexpected result"),
+        }
+    }
+}
@@ -0,0 +1,704 @@
+use std::collections::HashMap;
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::constants::{
+    CapabilityFlag, PacketType, AUTH_MORE_DATA_PACKET, CACHING_SHA2_PASSWORD, CHARACTER_SET_UTF8,
+    DEFAULT_CLIENT_CAPABILITY, EOF_PACKET, ERR_PACKET, MYSQL_CLEAR_PASSWORD,
+    MYSQL_NATIVE_PASSWORD, OK_PACKET, SERVER_MORE_RESULTS_EXISTS,
+};
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::auth::{gen_auth_response, parse_auth_switch_request};
+use crate::proto::binary::read_binary_row;
+use crate::proto::caching_sha2::caching_sha2_more_data;
+use crate::proto::packets::{
+    parse_column_definition, parse_end_packet, parse_err_packet, parse_ok_packet, parse_text_row,
+    Packets, ReadLenEncode,
+};
+use crate::proto::prepare::write_com_stmt_execute;
+use crate::proto::{Auth, Greeting};
+use crate::sql_type::{Field, SqlResult, Value};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use dakv_logger::prelude::*;
+
+/// Client is a blocking connection to a MySQL server, the errors reported by
+/// the server are returned as ProtoError::Server and leave the connection
+/// usable.
+///
+/// Client does not support TLS nor the RSA public key exchange, so a
+/// caching_sha2_password login only succeeds when the server verifies the
+/// scramble from its cache, a full authentication or a switch to
+/// mysql_clear_password fails with ProtoError::AuthRequiresSecureConnection.
+pub struct Client {
+    // The stream is a TcpStream, so that a Client is Send, e.g. in a Pool.
+    packets: Packets<TcpStream>,
+    // capability flags negotiated with the server.
+    capability: u32,
+    greeting: Greeting,
+    // status flags of the last OK or EOF packet.
+    status_flags: u16,
+}
+
+impl Client {
+    /// Connect to the server and log in as user, database is selected once
+    /// logged in unless it is empty.
+    pub fn connect<Addr: ToSocketAddrs>(
+        addr: Addr,
+        user: &str,
+        password: &str,
+        database: &str,
+    ) -> ProtoResult<Self> {
+        Client::connect_with(
+            addr,
+            user,
+            password,
+            database,
+            DEFAULT_CLIENT_CAPABILITY,
+            CHARACTER_SET_UTF8,
+        )
+    }
+
+    /// Connect like connect, with the flags of capability the server supports
+    /// and the character set charset, e.g. to log in on behalf of another
+    /// client whose packets are relayed.
+    pub fn connect_with<Addr: ToSocketAddrs>(
+        addr: Addr,
+        user: &str,
+        password: &str,
+        database: &str,
+        capability: u32,
+        charset: u8,
+    ) -> ProtoResult<Self> {
+        let mut client = Client::open(addr)?;
+        client.login(user, password, database, capability, charset)?;
+        Ok(client)
+    }
+
+    /// Connect to the server and read its greeting without logging in, e.g.
+    /// to learn the connection id before the credentials are known. The
+    /// server closes the connection if login is not called in time.
+    pub fn open<Addr: ToSocketAddrs>(addr: Addr) -> ProtoResult<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut packets = Packets::default();
+        packets.set_stream(Box::new(stream));
+        let data = packets.read_ephemeral_packet_direct()?;
+        if data.first() == Some(&ERR_PACKET) {
+            return Err(parse_err_packet(&data));
+        }
+        let mut greeting = Greeting::default();
+        greeting.parse_client_handshake_packet(&data)?;
+        if greeting.capability() & CapabilityFlag::CapabilityClientProtocol41 as u32 == 0 {
+            return Err(ProtoError::ProtocolNotSupport);
+        }
+        Ok(Client {
+            packets,
+            capability: greeting.capability(),
+            greeting,
+            status_flags: 0,
+        })
+    }
+
+    /// Log in to a connection returned by open as user, with the flags of
+    /// capability the server supports and the character set charset,
+    /// database is selected once logged in unless it is empty.
+    pub fn login(
+        &mut self,
+        user: &str,
+        password: &str,
+        database: &str,
+        capability: u32,
+        charset: u8,
+    ) -> ProtoResult<()> {
+        self.capability &= capability;
+        // The server switches the other plugins to the one of the user.
+        let auth_plugin = match self.greeting.auth_plugin_name() {
+            CACHING_SHA2_PASSWORD => CACHING_SHA2_PASSWORD,
+            _ => MYSQL_NATIVE_PASSWORD,
+        };
+        let resp = Auth::write_handshake_resp(
+            self.capability,
+            charset,
+            user.to_string(),
+            password.to_string(),
+            self.greeting.salt(),
+            auth_plugin,
+            database.to_string(),
+            &HashMap::new(),
+        )?;
+        self.packets.write_packet(&resp)?;
+        self.authenticate(auth_plugin, password)
+    }
+
+    /// Return the id of the connection on the server, it is the id KILL takes.
+    pub fn connection_id(&self) -> u32 {
+        self.greeting.connection_id()
+    }
+
+    pub fn server_version(&self) -> &str {
+        self.greeting.server_version()
+    }
+
+    pub fn status_flags(&self) -> u16 {
+        self.status_flags
+    }
+
+    /// Return the capability flags negotiated with the server.
+    pub fn capability(&self) -> u32 {
+        self.capability
+    }
+
+    /// Run sql and return its first result, the other results of a multi
+    /// statement query are discarded.
+    pub fn query(&mut self, sql: &str) -> ProtoResult<SqlResult> {
+        let mut results = self.query_multi(sql)?;
+        Ok(results.remove(0))
+    }
+
+    /// Run sql and return the result of every statement.
+    pub fn query_multi(&mut self, sql: &str) -> ProtoResult<Vec<SqlResult>> {
+        self.write_command(PacketType::ComQuery, sql.as_bytes())?;
+        self.read_results(false)
+    }
+
+    /// Prepare sql on the server, the statement is executed with execute.
+    pub fn prepare(&mut self, sql: &str) -> ProtoResult<Statement> {
+        self.write_command(PacketType::ComStmtPrepare, sql.as_bytes())?;
+        let data = self.packets.read_ephemeral_packet()?;
+        match data.first() {
+            Some(&OK_PACKET) => {}
+            Some(&ERR_PACKET) => return Err(parse_err_packet(&data)),
+            _ => return Err(ProtoError::MalformedPacket),
+        }
+        let mut data = &data[1..];
+        let id = data.read_u32::<LittleEndian>()?;
+        let columns_count = data.read_u16::<LittleEndian>()?;
+        let params_count = data.read_u16::<LittleEndian>()?;
+        let params = self.read_column_definitions(params_count as u64)?;
+        let columns = self.read_column_definitions(columns_count as u64)?;
+        Ok(Statement {
+            id,
+            params,
+            columns,
+        })
+    }
+
+    /// Execute a prepared statement with params bound to its placeholders,
+    /// the rows are sent with the binary protocol. The other results of the
+    /// statement are discarded.
+    pub fn execute(&mut self, stmt: &Statement, params: &[Value]) -> ProtoResult<SqlResult> {
+        if params.len() != stmt.params.len() {
+            return Err(ProtoError::WrongParamsCount(
+                stmt.params.len() as u16,
+                params.len(),
+            ));
+        }
+        let data = write_com_stmt_execute(stmt.id, params)?;
+        self.packets.reset_sequence_id();
+        self.packets.write_packet(&data)?;
+        let mut results = self.read_results(true)?;
+        Ok(results.remove(0))
+    }
+
+    /// Deallocate a prepared statement, the server does not answer
+    /// COM_STMT_CLOSE.
+    pub fn close_statement(&mut self, stmt: Statement) -> ProtoResult<()> {
+        self.write_command(PacketType::ComStmtClose, &stmt.id.to_le_bytes())
+    }
+
+    pub fn ping(&mut self) -> ProtoResult<()> {
+        self.write_command(PacketType::ComPing, &[])?;
+        self.read_ok()
+    }
+
+    /// Reset the session state of the connection, e.g. the prepared
+    /// statements and the variables, without logging in again.
+    pub fn reset_connection(&mut self) -> ProtoResult<()> {
+        self.write_command(PacketType::ComResetConnection, &[])?;
+        self.read_ok()
+    }
+
+    /// Select the default database.
+    pub fn init_db(&mut self, database: &str) -> ProtoResult<()> {
+        self.write_command(PacketType::ComInitDB, database.as_bytes())?;
+        self.read_ok()
+    }
+
+    /// Close the connection, the server does not answer COM_QUIT.
+    pub fn quit(mut self) -> ProtoResult<()> {
+        self.write_command(PacketType::ComQuit, &[])
+    }
+
+    /// Send data, a command followed by its payload, as is. The answer is
+    /// read packet by packet with read_packet.
+    pub fn write_command_packet(&mut self, data: &[u8]) -> ProtoResult<()> {
+        self.packets.reset_sequence_id();
+        self.packets.write_packet(data)?;
+        Ok(())
+    }
+
+    /// Read the next packet of the answer to the last command as is.
+    pub fn read_packet(&mut self) -> ProtoResult<Vec<u8>> {
+        self.packets.read_ephemeral_packet()
+    }
+
+    /// Answer the AuthSwitchRequest and AuthMoreData packets until the server
+    /// accepts or refuses the client.
+    fn authenticate(&mut self, auth_plugin: &str, password: &str) -> ProtoResult<()> {
+        let mut auth_plugin = auth_plugin.to_string();
+        loop {
+            let data = self.packets.read_ephemeral_packet_direct()?;
+            match data.first() {
+                Some(&OK_PACKET) => {
+                    self.status_flags = parse_ok_packet(&data)?.2;
+                    return Ok(());
+                }
+                Some(&ERR_PACKET) => return Err(parse_err_packet(&data)),
+                Some(&EOF_PACKET) => {
+                    let (plugin, salt) = parse_auth_switch_request(&data)?;
+                    debug!("Switch auth plugin to {}", plugin);
+                    // The clear text password is only sent over TLS.
+                    if plugin == MYSQL_CLEAR_PASSWORD {
+                        return Err(ProtoError::AuthRequiresSecureConnection);
+                    }
+                    let resp = gen_auth_response(&plugin, password, &salt)?;
+                    self.packets.write_packet(&resp)?;
+                    auth_plugin = plugin;
+                }
+                Some(&AUTH_MORE_DATA_PACKET) if auth_plugin == CACHING_SHA2_PASSWORD => {
+                    if let Some(resp) = caching_sha2_more_data(&data, password, false)? {
+                        self.packets.write_packet(&resp)?;
+                    }
+                }
+                _ => return Err(ProtoError::MalformedPacket),
+            }
+        }
+    }
+
+    fn write_command(&mut self, command: PacketType, payload: &[u8]) -> ProtoResult<()> {
+        let mut data = Vec::with_capacity(1 + payload.len());
+        data.push(command as u8);
+        data.extend_from_slice(payload);
+        self.write_command_packet(&data)
+    }
+
+    fn read_ok(&mut self) -> ProtoResult<()> {
+        let data = self.packets.read_ephemeral_packet()?;
+        match data.first() {
+            Some(&OK_PACKET) => {
+                self.status_flags = parse_ok_packet(&data)?.2;
+                Ok(())
+            }
+            Some(&ERR_PACKET) => Err(parse_err_packet(&data)),
+            _ => Err(ProtoError::MalformedPacket),
+        }
+    }
+
+    /// Read the results of a command until the server has no more results.
+    fn read_results(&mut self, binary: bool) -> ProtoResult<Vec<SqlResult>> {
+        let mut results = vec![];
+        loop {
+            results.push(self.read_result(binary)?);
+            if self.status_flags & SERVER_MORE_RESULTS_EXISTS == 0 {
+                return Ok(results);
+            }
+        }
+    }
+
+    /// Read an OK packet or a result set, whose rows are in the binary
+    /// protocol if binary.
+    fn read_result(&mut self, binary: bool) -> ProtoResult<SqlResult> {
+        let data = self.packets.read_ephemeral_packet()?;
+        match data.first() {
+            Some(&OK_PACKET) => {
+                let (affected_rows, insert_id, flags, _) = parse_ok_packet(&data)?;
+                self.status_flags = flags;
+                return Ok(SqlResult {
+                    affected_rows,
+                    insert_id,
+                    ..Default::default()
+                });
+            }
+            Some(&ERR_PACKET) => return Err(parse_err_packet(&data)),
+            Some(_) => {}
+            None => return Err(ProtoError::MalformedPacket),
+        }
+        let count = (&data[..]).read_len_int()?;
+        let fields = self.read_column_definitions(count)?;
+        let mut rows = vec![];
+        loop {
+            let data = self.packets.read_ephemeral_packet()?;
+            match data.first() {
+                Some(&ERR_PACKET) => return Err(parse_err_packet(&data)),
+                Some(&EOF_PACKET) if self.is_end(&data) => break,
+                Some(_) if binary => rows.push(read_binary_row(&data, &fields)?),
+                Some(_) => rows.push(parse_text_row(&data, &fields)?),
+                None => return Err(ProtoError::MalformedPacket),
+            }
+        }
+        Ok(SqlResult {
+            fields,
+            rows,
+            ..Default::default()
+        })
+    }
+
+    /// Read count column definitions, followed by an EOF packet unless the
+    /// client deprecates EOF.
+    fn read_column_definitions(&mut self, count: u64) -> ProtoResult<Vec<Field>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        let mut fields = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let data = self.packets.read_ephemeral_packet()?;
+            fields.push(parse_column_definition(&data)?);
+        }
+        if !self.deprecate_eof() {
+            let data = self.packets.read_ephemeral_packet()?;
+            if !self.is_end(&data) {
+                return Err(ProtoError::MalformedPacket);
+            }
+        }
+        Ok(fields)
+    }
+
+    fn deprecate_eof(&self) -> bool {
+        self.capability & CapabilityFlag::CapabilityClientDeprecateEOF as u32 != 0
+    }
+
+    /// Whether data ends the column definitions or the rows, keep the status
+    /// flags it carries.
+    fn is_end(&mut self, data: &[u8]) -> bool {
+        match parse_end_packet(data, self.deprecate_eof()) {
+            Some(flags) => {
+                self.status_flags = flags;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Statement is a statement prepared by Client::prepare.
+#[derive(Debug, Clone)]
+pub struct Statement {
+    id: u32,
+    // params are the definitions of the placeholders.
+    params: Vec<Field>,
+    // columns are the definitions of the result columns, empty if the
+    // statement returns no rows.
+    columns: Vec<Field>,
+}
+
+impl Statement {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn params_count(&self) -> u16 {
+        self.params.len() as u16
+    }
+
+    pub fn columns(&self) -> &[Field] {
+        &self.columns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::constants::{ServerError, StateError, CACHING_SHA2_PASSWORD};
+    use crate::errors::{ProtoError, SqlError};
+    use crate::proto::{native_password_hash, verify_native_password_hash};
+    use crate::proto::{Auth, Client, Handler, Listener, PrepareData, Session};
+    use crate::sql_type::{Field, MysqlType, SqlResult, Type, Value};
+
+    struct MockHandler {}
+
+    impl Handler for MockHandler {
+        fn new_connection(&self, _session: &mut Session) {}
+        fn close_connection(&self, _session: &mut Session) {}
+        fn com_query(
+            &self,
+            session: &mut Session,
+            sql: &str,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            match sql {
+                "SELECT id, name FROM t" => callback(SqlResult {
+                    fields: vec![
+                        Field {
+                            name: "id".to_string(),
+                            typ: MysqlType::Int64 as Type,
+                            ..Default::default()
+                        },
+                        Field {
+                            name: "name".to_string(),
+                            typ: MysqlType::Varchar as Type,
+                            ..Default::default()
+                        },
+                    ],
+                    rows: vec![
+                        vec![
+                            Value {
+                                typ: MysqlType::Int64 as Type,
+                                val: b"1".to_vec(),
+                            },
+                            Value {
+                                typ: MysqlType::Varchar as Type,
+                                val: b"a".to_vec(),
+                            },
+                        ],
+                        vec![
+                            Value {
+                                typ: MysqlType::Int64 as Type,
+                                val: b"2".to_vec(),
+                            },
+                            Value {
+                                typ: MysqlType::NullType as Type,
+                                val: vec![],
+                            },
+                        ],
+                    ],
+                    ..Default::default()
+                }),
+                "SELECT DATABASE()" => callback(SqlResult {
+                    fields: vec![Field {
+                        name: "DATABASE()".to_string(),
+                        typ: MysqlType::Varchar as Type,
+                        ..Default::default()
+                    }],
+                    rows: vec![vec![Value {
+                        typ: MysqlType::Varchar as Type,
+                        val: session.schema().as_bytes().to_vec(),
+                    }]],
+                    ..Default::default()
+                }),
+                "INSERT INTO t VALUES (3)" => callback(SqlResult {
+                    affected_rows: 1,
+                    insert_id: 3,
+                    ..Default::default()
+                }),
+                _ => Err(SqlError::new(
+                    ServerError::ERNoSuchTable,
+                    StateError::SSNoSuchTable,
+                    "Table 'test.missing' doesn't exist",
+                )
+                .into()),
+            }
+        }
+        fn com_prepare(
+            &self,
+            _session: &mut Session,
+            sql: &str,
+            _params_count: u16,
+        ) -> io::Result<Vec<Field>> {
+            match sql {
+                "SELECT * FROM t WHERE id = ? AND score > ?" => Ok(vec![
+                    Field {
+                        name: "id".to_string(),
+                        typ: MysqlType::Uint32 as Type,
+                        ..Default::default()
+                    },
+                    Field {
+                        name: "name".to_string(),
+                        typ: MysqlType::Varchar as Type,
+                        ..Default::default()
+                    },
+                    Field {
+                        name: "score".to_string(),
+                        typ: MysqlType::Float64 as Type,
+                        ..Default::default()
+                    },
+                    Field {
+                        name: "created".to_string(),
+                        typ: MysqlType::Datetime as Type,
+                        ..Default::default()
+                    },
+                ]),
+                "DELETE FROM t WHERE id = ?" => Ok(vec![]),
+                _ => Err(SqlError::new(
+                    ServerError::ERNoSuchTable,
+                    StateError::SSNoSuchTable,
+                    "Table 'test.missing' doesn't exist",
+                )
+                .into()),
+            }
+        }
+        fn com_stmt_execute(
+            &self,
+            _session: &mut Session,
+            prepare: &PrepareData,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            if prepare.columns_count == 0 {
+                return callback(SqlResult {
+                    affected_rows: 1,
+                    ..Default::default()
+                });
+            }
+            // Echo the bound id.
+            let id = &prepare.params[0];
+            callback(SqlResult {
+                fields: self.com_prepare(_session, &prepare.prepare_stmt, 2)?,
+                rows: vec![vec![
+                    Value {
+                        typ: MysqlType::Uint32 as Type,
+                        val: id.val.clone(),
+                    },
+                    Value::default(),
+                    Value::from(1.5),
+                    Value {
+                        typ: MysqlType::Datetime as Type,
+                        val: b"2020-02-29 12:34:56".to_vec(),
+                    },
+                ]],
+                ..Default::default()
+            })
+        }
+        fn com_init_db(&self, _session: &mut Session, schema: &str) -> bool {
+            schema == "test"
+        }
+        fn check_auth(&self, auth: &Auth, salt: &[u8], _addr: &SocketAddr) -> bool {
+            verify_native_password_hash(auth.auth_response(), salt, &native_password_hash("pw"))
+        }
+    }
+
+    fn listen() -> SocketAddr {
+        let mut listener = Listener::new_tcp_listener("127.0.0.1:0");
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.accept(Arc::new(MockHandler {})));
+        addr
+    }
+
+    #[test]
+    fn test_connect() {
+        let addr = listen();
+        match Client::connect(addr, "root", "wrong", "") {
+            Err(ProtoError::Server(code, state, _)) => {
+                assert_eq!(code, ServerError::ERAccessDeniedError as u16);
+                assert_eq!(state, "28000");
+            }
+            _ => panic!("Wrong password is accepted"),
+        }
+        match Client::connect(addr, "root", "pw", "unknown") {
+            Err(ProtoError::Server(code, _, message)) => {
+                assert_eq!(code, ServerError::ERBadDb as u16);
+                assert_eq!(message, "Unknown database 'unknown'");
+            }
+            _ => panic!("Unknown database is accepted"),
+        }
+
+        let mut client = Client::connect(addr, "root", "pw", "test").unwrap();
+        assert_eq!(client.server_version(), "5.7.0");
+        let result = client.query("SELECT DATABASE()").unwrap();
+        assert_eq!(result.rows[0][0].val, b"test");
+        client.ping().unwrap();
+        client.quit().unwrap();
+    }
+
+    #[test]
+    fn test_caching_sha2_full_auth() {
+        let mut listener = Listener::new_tcp_listener("127.0.0.1:0");
+        listener
+            .set_auth_plugin_name(CACHING_SHA2_PASSWORD)
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.accept(Arc::new(MockHandler {})));
+        // The cache is empty, the server asks for the password in clear text.
+        match Client::connect(addr, "root", "pw", "") {
+            Err(ProtoError::AuthRequiresSecureConnection) => {}
+            _ => panic!("Full authentication over an insecure connection"),
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let addr = listen();
+        let mut client = Client::connect(addr, "root", "pw", "").unwrap();
+
+        let result = client.query("SELECT id, name FROM t").unwrap();
+        let names: Vec<_> = result.fields.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["id", "name"]);
+        assert_eq!(result.fields[0].typ, MysqlType::Int64 as Type);
+        assert_eq!(result.rows.len(), 2);
+        assert_eq!(result.rows[0][1].val, b"a");
+        assert_eq!(result.rows[1][0].val, b"2");
+        assert!(result.rows[1][1].is_null());
+
+        let result = client.query("INSERT INTO t VALUES (3)").unwrap();
+        assert_eq!((result.affected_rows, result.insert_id), (1, 3));
+
+        // Server errors leave the connection usable.
+        match client.query("SELECT * FROM missing") {
+            Err(ProtoError::Server(code, state, message)) => {
+                assert_eq!(code, ServerError::ERNoSuchTable as u16);
+                assert_eq!(state, "42S02");
+                assert_eq!(message, "Table 'test.missing' doesn't exist");
+            }
+            _ => panic!("Query should fail"),
+        }
+        match client.init_db("unknown") {
+            Err(ProtoError::Server(code, _, _)) => assert_eq!(code, ServerError::ERBadDb as u16),
+            _ => panic!("Unknown database is accepted"),
+        }
+        client.init_db("test").unwrap();
+
+        let results = client
+            .query_multi("INSERT INTO t VALUES (3); SELECT id, name FROM t")
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].affected_rows, 1);
+        assert_eq!(results[1].rows.len(), 2);
+        let result = client.query("SELECT DATABASE()").unwrap();
+        assert_eq!(result.rows[0][0].val, b"test");
+    }
+
+    #[test]
+    fn test_prepare() {
+        let addr = listen();
+        let mut client = Client::connect(addr, "root", "pw", "").unwrap();
+
+        let stmt = client
+            .prepare("SELECT * FROM t WHERE id = ? AND score > ?")
+            .unwrap();
+        assert_eq!(stmt.params_count(), 2);
+        assert_eq!(stmt.columns().len(), 4);
+        for id in 1..4u64 {
+            let result = client
+                .execute(&stmt, &[Value::from(id), Value::from(0.5)])
+                .unwrap();
+            let row = &result.rows[0];
+            assert_eq!(row[0].typ, MysqlType::Uint32 as Type);
+            assert_eq!(row[0].val, id.to_string().into_bytes());
+            assert!(row[1].is_null());
+            assert_eq!(row[2].typ, MysqlType::Float64 as Type);
+            assert_eq!(row[2].val, b"1.5");
+            assert_eq!(row[3].val, b"2020-02-29 12:34:56");
+        }
+        match client.execute(&stmt, &[Value::from(1i64)]) {
+            Err(ProtoError::WrongParamsCount(2, 1)) => {}
+            _ => panic!("Missing parameter is accepted"),
+        }
+        client.close_statement(stmt).unwrap();
+
+        let stmt = client.prepare("DELETE FROM t WHERE id = ?").unwrap();
+        assert!(stmt.columns().is_empty());
+        let result = client.execute(&stmt, &[Value::default()]).unwrap();
+        assert_eq!(result.affected_rows, 1);
+
+        match client.prepare("SELECT * FROM missing") {
+            Err(ProtoError::Server(code, state, _)) => {
+                assert_eq!(code, ServerError::ERNoSuchTable as u16);
+                assert_eq!(state, "42S02");
+            }
+            _ => panic!("Unknown statement is prepared"),
+        }
+        client.ping().unwrap();
+    }
+}
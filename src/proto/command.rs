@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use crate::constants::{CapabilityFlag, PacketType, ServerError, StateError, OK_PACKET};
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::packets::{
+    end_result_packet, err_packet, field_list_packets, ok_packet_with_header,
+    parse_com_field_list, parse_com_init_db, parse_com_process_kill, parse_com_query,
+    parse_com_statement, parse_set_option, prepare_ok_packets, ResultEncoder,
+};
+use crate::proto::prepare::{parse_com_stmt_execute, parse_com_stmt_send_long_data, PrepareData};
+use crate::proto::query::{count_params, parse_kill, parse_show_processlist, split_statements};
+use crate::proto::registry::{ProcessInfo, Registry};
+use crate::sql_type::Field;
+
+use dakv_logger::prelude::*;
+
+/// Command is a decoded command, the ones the handler takes part in are left
+/// to the connection, the others are answered by the packets of Reply.
+pub enum Command {
+    Quit,
+    InitDb(String),
+    FieldList(String, String),
+    // The prepared statements are already dropped.
+    ResetConnection,
+    // The statements of the query, split when multi statements are enabled.
+    Query(Vec<String>),
+    // A new statement, kept once the handler accepts it, see Commands::prepared.
+    Prepare(PrepareData),
+    Execute(PrepareData),
+    ProcessInfo,
+    ProcessKill(u32),
+    // Packets answering the command, none for the commands without a response.
+    Reply(Vec<Vec<u8>>),
+}
+
+/// QueryStatement tells how a statement of a query is run, KILL and SHOW
+/// PROCESSLIST are answered from the registry of the listener.
+pub enum QueryStatement {
+    Query,
+    // The connection id and whether only its running query is killed.
+    Kill(u32, bool),
+    // Whether the queries are shown in full.
+    Processlist(bool),
+}
+
+/// Commands holds the command state of a connection: the negotiated
+/// capabilities, the prepared statements and the registry. It decodes the
+/// commands and encodes their answers, the blocking and the async connections
+/// only move the packets and call their handler.
+pub struct Commands {
+    capability: u32,
+    status_flags: u16,
+    // Prepared statements of the connection, by statement id.
+    prepares: HashMap<u32, PrepareData>,
+    last_stmt_id: u32,
+    // registry of the listener, the connections are killed through it.
+    registry: Option<Arc<Registry>>,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Commands::new()
+    }
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Commands {
+            capability: 0,
+            status_flags: 0,
+            prepares: HashMap::new(),
+            last_stmt_id: 0,
+            registry: None,
+        }
+    }
+
+    /// Set the capabilities negotiated during the handshake, COM_SET_OPTION
+    /// may toggle multi statements afterwards.
+    pub fn set_capability(&mut self, capability: u32) {
+        self.capability = capability;
+    }
+
+    pub fn capability(&self) -> u32 {
+        self.capability
+    }
+
+    /// Set the status flags of the answers to the next command.
+    pub fn set_status_flags(&mut self, status_flags: u16) {
+        self.status_flags = status_flags;
+    }
+
+    pub fn status_flags(&self) -> u16 {
+        self.status_flags
+    }
+
+    /// Let the connection kill the other connections of the registry.
+    pub fn set_registry(&mut self, registry: Arc<Registry>) {
+        self.registry = Some(registry);
+    }
+
+    /// Drop the prepared statements of the connection.
+    pub fn clear_prepares(&mut self) {
+        self.prepares.clear();
+    }
+
+    /// Decode a command and apply what it does to the state of the connection.
+    pub fn decode(&mut self, data: &[u8]) -> ProtoResult<Command> {
+        let pt = match data.first() {
+            Some(&pt) => pt,
+            None => {
+                debug!("Empty command packet");
+                return malformed_reply();
+            }
+        };
+        debug!("Packet type {}", PacketType::from(pt as u64).to_string());
+
+        let command = match pt.into() {
+            PacketType::ComQuit => {
+                debug!("ComQuit");
+                Command::Quit
+            }
+            PacketType::ComInitDB => {
+                let db = match parse_com_init_db(data) {
+                    Ok(db) => db,
+                    Err(_) => return malformed_reply(),
+                };
+                debug!("ComInitDB {}", db);
+                Command::InitDb(db)
+            }
+            PacketType::ComPing => Command::Reply(vec![self.ok_packet()?]),
+            PacketType::ComProcessInfo => Command::ProcessInfo,
+            PacketType::ComProcessKill => {
+                let id = parse_com_process_kill(data)?;
+                debug!("ComProcessKill {}", id);
+                Command::ProcessKill(id)
+            }
+            PacketType::ComFieldList => {
+                let (table, wildcard) = parse_com_field_list(data);
+                debug!("ComFieldList {} {}", table, wildcard);
+                Command::FieldList(table, wildcard)
+            }
+            PacketType::ComResetConnection => {
+                debug!("ComResetConnection");
+                self.clear_prepares();
+                Command::ResetConnection
+            }
+            PacketType::ComQuery => {
+                let query = match parse_com_query(data) {
+                    Ok(query) => query,
+                    Err(_) => return malformed_reply(),
+                };
+                let mut statements = if self.capability
+                    & CapabilityFlag::CapabilityClientMultiStatements as u32
+                    != 0
+                {
+                    split_statements(&query)
+                } else {
+                    vec![]
+                };
+                if statements.is_empty() {
+                    statements.push(query);
+                }
+                Command::Query(statements)
+            }
+            PacketType::ComSetOption => {
+                let pkg = match parse_set_option(data) {
+                    Ok(0) => {
+                        self.capability |= CapabilityFlag::CapabilityClientMultiStatements as u32;
+                        end_result_packet(self.capability, self.status_flags, 0, 0, 0)?
+                    }
+                    Ok(1) => {
+                        self.capability &=
+                            !(CapabilityFlag::CapabilityClientMultiStatements as u32);
+                        end_result_packet(self.capability, self.status_flags, 0, 0, 0)?
+                    }
+                    Ok(_) => unknown_com_packet("Unknown set option".to_string())?,
+                    Err(_) => unknown_com_packet("Error parsing set option".to_string())?,
+                };
+                Command::Reply(vec![pkg])
+            }
+            PacketType::ComStmtPrepare => {
+                let query = match parse_com_query(data) {
+                    Ok(query) => query,
+                    Err(_) => return malformed_reply(),
+                };
+                debug!("ComStmtPrepare {}", query);
+                self.last_stmt_id += 1;
+                let params_count = count_params(&query);
+                Command::Prepare(PrepareData::new(self.last_stmt_id, query, params_count))
+            }
+            PacketType::ComStmtExecute => match parse_com_stmt_execute(data, &mut self.prepares) {
+                Ok(stmt_id) => Command::Execute(self.prepares[&stmt_id].clone()),
+                Err(ProtoError::UnknownStmtHandler(stmt_id)) => {
+                    Command::Reply(vec![unknown_stmt_packet(stmt_id, "mysqld_stmt_execute")?])
+                }
+                Err(_) => Command::Reply(vec![unknown_com_packet(
+                    "Error parsing statement execute".to_string(),
+                )?]),
+            },
+            PacketType::ComStmtSendLongData => {
+                // No response is sent, even on error.
+                if let Ok((stmt_id, param_id, long_data)) = parse_com_stmt_send_long_data(data) {
+                    if let Some(prepare) = self.prepares.get_mut(&stmt_id) {
+                        prepare.append_long_data(param_id, long_data);
+                    }
+                }
+                Command::Reply(vec![])
+            }
+            PacketType::ComStmtReset => {
+                let stmt_id = parse_com_statement(data)?;
+                let pkg = match self.prepares.get_mut(&stmt_id) {
+                    Some(prepare) => {
+                        prepare.reset();
+                        self.ok_packet()?
+                    }
+                    None => unknown_stmt_packet(stmt_id, "mysqld_stmt_reset")?,
+                };
+                Command::Reply(vec![pkg])
+            }
+            PacketType::ComStmtClose => {
+                // No response is sent.
+                let stmt_id = parse_com_statement(data)?;
+                self.prepares.remove(&stmt_id);
+                Command::Reply(vec![])
+            }
+            cmd => {
+                let cmd_str: &'static str = cmd.into();
+                debug!("Unknown command {}", cmd_str);
+                Command::Reply(vec![unknown_com_packet(format!(
+                    "Unknown command: {}",
+                    cmd_str
+                ))?])
+            }
+        };
+        Ok(command)
+    }
+
+    /// Tell how a statement of a query is run, KILL and SHOW PROCESSLIST are
+    /// only answered by the connections of a listener.
+    pub fn statement(&self, sql: &str) -> QueryStatement {
+        if self.registry.is_some() {
+            if let Some((id, query_only)) = parse_kill(sql) {
+                return QueryStatement::Kill(id, query_only);
+            }
+            if let Some(full) = parse_show_processlist(sql) {
+                return QueryStatement::Processlist(full);
+            }
+        }
+        QueryStatement::Query
+    }
+
+    /// OK packet with the status flags of the connection.
+    pub fn ok_packet(&self) -> io::Result<Vec<u8>> {
+        ok_packet_with_header(OK_PACKET, 0, 0, self.status_flags, 0)
+    }
+
+    /// Column definitions answering COM_FIELD_LIST.
+    pub fn field_list(&self, fields: &[Field]) -> io::Result<Vec<Vec<u8>>> {
+        field_list_packets(self.capability, self.status_flags, fields)
+    }
+
+    /// Keep a statement accepted by the handler, return the COM_STMT_PREPARE_OK
+    /// packets announcing it.
+    pub fn prepared(
+        &mut self,
+        mut prepare: PrepareData,
+        fields: &[Field],
+    ) -> io::Result<Vec<Vec<u8>>> {
+        prepare.columns_count = fields.len() as u16;
+        let packets = prepare_ok_packets(self.capability, self.status_flags, &prepare, fields)?;
+        self.prepares.insert(prepare.statement_id, prepare);
+        Ok(packets)
+    }
+
+    /// Return the encoder of the results of a statement.
+    pub fn encoder(&self, more: bool, binary: bool) -> ResultEncoder {
+        ResultEncoder::new(self.capability, self.status_flags, more, binary)
+    }
+
+    /// Return the connections of the registry, for SHOW PROCESSLIST.
+    pub fn processes(&self) -> Vec<ProcessInfo> {
+        match &self.registry {
+            Some(registry) => registry.processlist(),
+            None => vec![],
+        }
+    }
+
+    /// Return the user of the connection KILL targets.
+    pub fn kill_target(&self, id: u32) -> ProtoResult<String> {
+        self.registry
+            .as_ref()
+            .and_then(|registry| registry.user(id))
+            .ok_or(ProtoError::NoSuchThread(id))
+    }
+
+    /// Kill a connection, or only its running query.
+    pub fn kill(&self, id: u32, query_only: bool) {
+        debug!("Kill connection {}, query only: {}", id, query_only);
+        if let Some(registry) = &self.registry {
+            registry.kill(id, query_only);
+        }
+    }
+}
+
+fn unknown_com_packet(message: String) -> io::Result<Vec<u8>> {
+    err_packet(
+        ServerError::ERUnknownComError as u16,
+        StateError::SSUnknownComError.into(),
+        message,
+    )
+}
+
+/// Answer a command whose packet cannot be decoded, the connection stays open.
+fn malformed_reply() -> ProtoResult<Command> {
+    let pkg = unknown_com_packet("Malformed packet".to_string())?;
+    Ok(Command::Reply(vec![pkg]))
+}
+
+fn unknown_stmt_packet(stmt_id: u32, command: &str) -> io::Result<Vec<u8>> {
+    err_packet(
+        ServerError::ERUnknownStmtHandler as u16,
+        StateError::SSUnknownSQLState.into(),
+        format!(
+            "Unknown prepared statement handler ({}) given to {}",
+            stmt_id, command
+        ),
+    )
+}
@@ -0,0 +1,288 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::Client;
+use crate::sql_type::SqlResult;
+
+use dakv_logger::prelude::*;
+
+/// FailoverClient runs commands on one of several hosts. On a connection
+/// error it reconnects with backoff, starting from the host it was connected
+/// to and failing over to the next ones. The other errors are returned
+/// unchanged. Note that a command failing with a connection error may have
+/// been applied before it is retried.
+pub struct FailoverClient {
+    hosts: Vec<String>,
+    user: String,
+    password: String,
+    database: String,
+    max_retries: u32,
+    // backoff is the wait before the first retry, it doubles on every retry.
+    backoff: Duration,
+    // Only connect to the hosts which are not read only.
+    require_writable: bool,
+    // current is the index of the host connected to, or tried first.
+    current: usize,
+    client: Option<Client>,
+}
+
+impl FailoverClient {
+    /// Create a client of hosts, it connects on its first command.
+    pub fn new(hosts: &[&str], user: &str, password: &str, database: &str) -> Self {
+        FailoverClient {
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            user: user.to_string(),
+            password: password.to_string(),
+            database: database.to_string(),
+            max_retries: 3,
+            backoff: Duration::from_millis(100),
+            require_writable: false,
+            current: 0,
+            client: None,
+        }
+    }
+
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    pub fn set_backoff(&mut self, backoff: Duration) {
+        self.backoff = backoff;
+    }
+
+    /// Skip the hosts whose @@global.read_only is set, e.g. the replicas.
+    pub fn set_require_writable(&mut self, require_writable: bool) {
+        self.require_writable = require_writable;
+    }
+
+    /// Return the host connected to, if any.
+    pub fn host(&self) -> Option<&str> {
+        self.client
+            .as_ref()
+            .map(|_| self.hosts[self.current].as_str())
+    }
+
+    /// Return the connection, connect first if needed.
+    pub fn client(&mut self) -> ProtoResult<&mut Client> {
+        if self.client.is_none() {
+            self.connect()?;
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    pub fn query(&mut self, sql: &str) -> ProtoResult<SqlResult> {
+        self.run(|client| client.query(sql))
+    }
+
+    pub fn ping(&mut self) -> ProtoResult<()> {
+        self.run(|client| client.ping())
+    }
+
+    /// Run f on the connection, retry it on a new connection after a
+    /// connection error.
+    pub fn run<T, F>(&mut self, mut f: F) -> ProtoResult<T>
+    where
+        F: FnMut(&mut Client) -> ProtoResult<T>,
+    {
+        let mut retries = 0;
+        loop {
+            let result = match self.client() {
+                Ok(client) => f(client),
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if is_retryable(&e) && retries < self.max_retries => {
+                    warn!("Retry on connection error: {}", e);
+                    // Drop the broken connection, the next one starts with
+                    // the next host.
+                    if self.client.take().is_some() {
+                        self.current = (self.current + 1) % self.hosts.len();
+                    }
+                    thread::sleep(self.backoff * 2u32.pow(retries.min(10)));
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Connect to the first available host, starting from the current one.
+    fn connect(&mut self) -> ProtoResult<()> {
+        let mut last_err = ProtoError::NoWritableHost;
+        for i in 0..self.hosts.len() {
+            let index = (self.current + i) % self.hosts.len();
+            let host = self.hosts[index].as_str();
+            let mut client =
+                match Client::connect(host, &self.user, &self.password, &self.database) {
+                    Ok(client) => client,
+                    Err(e) if e.is_conn_err() => {
+                        debug!("Connect to {} failed: {}", host, e);
+                        last_err = e;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+            if self.require_writable && !is_writable(&mut client) {
+                debug!("Skip read only host {}", host);
+                last_err = ProtoError::NoWritableHost;
+                continue;
+            }
+            self.current = index;
+            self.client = Some(client);
+            return Ok(());
+        }
+        Err(last_err)
+    }
+}
+
+/// A host may become writable once a replica is promoted.
+fn is_retryable(err: &ProtoError) -> bool {
+    err.is_conn_err() || matches!(err, ProtoError::NoWritableHost)
+}
+
+/// A host failing the check is skipped like a read only one.
+fn is_writable(client: &mut Client) -> bool {
+    match client.query("SELECT @@global.read_only") {
+        Ok(result) => match result.rows.first().and_then(|row| row.first()) {
+            Some(value) => value.val == b"0",
+            None => false,
+        },
+        Err(e) => {
+            debug!("Check read only failed: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::constants::{ServerError, StateError};
+    use crate::errors::{ProtoError, SqlError};
+    use crate::proto::{Auth, Client, FailoverClient, Handler, Listener, Session};
+    use crate::sql_type::{Field, MysqlType, SqlResult, Type, Value};
+
+    struct RoleHandler {
+        // None fails the read only check.
+        read_only: Option<bool>,
+    }
+
+    impl Handler for RoleHandler {
+        fn new_connection(&self, _session: &mut Session) {}
+        fn close_connection(&self, _session: &mut Session) {}
+        fn com_query(
+            &self,
+            _session: &mut Session,
+            sql: &str,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            match sql {
+                "SELECT @@global.read_only" => match self.read_only {
+                    Some(read_only) => callback(SqlResult {
+                        fields: vec![Field {
+                            name: "@@global.read_only".to_string(),
+                            typ: MysqlType::Int64 as Type,
+                            ..Default::default()
+                        }],
+                        rows: vec![vec![Value::from(read_only as i64)]],
+                        ..Default::default()
+                    }),
+                    None => Err(SqlError::new(
+                        ServerError::ERUnknownSystemVariable,
+                        StateError::SSUnknownSQLState,
+                        "Unknown system variable 'read_only'",
+                    )
+                    .into()),
+                },
+                "SELECT * FROM missing" => Err(SqlError::new(
+                    ServerError::ERNoSuchTable,
+                    StateError::SSNoSuchTable,
+                    "Table 'test.missing' doesn't exist",
+                )
+                .into()),
+                _ => callback(SqlResult {
+                    affected_rows: 1,
+                    ..Default::default()
+                }),
+            }
+        }
+        fn check_auth(&self, _auth: &Auth, _salt: &[u8], _addr: &SocketAddr) -> bool {
+            true
+        }
+    }
+
+    fn listen(read_only: Option<bool>) -> String {
+        let mut listener = Listener::new_tcp_listener("127.0.0.1:0");
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.accept(Arc::new(RoleHandler { read_only })));
+        addr.to_string()
+    }
+
+    /// Return an address nothing listens on.
+    fn closed_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_failover() {
+        let dead = closed_addr();
+        let live = listen(Some(false));
+        let mut client = FailoverClient::new(&[&dead, &live], "root", "", "");
+        client.set_backoff(Duration::from_millis(1));
+        assert_eq!(client.query("UPDATE t SET a = 1").unwrap().affected_rows, 1);
+        assert_eq!(client.host(), Some(live.as_str()));
+
+        // Server errors are returned without reconnecting.
+        let id = client.client().unwrap().connection_id();
+        match client.query("SELECT * FROM missing") {
+            Err(ProtoError::Server(code, _, _)) => {
+                assert_eq!(code, ServerError::ERNoSuchTable as u16)
+            }
+            _ => panic!("Query should fail"),
+        }
+        assert_eq!(client.client().unwrap().connection_id(), id);
+
+        // The killed connection is replaced.
+        let mut other = Client::connect(live.as_str(), "root", "", "").unwrap();
+        other.query(&format!("KILL {}", id)).unwrap();
+        assert_eq!(client.query("UPDATE t SET a = 1").unwrap().affected_rows, 1);
+        assert_ne!(client.client().unwrap().connection_id(), id);
+        assert_eq!(client.host(), Some(live.as_str()));
+
+        let mut client = FailoverClient::new(&[&dead], "root", "", "");
+        client.set_max_retries(2);
+        client.set_backoff(Duration::from_millis(1));
+        match client.ping() {
+            Err(e) => assert!(e.is_conn_err()),
+            Ok(_) => panic!("Nothing listens"),
+        }
+    }
+
+    #[test]
+    fn test_require_writable() {
+        let replica = listen(Some(true));
+        let unknown = listen(None);
+        let primary = listen(Some(false));
+        // The host failing the read only check is skipped too.
+        let mut client = FailoverClient::new(&[&replica, &unknown, &primary], "root", "", "");
+        client.set_require_writable(true);
+        client.ping().unwrap();
+        assert_eq!(client.host(), Some(primary.as_str()));
+
+        let mut client = FailoverClient::new(&[&replica], "root", "", "");
+        client.set_require_writable(true);
+        client.set_max_retries(1);
+        client.set_backoff(Duration::from_millis(1));
+        match client.ping() {
+            Err(ProtoError::NoWritableHost) => {}
+            _ => panic!("Read only host is used"),
+        }
+    }
+}
@@ -27,18 +27,23 @@ fn byte_rand(min: u64, max: u64) -> u8 {
     (min + rng.gen_range(0, max - min)) as u8
 }
 
+fn gen_salt() -> Vec<u8> {
+    let mut salt = vec![0; 20];
+    for item in &mut salt {
+        *item = byte_rand(1, 123);
+    }
+    salt
+}
+
 impl Greeting {
     pub fn new(connection_id: u32, server_version: String) -> Box<Self> {
-        let mut salt = vec![0; 20];
-        for item in &mut salt {
-            *item = byte_rand(1, 123);
-        }
+        let salt = gen_salt();
         box Greeting {
             status_flag: SERVER_STATUS_AUTOCOMMIT,
             capability: DEFAULT_SERVER_CAPABILITY,
             connection_id,
             server_version,
-            auth_plugin_name: "".to_string(),
+            auth_plugin_name: MYSQL_NATIVE_PASSWORD.to_string(),
             salt,
         }
     }
@@ -51,6 +56,32 @@ impl Greeting {
         self.capability
     }
 
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+
+    pub fn server_version(&self) -> &str {
+        self.server_version.as_str()
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        self.salt.as_slice()
+    }
+
+    /// Replace the salt, e.g. before sending an AuthSwitchRequest.
+    pub fn regenerate_salt(&mut self) {
+        self.salt = gen_salt();
+    }
+
+    pub fn auth_plugin_name(&self) -> &str {
+        self.auth_plugin_name.as_str()
+    }
+
+    /// Set the auth plugin advertised to the client, default to mysql_native_password.
+    pub fn set_auth_plugin_name(&mut self, auth_plugin_name: &str) {
+        self.auth_plugin_name = auth_plugin_name.to_string();
+    }
+
     /// Initial Handshake Packet - protocol version 10
     /// See https://dev.mysql.com/doc/internals/en/connection-phase-packets.html#packet-Protocol::HandshakeV10
     pub fn write_handshake_v10(&mut self, enable_tls: bool) -> io::Result<Vec<u8>> {
@@ -85,92 +116,97 @@ impl Greeting {
         buf.write_u8(0)?;
 
         // string[NUL]    auth-plugin name
-        buf.write_all(MYSQL_NATIVE_PASSWORD.as_ref())?;
+        buf.write_all(self.auth_plugin_name.as_bytes())?;
         buf.write_u8(0)?;
         Ok(buf)
     }
 
     pub fn parse_client_handshake_packet(&mut self, payload: &[u8]) -> ProtoResult<()> {
         let mut payload = Cursor::new(payload);
-        // Parse protocol version
+        // Parse protocol version, always 10
         match payload.read_u8() {
-            Err(_) => {
-                return Err(ProtoError::ReadProtocolVersionError);
-            }
-            Ok(n) => {
-                // Always 10
-                assert_eq!(n, 10);
-            }
+            Ok(10) => {}
+            _ => return Err(ProtoError::ReadProtocolVersionError),
         }
-        unsafe {
-            // server version
-            payload
-                .real_read_until(0x00, self.server_version.as_mut_vec())
-                .map_err(|_| ProtoError::ReadServerVersionError)?;
-            // connection_id
-            self.connection_id = payload
-                .read_u32::<LittleEndian>()
-                .map_err(|_| ProtoError::ReadConnectionIdError)?;
-            let mut salt1 = vec![0; 8];
-            // salt[..8]
-            payload
-                .read(&mut salt1)
-                .map_err(|_| ProtoError::ReadSaltError)?;
-            payload.read_u8().map_err(|_| ProtoError::ReadZeroError)?;
+        // server version
+        let mut server_version = vec![];
+        payload
+            .real_read_until(0x00, &mut server_version)
+            .map_err(|_| ProtoError::ReadServerVersionError)?;
+        self.server_version =
+            String::from_utf8(server_version).map_err(|_| ProtoError::ReadServerVersionError)?;
+        // connection_id
+        self.connection_id = payload
+            .read_u32::<LittleEndian>()
+            .map_err(|_| ProtoError::ReadConnectionIdError)?;
+        let mut salt1 = vec![0; 8];
+        // salt[..8]
+        payload
+            .read(&mut salt1)
+            .map_err(|_| ProtoError::ReadSaltError)?;
+        payload.read_u8().map_err(|_| ProtoError::ReadZeroError)?;
 
-            // capability flags (lower 2 bytes)
-            let lower_capability = payload
-                .read_u16::<LittleEndian>()
-                .map_err(|_| ProtoError::ReadCapabilityFlagError)?;
-            // charset
-            payload
-                .read_u8()
-                .map_err(|_| ProtoError::ReadCharsetError)?;
-            // status flag
-            self.status_flag = payload
-                .read_u16::<LittleEndian>()
-                .map_err(|_| ProtoError::ReadStatusFlagError)?;
-            // capability flags (upper 2 bytes)
-            let upper_capability = payload
-                .read_u16::<LittleEndian>()
-                .map_err(|_| ProtoError::ReadCapabilityFlagError)?;
-            self.capability = ((upper_capability as u32) << 16) | lower_capability as u32;
-            let auth_plugin_part1_len =
-                if (self.capability & CapabilityFlag::CapabilityClientPluginAuth as u32) > 0 {
-                    payload
-                        .read_u8()
-                        .map_err(|_| ProtoError::ReadAuthPluginLenError)?
-                } else {
-                    payload
-                        .read_u8()
-                        .map_err(|_| ProtoError::ReadAuthPluginLenError)?;
-                    0
-                };
-            // Read 10 zeros
-            let mut trailer = [0; 10];
-            if payload
-                .read(&mut trailer)
-                .map_err(|_| ProtoError::ReadZeroError)?
-                != trailer.len()
-            {
-                return Err(ProtoError::ReadZeroError);
-            }
-            // string[$len]: auth-plugin-data-part-2 ($len=MAX(13, length of auth-plugin-data - 8))
-            if self.capability & CapabilityFlag::CapabilityClientSecureConnection as u32 > 0 {
-                let mut read = auth_plugin_part1_len as i32 - 8;
-                if read < 0 || read > 13 {
-                    read = 13;
-                }
-                let mut salt2 = vec![0; read as usize];
+        // capability flags (lower 2 bytes)
+        let lower_capability = payload
+            .read_u16::<LittleEndian>()
+            .map_err(|_| ProtoError::ReadCapabilityFlagError)?;
+        // charset
+        payload
+            .read_u8()
+            .map_err(|_| ProtoError::ReadCharsetError)?;
+        // status flag
+        self.status_flag = payload
+            .read_u16::<LittleEndian>()
+            .map_err(|_| ProtoError::ReadStatusFlagError)?;
+        // capability flags (upper 2 bytes)
+        let upper_capability = payload
+            .read_u16::<LittleEndian>()
+            .map_err(|_| ProtoError::ReadCapabilityFlagError)?;
+        self.capability = ((upper_capability as u32) << 16) | lower_capability as u32;
+        let auth_plugin_part1_len =
+            if (self.capability & CapabilityFlag::CapabilityClientPluginAuth as u32) > 0 {
                 payload
-                    .read(salt2.as_mut_slice())
-                    .map_err(|_| ProtoError::ReadSaltError)?;
-                if salt2[read as usize - 1] != 0 {
-                    return Err(ProtoError::ReadSaltError);
-                }
-                salt2.remove(read as usize - 1);
-                self.salt = [salt1, salt2].concat();
+                    .read_u8()
+                    .map_err(|_| ProtoError::ReadAuthPluginLenError)?
+            } else {
+                payload
+                    .read_u8()
+                    .map_err(|_| ProtoError::ReadAuthPluginLenError)?;
+                0
+            };
+        // Read 10 zeros
+        let mut trailer = [0; 10];
+        if payload
+            .read(&mut trailer)
+            .map_err(|_| ProtoError::ReadZeroError)?
+            != trailer.len()
+        {
+            return Err(ProtoError::ReadZeroError);
+        }
+        // string[$len]: auth-plugin-data-part-2 ($len=MAX(13, length of auth-plugin-data - 8))
+        if self.capability & CapabilityFlag::CapabilityClientSecureConnection as u32 > 0 {
+            let mut read = auth_plugin_part1_len as i32 - 8;
+            if read <= 0 || read > 13 {
+                read = 13;
             }
+            let mut salt2 = vec![0; read as usize];
+            payload
+                .read(salt2.as_mut_slice())
+                .map_err(|_| ProtoError::ReadSaltError)?;
+            if salt2[read as usize - 1] != 0 {
+                return Err(ProtoError::ReadSaltError);
+            }
+            salt2.remove(read as usize - 1);
+            self.salt = [salt1, salt2].concat();
+        }
+        // string[NUL]: auth-plugin name
+        if self.capability & CapabilityFlag::CapabilityClientPluginAuth as u32 > 0 {
+            let mut auth_plugin_name = vec![];
+            payload
+                .real_read_until(0x00, &mut auth_plugin_name)
+                .map_err(|_| ProtoError::ReadPluginError)?;
+            self.auth_plugin_name =
+                String::from_utf8(auth_plugin_name).map_err(|_| ProtoError::ReadPluginError)?;
         }
         Ok(())
     }
@@ -191,6 +227,7 @@ impl cmp::PartialEq for Greeting {
 mod tests {
     use crate::constants::CapabilityFlag::CapabilityClientPluginAuth;
     use crate::constants::DEFAULT_SERVER_CAPABILITY;
+    use crate::errors::ProtoError;
     use crate::proto::Greeting;
 
     #[test]
@@ -215,4 +252,26 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_greeting_malformed() {
+        let mut expected = Greeting::new(4, "".to_string());
+        let data = expected.write_handshake_v10(false).unwrap();
+
+        let mut version = data.clone();
+        version[0] = 9;
+        match Greeting::default().parse_client_handshake_packet(&version) {
+            Err(ProtoError::ReadProtocolVersionError) => {}
+            _ => panic!("Protocol version 9 is accepted"),
+        }
+
+        // The auth plugin name is the last field, terminated by NUL.
+        let mut plugin = data;
+        let len = plugin.len();
+        plugin[len - 2] = 0xff;
+        match Greeting::default().parse_client_handshake_packet(&plugin) {
+            Err(ProtoError::ReadPluginError) => {}
+            _ => panic!("Auth plugin name is not valid UTF-8"),
+        }
+    }
 }
@@ -0,0 +1,402 @@
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::constants::{
+    CapabilityFlag, ClientError, ServerError, StateError, AUTH_MORE_DATA_PACKET,
+    CACHING_SHA2_FAST_AUTH_SUCCESS, CACHING_SHA2_PASSWORD, CACHING_SHA2_PERFORM_FULL_AUTH,
+    DIALOG_LAST_QUESTION, DIALOG_ORDINARY_QUESTION, DIALOG_PASSWORD_QUESTION,
+    MYSQL_CLEAR_PASSWORD, MYSQL_DIALOG,
+};
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::auth::{is_ssl_request, write_auth_switch_request};
+use crate::proto::caching_sha2::Sha2Cache;
+use crate::proto::packets::err_packet;
+use crate::proto::{Auth, Greeting, Session};
+
+use dakv_logger::prelude::*;
+use native_tls::TlsAcceptor;
+
+/// AuthStep is what the connection does next to authenticate its client.
+pub enum AuthStep {
+    // Send the packet and pass the answer of the client to Handshake::answer.
+    Ask(Vec<u8>),
+    // Wrap the stream with TLS, then pass the next packet to Handshake::answer.
+    Tls(Arc<TlsAcceptor>),
+    // Pass the result of the check_auth of the handler to Handshake::checked.
+    CheckAuth,
+    // Pass the result of the auth_dialog of the handler to Handshake::checked.
+    Dialog,
+    // The client is authenticated, the packet is sent before the OK packet.
+    Done(Option<Vec<u8>>),
+    // Send the ERR packet and close the connection with the error.
+    Fail(Vec<u8>, ProtoError),
+}
+
+// State tells what the next answer of the client is.
+enum State {
+    // The handshake response, or an SSLRequest.
+    Response,
+    // The auth response of the plugin asked by an AuthSwitchRequest.
+    Switch(String),
+    // The clear text password of a full caching_sha2_password authentication.
+    FullAuth,
+    // The handler checks the client, the clear text password of a full
+    // caching_sha2_password authentication is cached once it is accepted.
+    Check(Option<Vec<u8>>),
+    // The handler checks the client whose scramble was verified against the
+    // cache, the fast auth success is sent once it is accepted.
+    Cached,
+}
+
+/// Handshake runs the authentication of a connection without doing any I/O,
+/// it returns the steps the blocking and the async connections carry out
+/// with their stream and their handler.
+pub struct Handshake {
+    id: u32,
+    addr: SocketAddr,
+    // User is the name used by the client to connect.
+    // It is set during the initial handshake.
+    user: String,
+    greeting: Box<Greeting>,
+    auth: Auth,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    require_secure_transport: bool,
+    // secure is set once the connection is upgraded to TLS.
+    secure: bool,
+    // Let the clear text plugins run over insecure connections.
+    allow_cleartext_passwords: bool,
+    // auth_cache is shared by the connections of a listener, it holds the
+    // users verified by a full caching_sha2_password authentication.
+    auth_cache: Arc<Sha2Cache>,
+    state: State,
+}
+
+impl Handshake {
+    pub fn new(id: u32, server_version: String) -> Self {
+        Handshake {
+            id,
+            addr: ([0, 0, 0, 0], 0).into(),
+            user: "".to_string(),
+            greeting: Greeting::new(id, server_version),
+            auth: Auth::new(),
+            tls_acceptor: None,
+            require_secure_transport: false,
+            secure: false,
+            allow_cleartext_passwords: false,
+            auth_cache: Arc::new(Sha2Cache::new()),
+            state: State::Response,
+        }
+    }
+
+    pub fn set_tls_acceptor(&mut self, acceptor: Option<Arc<TlsAcceptor>>) {
+        self.tls_acceptor = acceptor;
+    }
+
+    pub fn set_require_secure_transport(&mut self, require: bool) {
+        self.require_secure_transport = require;
+    }
+
+    pub fn set_auth_plugin_name(&mut self, auth_plugin_name: &str) {
+        self.greeting.set_auth_plugin_name(auth_plugin_name);
+    }
+
+    pub fn set_allow_cleartext_passwords(&mut self, allow: bool) {
+        self.allow_cleartext_passwords = allow;
+    }
+
+    pub fn set_auth_cache(&mut self, auth_cache: Arc<Sha2Cache>) {
+        self.auth_cache = auth_cache;
+    }
+
+    /// Mark the connection upgraded to TLS.
+    pub fn set_secure(&mut self) {
+        debug!("Connection {} upgraded to TLS", self.id);
+        self.secure = true;
+    }
+
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
+    pub fn auth_mut(&mut self) -> &mut Auth {
+        &mut self.auth
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        self.greeting.salt()
+    }
+
+    pub fn status_flag(&self) -> u16 {
+        self.greeting.status_flag()
+    }
+
+    /// Return the capability flags negotiated with the client.
+    pub fn capability(&self) -> u32 {
+        self.greeting.capability() & self.auth.capability_flags()
+    }
+
+    /// Return the database sent with the credentials, empty if none.
+    pub fn database(&self) -> &str {
+        self.auth.database()
+    }
+
+    /// Return a new session of the authenticated user.
+    pub fn session(&self) -> Session {
+        Session::from_auth(self.id, self.addr, &self.auth)
+    }
+
+    /// Return the greeting sent to the client of addr.
+    pub fn greet(&mut self, addr: SocketAddr) -> io::Result<Vec<u8>> {
+        self.addr = addr;
+        self.state = State::Response;
+        let pkg = self
+            .greeting
+            .write_handshake_v10(self.tls_acceptor.is_some())?;
+        debug!("handshake:{:?}", pkg.as_slice());
+        Ok(pkg)
+    }
+
+    /// Take the next packet of the client.
+    pub fn answer(&mut self, pkg: Vec<u8>) -> ProtoResult<AuthStep> {
+        match mem::replace(&mut self.state, State::Response) {
+            State::Response => self.response(pkg),
+            State::Switch(auth_plugin) => {
+                self.auth.set_auth_response(&auth_plugin, pkg);
+                self.verify()
+            }
+            State::FullAuth => {
+                if !self.secure {
+                    // The client requests the RSA public key, which is not supported.
+                    return self.fail(ProtoError::AuthRequiresSecureConnection);
+                }
+                let password = trim_nul(pkg);
+                self.auth
+                    .set_auth_response(MYSQL_CLEAR_PASSWORD, password.clone());
+                self.state = State::Check(Some(password));
+                Ok(AuthStep::CheckAuth)
+            }
+            State::Check(_) | State::Cached => panic!("The client is not checked by the handler"),
+        }
+    }
+
+    /// Take whether the handler accepts the client.
+    pub fn checked(&mut self, allowed: bool) -> ProtoResult<AuthStep> {
+        let (password, cached) = match mem::replace(&mut self.state, State::Response) {
+            State::Check(password) => (password, false),
+            State::Cached => (None, true),
+            _ => (None, false),
+        };
+        if !allowed {
+            return self.fail(ProtoError::AccessDenied(self.user.clone()));
+        }
+        if let Some(password) = password {
+            self.auth_cache.insert(&self.user, &password);
+        }
+        if cached {
+            return Ok(AuthStep::Done(Some(vec![
+                AUTH_MORE_DATA_PACKET,
+                CACHING_SHA2_FAST_AUTH_SUCCESS,
+            ])));
+        }
+        Ok(AuthStep::Done(None))
+    }
+
+    /// COM_CHANGE_USER authenticates the client again with a fresh salt.
+    pub fn change_user(&mut self, data: &[u8]) -> ProtoResult<AuthStep> {
+        let capability_flags = self.auth.capability_flags();
+        self.auth.parse_change_user_packet(data, capability_flags)?;
+        debug!("{}", self.auth);
+        self.user = self.auth.user().clone();
+        self.authenticate(true)
+    }
+
+    fn response(&mut self, pkg: Vec<u8>) -> ProtoResult<AuthStep> {
+        if !self.secure && is_ssl_request(pkg.as_slice()) {
+            return match &self.tls_acceptor {
+                Some(acceptor) => Ok(AuthStep::Tls(acceptor.clone())),
+                None => Ok(AuthStep::Fail(
+                    tls_not_configured_packet()?,
+                    ProtoError::TlsNotConfigured,
+                )),
+            };
+        }
+        if self.require_secure_transport && !self.secure {
+            return Ok(AuthStep::Fail(
+                secure_transport_required_packet()?,
+                ProtoError::SecureTransportRequired,
+            ));
+        }
+        self.auth
+            .parse_client_handshake_packet(pkg.as_slice(), false)?;
+        debug!("{:?}", pkg.as_slice());
+        debug!("{}", self.auth);
+        self.user = self.auth.user().clone();
+        self.authenticate(false)
+    }
+
+    /// Switch the client to the advertised plugin when it picked another one,
+    /// or to get an auth response for a fresh salt on reauth, then run the
+    /// exchange of the plugin.
+    fn authenticate(&mut self, reauth: bool) -> ProtoResult<AuthStep> {
+        let auth_plugin = self.greeting.auth_plugin_name().to_string();
+        if is_cleartext_plugin(&auth_plugin) && !self.secure && !self.allow_cleartext_passwords {
+            return self.fail(ProtoError::AuthRequiresSecureConnection);
+        }
+        if auth_plugin == MYSQL_DIALOG {
+            // The first question takes the place of the AuthSwitchRequest.
+            self.greeting.regenerate_salt();
+            self.state = State::Check(None);
+            return Ok(AuthStep::Dialog);
+        }
+        if (reauth || *self.auth.auth_method() != auth_plugin)
+            && self.auth.capability_flags() & CapabilityFlag::CapabilityClientPluginAuth as u32
+                != 0
+        {
+            debug!(
+                "Switch auth plugin from {} to {}",
+                self.auth.auth_method(),
+                auth_plugin
+            );
+            self.greeting.regenerate_salt();
+            let pkg = write_auth_switch_request(&auth_plugin, self.greeting.salt())?;
+            self.state = State::Switch(auth_plugin);
+            return Ok(AuthStep::Ask(pkg));
+        }
+        self.verify()
+    }
+
+    fn verify(&mut self) -> ProtoResult<AuthStep> {
+        match self.auth.auth_method().as_str() {
+            CACHING_SHA2_PASSWORD => self.caching_sha2_auth(),
+            MYSQL_CLEAR_PASSWORD => {
+                let password = trim_nul(self.auth.auth_response().clone());
+                self.auth.set_auth_response(MYSQL_CLEAR_PASSWORD, password);
+                Ok(self.check())
+            }
+            _ => Ok(self.check()),
+        }
+    }
+
+    /// caching_sha2_password authentication, the scramble is verified against
+    /// the cache, otherwise the client is asked for its clear text password,
+    /// which is only accepted over TLS. Either way the handler checks the client.
+    /// See https://dev.mysql.com/doc/dev/mysql-server/latest/page_caching_sha2_authentication_exchanges.html
+    fn caching_sha2_auth(&mut self) -> ProtoResult<AuthStep> {
+        if self.auth.auth_response().is_empty() {
+            // Empty password, nothing to exchange.
+            self.auth.set_auth_response(MYSQL_CLEAR_PASSWORD, vec![]);
+            return Ok(self.check());
+        }
+        if self
+            .auth_cache
+            .verify(&self.user, self.auth.auth_response(), self.greeting.salt())
+        {
+            // The cache is keyed by user, the handler still checks its address.
+            self.auth.set_verified_from_cache(true);
+            self.state = State::Cached;
+            return Ok(AuthStep::CheckAuth);
+        }
+        self.state = State::FullAuth;
+        Ok(AuthStep::Ask(auth_more_data_packet(&[
+            CACHING_SHA2_PERFORM_FULL_AUTH,
+        ])))
+    }
+
+    fn check(&mut self) -> AuthStep {
+        self.state = State::Check(None);
+        AuthStep::CheckAuth
+    }
+
+    fn fail(&self, err: ProtoError) -> ProtoResult<AuthStep> {
+        let pkg = access_denied_packet(&self.auth, &self.addr)?;
+        Ok(AuthStep::Fail(pkg, err))
+    }
+}
+
+/// The plugins sending the password in clear text, refused over insecure
+/// connections unless allowed.
+pub fn is_cleartext_plugin(auth_plugin: &str) -> bool {
+    auth_plugin == MYSQL_CLEAR_PASSWORD || auth_plugin == MYSQL_DIALOG
+}
+
+/// Strip the NUL terminating a clear text password or a dialog answer.
+pub fn trim_nul(mut data: Vec<u8>) -> Vec<u8> {
+    if data.last() == Some(&0) {
+        data.pop();
+    }
+    data
+}
+
+pub fn auth_more_data_packet(data: &[u8]) -> Vec<u8> {
+    let mut pkg = Vec::with_capacity(data.len() + 1);
+    pkg.push(AUTH_MORE_DATA_PACKET);
+    pkg.extend_from_slice(data);
+    pkg
+}
+
+/// A question of the dialog plugin, the first one is sent in an
+/// AuthSwitchRequest and the next ones in AuthMoreData packets.
+pub fn dialog_question_packet(
+    first: bool,
+    prompt: &str,
+    password: bool,
+    last: bool,
+) -> io::Result<Vec<u8>> {
+    let mut question = vec![if password {
+        DIALOG_PASSWORD_QUESTION
+    } else {
+        DIALOG_ORDINARY_QUESTION
+    }];
+    if last {
+        question[0] |= DIALOG_LAST_QUESTION;
+    }
+    question.extend_from_slice(prompt.as_bytes());
+    if first {
+        return write_auth_switch_request(MYSQL_DIALOG, &question);
+    }
+    question.push(0);
+    Ok(auth_more_data_packet(&question))
+}
+
+pub fn access_denied_packet(auth: &Auth, addr: &SocketAddr) -> io::Result<Vec<u8>> {
+    let using_password = if auth.auth_response().is_empty() {
+        "NO"
+    } else {
+        "YES"
+    };
+    err_packet(
+        ServerError::ERAccessDeniedError as u16,
+        StateError::SSAccessDeniedError.into(),
+        format!(
+            "Access denied for user '{}'@'{}' (using password: {})",
+            auth.user(),
+            addr.ip(),
+            using_password
+        ),
+    )
+}
+
+pub fn secure_transport_required_packet() -> io::Result<Vec<u8>> {
+    err_packet(
+        ServerError::ERSecureTransportRequired as u16,
+        StateError::SSUnknownSQLState.into(),
+        "Connections using insecure transport are prohibited while \
+         --require_secure_transport=ON."
+            .to_string(),
+    )
+}
+
+/// ERR packet answering an SSLRequest when the server has no certificate.
+pub fn tls_not_configured_packet() -> io::Result<Vec<u8>> {
+    err_packet(
+        ClientError::CRSSLConnectionError as u16,
+        StateError::SSUnknownSQLState.into(),
+        "SSL connection error: SSL is not configured on the server".to_string(),
+    )
+}
@@ -1,36 +1,156 @@
-use std::net::{TcpListener, ToSocketAddrs};
-use std::sync::atomic::AtomicBool;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
 use std::sync::Arc;
 use std::{io, thread};
 
-use crate::proto::Connection;
-use crate::sql_type::SqlResult;
+use crate::constants::{
+    CACHING_SHA2_PASSWORD, MYSQL_CLEAR_PASSWORD, MYSQL_DIALOG, MYSQL_NATIVE_PASSWORD,
+};
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::{
+    Auth, Connection, PrepareData, ProcessInfo, Registry, Session, Sha2Cache, ShutdownHandle,
+    TlsConfig,
+};
+use crate::sql_type::{Field, SqlResult};
 
 use dakv_logger::prelude::*;
+use native_tls::TlsAcceptor;
 
 pub trait Handler: Send + Sync {
-    // new_connection is called when a connection is created.
-    // The handler can decide to set StatusFlags that will
-    // be returned by the handshake methods.
-    // In particular, ServerStatusAutocommit might be set.
-    fn new_connection(&self);
+    // new_connection is called once a connection is authenticated, with the
+    // session of the connection, which holds the client connection attributes,
+    // e.g. _client_name or program_name, to log or route per client application.
+    // The handler may keep its own state in the session extensions.
+    fn new_connection(&self, session: &mut Session);
     // close_connection is called when a connection is closed.
-    fn close_connection(&self);
+    fn close_connection(&self, session: &mut Session);
     // com_query is called when a connection receives a query.
     fn com_query(
         &self,
+        session: &mut Session,
         sql: &str,
         callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
     ) -> io::Result<()>;
+    // com_prepare is called when a connection receives a prepared statement.
+    // It returns the column definitions of the statement result, which is
+    // empty if the statement returns no rows.
+    fn com_prepare(
+        &self,
+        _session: &mut Session,
+        _sql: &str,
+        _params_count: u16,
+    ) -> io::Result<Vec<Field>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Prepared statements are not supported",
+        ))
+    }
+    // com_stmt_execute is called when a connection executes a prepared
+    // statement, with the parameters bound in prepare.params.
+    fn com_stmt_execute(
+        &self,
+        _session: &mut Session,
+        _prepare: &PrepareData,
+        _callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Prepared statements are not supported",
+        ))
+    }
+    // com_field_list is called when a connection receives COM_FIELD_LIST.
+    // It returns the columns of table matching wildcard, a LIKE pattern which
+    // may be empty, the default values of the columns are sent too.
+    fn com_field_list(
+        &self,
+        _session: &mut Session,
+        _table: &str,
+        _wildcard: &str,
+    ) -> io::Result<Vec<Field>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "COM_FIELD_LIST is not supported",
+        ))
+    }
+    // com_init_db is called when the client selects the default database,
+    // with COM_INIT_DB or in its handshake response. It returns whether the
+    // database exists, the client gets ER_BAD_DB_ERROR otherwise. The schema
+    // of the session is still the previous one, it is switched once accepted.
+    fn com_init_db(&self, _session: &mut Session, _schema: &str) -> bool {
+        true
+    }
+    // com_change_user is called once COM_CHANGE_USER authenticated the
+    // connection as another user, with the session of the new user which
+    // replaces the previous one. The prepared statements are dropped.
+    fn com_change_user(&self, _session: &mut Session) {}
+    // com_reset_connection is called when a connection receives
+    // COM_RESET_CONNECTION, once its prepared statements and the extensions of
+    // its session are dropped, to reset the state the handler keeps elsewhere.
+    fn com_reset_connection(&self, _session: &mut Session) {}
+    // check_kill is called when a connection runs KILL or COM_PROCESS_KILL
+    // against the connection connection_id of user, it returns whether the
+    // session may kill it. By default users may only kill their own connections.
+    fn check_kill(&self, session: &Session, _connection_id: u32, user: &str) -> bool {
+        session.user() == user
+    }
+    // processlist is called on SHOW [FULL] PROCESSLIST and COM_PROCESS_INFO
+    // with the connections of the listener, it returns the ones to list. By
+    // default users only see their own connections.
+    fn processlist(
+        &self,
+        session: &mut Session,
+        processes: Vec<ProcessInfo>,
+    ) -> io::Result<Vec<ProcessInfo>> {
+        Ok(processes
+            .into_iter()
+            .filter(|process| process.user == session.user())
+            .collect())
+    }
 
-    fn check_auth(&self) {}
+    // check_auth is called once the client handshake response is parsed.
+    // It receives the client credentials, the salt sent in the greeting and
+    // the peer address, and returns whether the user is allowed to connect.
+    // The auth method is mysql_clear_password when the auth response is the
+    // clear text password, e.g. during a full caching_sha2_password authentication.
+    // When auth.verified_from_cache() is set, the caching_sha2_password scramble
+    // was already verified against the cache, which is keyed by user only, so
+    // the handler still decides whether the user may connect from addr.
+    fn check_auth(&self, auth: &Auth, salt: &[u8], addr: &SocketAddr) -> bool;
+    // auth_dialog is called when the server authenticates with the dialog
+    // plugin, e.g. to ask for an OTP code. ask sends a prompt to the client
+    // and returns its answer, its flags tell whether the answer is a password,
+    // which the client does not echo, and whether it is the last question.
+    // A password question without prompt is answered with the password of the
+    // client. By default it asks for the password and calls check_auth with it.
+    fn auth_dialog(
+        &self,
+        auth: &Auth,
+        salt: &[u8],
+        addr: &SocketAddr,
+        ask: &mut dyn FnMut(&str, bool, bool) -> io::Result<String>,
+    ) -> bool {
+        let password = match ask("", true, true) {
+            Ok(password) => password,
+            Err(_) => return false,
+        };
+        let mut auth = auth.clone();
+        auth.set_auth_response(MYSQL_CLEAR_PASSWORD, password.into_bytes());
+        self.check_auth(&auth, salt, addr)
+    }
 }
 
 pub struct Listener {
     listener: TcpListener,
     connection_id: u32,
     server_version: String,
-    shutdown: AtomicBool,
+    // registry tracks the connections to drain them on shutdown.
+    registry: Arc<Registry>,
+    tls_acceptor: Option<Arc<TlsAcceptor>>,
+    // Reject the clients that do not upgrade the connection to TLS.
+    require_secure_transport: bool,
+    // auth_plugin_name is the auth plugin advertised in the greeting.
+    auth_plugin_name: String,
+    allow_cleartext_passwords: bool,
+    auth_cache: Arc<Sha2Cache>,
 }
 
 impl Listener {
@@ -40,22 +160,98 @@ impl Listener {
             listener,
             connection_id: 0,
             server_version: "5.7.0".to_string(),
-            shutdown: AtomicBool::new(false),
+            registry: Arc::new(Registry::new()),
+            tls_acceptor: None,
+            require_secure_transport: false,
+            auth_plugin_name: MYSQL_NATIVE_PASSWORD.to_string(),
+            allow_cleartext_passwords: false,
+            auth_cache: Arc::new(Sha2Cache::new()),
         }
     }
 
+    /// Enable TLS, clients sending an SSLRequest are upgraded with the
+    /// certificate of config.
+    pub fn set_tls_config(&mut self, config: &TlsConfig) -> ProtoResult<()> {
+        self.tls_acceptor = Some(Arc::new(config.build_acceptor()?));
+        Ok(())
+    }
+
+    pub fn set_require_secure_transport(&mut self, require: bool) {
+        self.require_secure_transport = require;
+    }
+
+    /// Set the auth plugin the clients authenticate with, one of
+    /// mysql_native_password, caching_sha2_password, mysql_clear_password
+    /// and dialog.
+    pub fn set_auth_plugin_name(&mut self, auth_plugin_name: &str) -> ProtoResult<()> {
+        check_auth_plugin_name(auth_plugin_name)?;
+        self.auth_plugin_name = auth_plugin_name.to_string();
+        Ok(())
+    }
+
+    /// Let mysql_clear_password and dialog run over connections not upgraded
+    /// to TLS, they are refused by default.
+    pub fn set_allow_cleartext_passwords(&mut self, allow: bool) {
+        self.allow_cleartext_passwords = allow;
+    }
+
+    /// Return the cache of the caching_sha2_password authentications, users
+    /// should be removed from it when their password changes.
+    pub fn auth_cache(&self) -> Arc<Sha2Cache> {
+        self.auth_cache.clone()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Return a handle to shut the listener down from another thread.
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        Ok(ShutdownHandle::new(
+            self.registry.clone(),
+            self.local_addr()?,
+        ))
+    }
+
+    /// Serve the connections until the listener is shut down, the connections
+    /// are drained before it returns.
     pub fn accept(&mut self, handler: Arc<dyn Handler>) {
         debug!("Start server ...");
         for stream in self.listener.incoming() {
+            if self.registry.is_shutdown() {
+                break;
+            }
             let connection_id = self.connection_id;
             self.connection_id += 1;
             let server_version = self.server_version.clone();
             let handler = handler.clone();
+            let tls_acceptor = self.tls_acceptor.clone();
+            let require_secure_transport = self.require_secure_transport;
+            let auth_plugin_name = self.auth_plugin_name.clone();
+            let allow_cleartext_passwords = self.allow_cleartext_passwords;
+            let auth_cache = self.auth_cache.clone();
+            let registry = self.registry.clone();
             match stream {
                 Ok(stream) => {
+                    // Registered before the thread starts, so that a shutdown
+                    // right after waits for it.
+                    match stream.try_clone() {
+                        Ok(clone) => registry.register(connection_id, clone),
+                        Err(e) => {
+                            error!("Clone stream failed: {}", e);
+                            continue;
+                        }
+                    }
                     thread::spawn(move || {
                         let mut conn = Connection::new(connection_id, server_version);
+                        conn.set_tls_acceptor(tls_acceptor);
+                        conn.set_require_secure_transport(require_secure_transport);
+                        conn.set_auth_plugin_name(&auth_plugin_name);
+                        conn.set_allow_cleartext_passwords(allow_cleartext_passwords);
+                        conn.set_auth_cache(auth_cache);
+                        conn.set_registry(registry.clone());
                         conn.handle(stream, handler);
+                        registry.unregister(connection_id);
                     });
                 }
                 Err(_) => {
@@ -63,5 +259,340 @@ impl Listener {
                 }
             }
         }
+        self.registry.drain();
+        debug!("Server stopped");
+    }
+}
+
+/// Check that the server supports auth_plugin_name.
+pub fn check_auth_plugin_name(auth_plugin_name: &str) -> ProtoResult<()> {
+    match auth_plugin_name {
+        MYSQL_NATIVE_PASSWORD | CACHING_SHA2_PASSWORD | MYSQL_CLEAR_PASSWORD | MYSQL_DIALOG => {
+            Ok(())
+        }
+        _ => Err(ProtoError::InvalidPluginError(auth_plugin_name.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::constants::{
+        DEFAULT_CLIENT_CAPABILITY, EOF_PACKET, ERR_PACKET, MYSQL_NATIVE_PASSWORD, OK_PACKET,
+    };
+    use crate::proto::packets::Packets;
+    use crate::proto::{Auth, Greeting, Handler, Listener, Session};
+    use crate::sql_type::SqlResult;
+
+    struct SlowHandler {
+        started: Mutex<mpsc::Sender<()>>,
+        closed: AtomicUsize,
+    }
+
+    impl Handler for SlowHandler {
+        fn new_connection(&self, _session: &mut Session) {}
+        fn close_connection(&self, _session: &mut Session) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+        fn com_query(
+            &self,
+            _session: &mut Session,
+            _sql: &str,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            self.started.lock().unwrap().send(()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            callback(SqlResult {
+                affected_rows: 1,
+                ..Default::default()
+            })
+        }
+        fn check_auth(&self, _auth: &Auth, _salt: &[u8], _addr: &SocketAddr) -> bool {
+            true
+        }
+    }
+
+    struct KillHandler {
+        started: Mutex<mpsc::Sender<()>>,
+        closed: AtomicUsize,
+    }
+
+    impl Handler for KillHandler {
+        fn new_connection(&self, _session: &mut Session) {}
+        fn close_connection(&self, _session: &mut Session) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+        fn com_query(
+            &self,
+            session: &mut Session,
+            sql: &str,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            if sql == "SLEEP" {
+                self.started.lock().unwrap().send(()).unwrap();
+                while !session.cancel_token().is_cancelled() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                return Err(io::Error::new(io::ErrorKind::Other, "Cancelled"));
+            }
+            callback(SqlResult {
+                affected_rows: 1,
+                ..Default::default()
+            })
+        }
+        fn check_auth(&self, _auth: &Auth, _salt: &[u8], _addr: &SocketAddr) -> bool {
+            true
+        }
+    }
+
+    fn login(addr: SocketAddr, user: &str) -> Packets {
+        let mut client = Packets::new();
+        client.set_stream(Box::new(TcpStream::connect(addr).unwrap()));
+        let mut greeting = Greeting::default();
+        greeting
+            .parse_client_handshake_packet(&client.read_ephemeral_packet_direct().unwrap())
+            .unwrap();
+        let resp = Auth::write_handshake_resp(
+            DEFAULT_CLIENT_CAPABILITY,
+            33,
+            user.to_string(),
+            "".to_string(),
+            greeting.salt(),
+            MYSQL_NATIVE_PASSWORD,
+            "".to_string(),
+            &HashMap::new(),
+        )
+        .unwrap();
+        client.write_packet(&resp).unwrap();
+        assert_eq!(client.read_ephemeral_packet_direct().unwrap()[0], OK_PACKET);
+        client
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut listener = Listener::new_tcp_listener("127.0.0.1:0");
+        let addr = listener.local_addr().unwrap();
+        let shutdown = listener.shutdown_handle().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handler = Arc::new(SlowHandler {
+            started: Mutex::new(tx),
+            closed: AtomicUsize::new(0),
+        });
+        let server = {
+            let handler = handler.clone();
+            thread::spawn(move || listener.accept(handler))
+        };
+
+        let mut idle = login(addr, "root");
+        let mut busy = login(addr, "root");
+        busy.reset_sequence_id();
+        busy.write_packet(b"\x03UPDATE t SET a = 1").unwrap();
+        rx.recv().unwrap();
+        shutdown.shutdown(Duration::from_secs(5));
+
+        // The idle client is told that the server shuts down.
+        idle.reset_sequence_id();
+        let data = idle.read_ephemeral_packet_direct().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0x1d, 0x04]);
+        // The running query completes first.
+        let data = busy.read_ephemeral_packet_direct().unwrap();
+        assert_eq!(data[..2], [OK_PACKET, 1]);
+        busy.reset_sequence_id();
+        let data = busy.read_ephemeral_packet_direct().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0x1d, 0x04]);
+
+        server.join().unwrap();
+        assert_eq!(handler.closed.load(Ordering::SeqCst), 2);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_deadline() {
+        let mut listener = Listener::new_tcp_listener("127.0.0.1:0");
+        let addr = listener.local_addr().unwrap();
+        let shutdown = listener.shutdown_handle().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handler = Arc::new(SlowHandler {
+            started: Mutex::new(tx),
+            closed: AtomicUsize::new(0),
+        });
+        let server = {
+            let handler = handler.clone();
+            thread::spawn(move || listener.accept(handler))
+        };
+
+        let mut busy = login(addr, "root");
+        busy.reset_sequence_id();
+        busy.write_packet(b"\x03UPDATE t SET a = 1").unwrap();
+        rx.recv().unwrap();
+        // The query outlives the deadline, its connection is closed and
+        // accept returns once the handler is done with it.
+        shutdown.shutdown(Duration::from_millis(0));
+        server.join().unwrap();
+        assert_eq!(handler.closed.load(Ordering::SeqCst), 1);
+    }
+
+    /// Send a command and return the first packet of the answer.
+    fn command(client: &mut Packets, data: &[u8]) -> Vec<u8> {
+        client.reset_sequence_id();
+        client.write_packet(data).unwrap();
+        client.read_ephemeral_packet_direct().unwrap()
+    }
+
+    #[test]
+    fn test_kill() {
+        let mut listener = Listener::new_tcp_listener("127.0.0.1:0");
+        let addr = listener.local_addr().unwrap();
+        let shutdown = listener.shutdown_handle().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handler = Arc::new(KillHandler {
+            started: Mutex::new(tx),
+            closed: AtomicUsize::new(0),
+        });
+        let server = {
+            let handler = handler.clone();
+            thread::spawn(move || listener.accept(handler))
+        };
+
+        // Connection ids are given in order.
+        let mut busy = login(addr, "root");
+        let mut killer = login(addr, "root");
+        let mut idle = login(addr, "root");
+        let mut other = login(addr, "bob");
+
+        // KILL QUERY interrupts the query, the connection stays.
+        busy.reset_sequence_id();
+        busy.write_packet(b"\x03SLEEP").unwrap();
+        rx.recv().unwrap();
+        assert_eq!(command(&mut killer, b"\x03KILL QUERY 0")[0], OK_PACKET);
+        let data = busy.read_ephemeral_packet_direct().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0x25, 0x05]);
+        assert_eq!(command(&mut busy, b"\x03UPDATE t SET a = 1")[..2], [
+            OK_PACKET, 1
+        ]);
+
+        let data = command(&mut killer, b"\x03KILL 99");
+        assert_eq!(data[..3], [ERR_PACKET, 0x46, 0x04]);
+        // Users may only kill their own connections.
+        let data = command(&mut other, &[0x0c, 0, 0, 0, 0]);
+        assert_eq!(data[..3], [ERR_PACKET, 0x47, 0x04]);
+
+        // KILL CONNECTION closes the connection once its query is interrupted.
+        busy.reset_sequence_id();
+        busy.write_packet(b"\x03SLEEP").unwrap();
+        rx.recv().unwrap();
+        assert_eq!(command(&mut killer, b"\x03KILL CONNECTION 0")[0], OK_PACKET);
+        let data = busy.read_ephemeral_packet_direct().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0x25, 0x05]);
+        busy.reset_sequence_id();
+        assert!(busy.read_ephemeral_packet_direct().is_err());
+        // An idle connection is closed right away.
+        assert_eq!(command(&mut killer, &[0x0c, 2, 0, 0, 0])[0], OK_PACKET);
+        assert!(idle.read_ephemeral_packet_direct().is_err());
+        for _ in 0..100 {
+            if handler.closed.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(handler.closed.load(Ordering::SeqCst), 2);
+
+        shutdown.shutdown(Duration::from_secs(5));
+        server.join().unwrap();
+        assert_eq!(handler.closed.load(Ordering::SeqCst), 4);
+    }
+
+    /// Read a text result set of short values, None stands for NULL.
+    fn read_rows(client: &mut Packets) -> Vec<Vec<Option<String>>> {
+        let columns = client.read_ephemeral_packet_direct().unwrap()[0];
+        for _ in 0..columns {
+            client.read_ephemeral_packet_direct().unwrap();
+        }
+        let mut rows = vec![];
+        loop {
+            let data = client.read_ephemeral_packet_direct().unwrap();
+            if data[0] == EOF_PACKET && data.len() < 9 {
+                return rows;
+            }
+            let mut data = data.as_slice();
+            let mut row = vec![];
+            while !data.is_empty() {
+                if data[0] == 0xfb {
+                    row.push(None);
+                    data = &data[1..];
+                } else {
+                    let len = data[0] as usize;
+                    row.push(Some(String::from_utf8_lossy(&data[1..=len]).into_owned()));
+                    data = &data[len + 1..];
+                }
+            }
+            rows.push(row);
+        }
+    }
+
+    #[test]
+    fn test_processlist() {
+        let mut listener = Listener::new_tcp_listener("127.0.0.1:0");
+        let addr = listener.local_addr().unwrap();
+        let shutdown = listener.shutdown_handle().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let handler = Arc::new(KillHandler {
+            started: Mutex::new(tx),
+            closed: AtomicUsize::new(0),
+        });
+        let server = {
+            let handler = handler.clone();
+            thread::spawn(move || listener.accept(handler))
+        };
+
+        let mut busy = login(addr, "root");
+        let mut client = login(addr, "root");
+        let mut other = login(addr, "bob");
+        assert_eq!(command(&mut busy, b"\x02test")[0], OK_PACKET);
+        busy.reset_sequence_id();
+        busy.write_packet(b"\x03SLEEP").unwrap();
+        rx.recv().unwrap();
+
+        client.reset_sequence_id();
+        client.write_packet(b"\x03SHOW FULL PROCESSLIST").unwrap();
+        let rows = read_rows(&mut client);
+        let some = |s: &str| Some(s.to_string());
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0][..2], [some("0"), some("root")]);
+        assert!(rows[0][2].as_ref().unwrap().starts_with("127.0.0.1:"));
+        assert_eq!(rows[0][3..5], [some("test"), some("Query")]);
+        assert_eq!(rows[0][7], some("SLEEP"));
+        assert_eq!(rows[1][..2], [some("1"), some("root")]);
+        assert_eq!(rows[1][3..5], [None, some("Query")]);
+        assert_eq!(rows[1][7], some("SHOW FULL PROCESSLIST"));
+
+        // Other users only see their own connections.
+        other.reset_sequence_id();
+        other.write_packet(&[0x0a]).unwrap();
+        let rows = read_rows(&mut other);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][0], some("2"));
+        assert_eq!(rows[0][4], some("Processlist"));
+        assert_eq!(rows[0][7], None);
+
+        // An empty command is refused, the connection stays open.
+        assert_eq!(command(&mut other, b"")[0], ERR_PACKET);
+        assert_eq!(command(&mut other, &[0x0e])[0], OK_PACKET);
+        // So is a statement that is not valid UTF-8.
+        assert_eq!(command(&mut other, b"\x16\xff\xfe")[0], ERR_PACKET);
+        assert_eq!(command(&mut other, b"\x03\xff\xfe")[0], ERR_PACKET);
+        assert_eq!(command(&mut other, &[0x0e])[0], OK_PACKET);
+
+        assert_eq!(command(&mut client, b"\x03KILL QUERY 0")[0], OK_PACKET);
+        assert_eq!(busy.read_ephemeral_packet_direct().unwrap()[0], ERR_PACKET);
+        shutdown.shutdown(Duration::from_secs(5));
+        server.join().unwrap();
     }
 }
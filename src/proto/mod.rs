@@ -1,10 +1,46 @@
+#[cfg(feature = "async")]
+mod async_connection;
+#[cfg(feature = "async")]
+mod async_listener;
+#[cfg(feature = "async")]
+mod async_packets;
 mod auth;
+mod binary;
+mod caching_sha2;
+mod client;
+mod command;
 mod connection;
+mod failover;
 mod greeting;
+mod handshake;
 mod listener;
 mod packets;
+mod pool;
+mod prepare;
+mod query;
+mod registry;
+mod session;
+mod tls;
 
-pub use auth::Auth;
+#[cfg(feature = "async")]
+pub use async_connection::{AsyncConnection, Dialog};
+#[cfg(feature = "async")]
+pub use async_listener::{AsyncHandler, AsyncListener};
+#[cfg(feature = "async")]
+pub use async_packets::ResultWriter;
+pub use auth::{
+    gen_auth_response, native_password_hash, parse_auth_more_data, parse_auth_switch_request,
+    verify_native_password, verify_native_password_hash, write_auth_switch_request, Auth,
+};
+pub use caching_sha2::{caching_sha2_more_data, gen_caching_sha2_password, Sha2Cache};
+pub use client::{Client, Statement};
 pub use connection::Connection;
+pub use failover::FailoverClient;
 pub use greeting::Greeting;
 pub use listener::{Handler, Listener};
+pub use packets::{parse_end_packet, parse_ok_packet, Packets, ReadLenEncode};
+pub use pool::{Pool, PooledClient};
+pub use prepare::PrepareData;
+pub use registry::{ProcessInfo, Registry, ShutdownHandle};
+pub use session::{CancelToken, Extensions, Session};
+pub use tls::TlsConfig;
@@ -3,11 +3,16 @@ use std::io::{Read, Write};
 use std::sync::Arc;
 
 use crate::constants::{
-    CapabilityFlag, PacketType, ServerError, StateError, EOF_PACKET, ERR_PACKET, MAX_PACKET_SIZE,
-    OK_PACKET, SERVER_MORE_RESULTS_EXISTS,
+    CapabilityFlag, ServerError, StateError, CHARACTER_SET_BINARY, EOF_PACKET, ERR_PACKET,
+    MAX_PACKET_SIZE, OK_PACKET, SERVER_MORE_RESULTS_EXISTS,
 };
-use crate::errors::{ProtoError, ProtoResult};
-use crate::sql_type::{type_to_mysql, Field, SqlResult, Value};
+use crate::errors::{ProtoError, ProtoResult, SqlError};
+use crate::proto::binary::write_binary_row;
+use crate::proto::command::{Command, Commands, QueryStatement};
+use crate::proto::prepare::PrepareData;
+use crate::proto::registry::{ProcessInfo, Registry};
+use crate::proto::Session;
+use crate::sql_type::{mysql_to_type, type_to_mysql, Field, MysqlType, SqlResult, Type, Value};
 use crate::Handler;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
@@ -17,14 +22,16 @@ pub trait ReadAndWrite: io::Read + io::Write {}
 
 impl<T> ReadAndWrite for T where T: io::Read + io::Write {}
 
-pub struct Packets {
+/// Packets frames the packets of a stream, any stream by default. A
+/// Packets of a concrete stream, e.g. a TcpStream, is Send when the stream is.
+pub struct Packets<S: ?Sized = dyn ReadAndWrite> {
     sequence_id: u8,
-    capability: u32,
-    status_flags: u16,
-    stream: Option<Box<dyn ReadAndWrite>>,
+    stream: Option<Box<S>>,
+    // State of the commands, shared with the async connections.
+    commands: Commands,
 }
 
-trait WriteLenEncode: WriteBytesExt {
+pub trait WriteLenEncode: WriteBytesExt {
     fn write_len_int(&mut self, value: u64) -> io::Result<()>;
     fn write_len_str(&mut self, s: &[u8]) -> io::Result<()> {
         self.write_len_int(s.len() as u64)?;
@@ -60,20 +67,72 @@ impl WriteLenEncode for Vec<u8> {
     }
 }
 
-impl Packets {
-    pub fn new() -> Self {
+pub trait ReadLenEncode: ReadBytesExt {
+    fn read_len_int(&mut self) -> io::Result<u64> {
+        match self.read_u8()? {
+            0xfc => Ok(self.read_u16::<LittleEndian>()? as u64),
+            0xfd => Ok(self.read_u24::<LittleEndian>()? as u64),
+            0xfe => self.read_u64::<LittleEndian>(),
+            n => Ok(n as u64),
+        }
+    }
+
+    fn read_len_str(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_len_int()?;
+        let mut buf = Vec::new();
+        if self.take(len).read_to_end(&mut buf)? as u64 != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Read length encoded string failed",
+            ));
+        }
+        Ok(buf)
+    }
+}
+
+impl<T: io::Read> ReadLenEncode for T {}
+
+impl<S: ?Sized> Default for Packets<S> {
+    fn default() -> Self {
         Packets {
             sequence_id: 0,
-            capability: 0,
-            status_flags: 0,
             stream: None,
+            commands: Commands::new(),
         }
     }
+}
 
-    pub fn set_stream(&mut self, stream: Box<dyn ReadAndWrite>) {
+impl Packets {
+    pub fn new() -> Self {
+        Packets::default()
+    }
+}
+
+impl<S: ReadAndWrite + ?Sized> Packets<S> {
+    pub fn set_stream(&mut self, stream: Box<S>) {
         self.stream = Some(stream);
     }
 
+    /// Let the connection kill the other connections of the registry.
+    pub fn set_registry(&mut self, registry: Arc<Registry>) {
+        self.commands.set_registry(registry);
+    }
+
+    pub fn take_stream(&mut self) -> Option<Box<S>> {
+        self.stream.take()
+    }
+
+    /// Set the capabilities negotiated during the handshake, COM_SET_OPTION
+    /// may toggle multi statements afterwards.
+    pub fn set_capability(&mut self, capability: u32) {
+        self.commands.set_capability(capability);
+    }
+
+    /// Restart the sequence id, every command starts a new sequence.
+    pub fn reset_sequence_id(&mut self) {
+        self.sequence_id = 0;
+    }
+
     pub fn next(&self) -> ProtoResult<Vec<u8>> {
         Ok(vec![])
     }
@@ -120,28 +179,12 @@ impl Packets {
     fn read_header(&mut self) -> io::Result<usize> {
         let mut header = [0; 4];
         if let Some(inner) = &mut self.stream {
+            // Keep the kind of the error, a lost connection is retried.
             return match inner.read_exact(&mut header) {
-                Ok(_) => {
-                    debug!("Header:{:?}", header);
-                    let sequence = header[3];
-                    if sequence != self.sequence_id {
-                        error!(
-                            "current sequence:{}, get sequence:{}",
-                            self.sequence_id, sequence
-                        );
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Invalid sequence",
-                        ));
-                    }
-                    self.sequence_id += 1;
-                    Ok((header[0] as usize)
-                        | (header[1] as usize) << 8
-                        | (header[2] as usize) << 16)
-                }
-                _ => Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Read packet header failed",
+                Ok(_) => parse_packet_header(&header, &mut self.sequence_id),
+                Err(e) => Err(io::Error::new(
+                    e.kind(),
+                    format!("Read packet header failed: {}", e),
                 )),
             };
         }
@@ -193,91 +236,11 @@ impl Packets {
         Ok(())
     }
 
-    /// Write all fields data into socket.
-    fn write_fields(&mut self, result: SqlResult) -> io::Result<()> {
-        let mut data = Vec::new();
-        // Write length of fields
-        let count = result.fields.len();
-        let len = len_enc_int_size(count as u64);
-        data.write_len_int(len as u64)?;
-        let inner = self.stream.as_mut().unwrap();
-        for f in result.fields {
-            let column = Self::write_column_definition(&f)?;
-            inner.write_all(column.as_slice())?;
-        }
-        if self.capability & CapabilityFlag::CapabilityClientDeprecateEOF as u32 == 0 {
-            self.write_eof_packet(self.status_flags, 0)?;
-        }
-        Ok(())
-    }
-
-    fn write_column_definition(field: &Field) -> io::Result<Vec<u8>> {
-        let (typ, mut flags) = type_to_mysql(field.typ);
-        if field.flags != 0 {
-            flags = field.flags as i64;
-        }
-        let capacity = 4 +
-            len_enc_str_size(&field.database) +
-            len_enc_str_size(&field.table) +
-            len_enc_str_size(&field.org_table) +
-            len_enc_str_size(&field.name) +
-            len_enc_str_size(&field.org_name) +
-            1 + // length of fixed length fields
-            2 + // character set
-            4 + // column length
-            1 + // type
-            2 + // flags
-            1 + // decimals
-            2; // filler
-        let mut data = Vec::with_capacity(capacity);
-        data.write_len_str("def".as_ref())?;
-        data.write_len_str(field.database.as_bytes())?;
-        data.write_len_str(field.table.as_bytes())?;
-        data.write_len_str(field.org_table.as_bytes())?;
-        data.write_len_str(field.name.as_bytes())?;
-        data.write_len_str(field.org_name.as_bytes())?;
-
-        data.write_u8(0x0c)?;
-        data.write_u16::<LittleEndian>(field.charset as u16)?;
-        data.write_u32::<LittleEndian>(field.column_len)?;
-        data.write_u8(typ as u8)?;
-        data.write_u16::<LittleEndian>(flags as u16)?;
-        data.write_u8(field.decimals as u8)?;
-        data.write_u16::<LittleEndian>(0x0000)?;
-        Ok(data)
-    }
-
-    /// Write rows into socket.
-    fn write_rows(&mut self, qr: SqlResult) -> io::Result<()> {
-        for row in qr.rows {
-            self.write_row(row)?;
-        }
-        Ok(())
-    }
-
-    fn write_row(&mut self, row: Vec<Value>) -> io::Result<()> {
-        let mut data = Vec::new();
-        for val in row {
-            if val.is_null() {
-                data.write_u8(0xfb)?; // NULL
-            } else {
-                let l = val.val.len();
-                data.write_len_int(l as u64)?;
-                data.write_all(val.val.as_slice())?;
-            }
-        }
-
-        let inner = self.stream.as_mut().unwrap();
-        inner.write_all(data.as_slice())?;
-        Ok(())
-    }
-
-    pub fn write_err_packet_from_err(&mut self) -> io::Result<()> {
-        self.write_err_packet(
-            ServerError::ERUnknownError as u16,
-            StateError::SSUnknownSQLState.into(),
-            "Unknown error".to_string(),
-        )
+    /// Report err to the client, see SqlError for the code and the state of
+    /// each error.
+    pub fn write_err_packet_from_err(&mut self, err: &ProtoError) -> io::Result<()> {
+        let pkg = sql_err_packet(&SqlError::from(err))?;
+        self.write_packet(pkg.as_slice())
     }
 
     pub fn write_ok_packet_with_eof_header(
@@ -287,19 +250,9 @@ impl Packets {
         flags: u16,
         warnings: u16,
     ) -> io::Result<()> {
-        let mut inner = Vec::with_capacity(
-            1 + len_enc_int_size(affected_rows) + len_enc_int_size(last_insert_id) + 2 + 2,
-        );
-
-        inner.write_u8(EOF_PACKET)?;
-        // Affected rows
-        inner.write_len_int(affected_rows)?;
-        // Last insert id
-        inner.write_len_int(last_insert_id)?;
-
-        inner.write_u16::<LittleEndian>(flags)?;
-        inner.write_u16::<LittleEndian>(warnings)?;
-        self.write_packet(inner.as_slice())
+        let pkg =
+            ok_packet_with_header(EOF_PACKET, affected_rows, last_insert_id, flags, warnings)?;
+        self.write_packet(pkg.as_slice())
     }
 
     pub fn write_end_result(
@@ -309,45 +262,34 @@ impl Packets {
         last_insert_id: u64,
         warnings: u16,
     ) -> io::Result<()> {
-        let mut flags = self.status_flags;
+        let mut flags = self.commands.status_flags();
         if more {
             flags |= SERVER_MORE_RESULTS_EXISTS;
         }
-        if self.capability & CapabilityFlag::CapabilityClientDeprecateEOF as u32 == 0 {
-            self.write_eof_packet(flags, warnings)?;
-        } else {
-            self.write_ok_packet_with_eof_header(affected_rows, last_insert_id, flags, warnings)?;
-        }
-        Ok(())
+        let pkg = end_result_packet(
+            self.commands.capability(),
+            flags,
+            affected_rows,
+            last_insert_id,
+            warnings,
+        )?;
+        self.write_packet(pkg.as_slice())
     }
 
     // flags may not be equal to self.status_flags
     pub fn write_eof_packet(&mut self, flags: u16, warnings: u16) -> io::Result<()> {
-        let inner = self.stream.as_mut().unwrap();
-        inner.write_u8(EOF_PACKET)?;
-        inner.write_u16::<LittleEndian>(warnings)?;
-        inner.write_u16::<LittleEndian>(flags)?;
-        Ok(())
+        let pkg = eof_packet(flags, warnings)?;
+        self.write_packet(pkg.as_slice())
     }
 
     pub fn write_err_packet(
         &mut self,
         err_code: u16,
-        mut sql_state: String,
+        sql_state: String,
         err_msg: String,
     ) -> io::Result<()> {
-        let mut inner = Vec::with_capacity(1 + 2 + 1 + 5 + err_msg.len());
-        inner.write_u8(ERR_PACKET)?;
-        inner.write_u16::<LittleEndian>(err_code)?;
-        inner.write_u8(b'#')?;
-        if sql_state.is_empty() {
-            sql_state = StateError::SSUnknownSQLState.into();
-        }
-        assert_eq!(sql_state.len(), 5);
-
-        inner.write_all(sql_state.as_bytes())?;
-        inner.write_all(err_msg.as_bytes())?;
-        self.write_packet(inner.as_slice())
+        let pkg = err_packet(err_code, sql_state, err_msg)?;
+        self.write_packet(pkg.as_slice())
     }
 
     pub fn write_ok_packet(
@@ -357,217 +299,817 @@ impl Packets {
         flags: u16,
         warnings: u16,
     ) -> io::Result<()> {
-        let mut inner = Vec::with_capacity(
-            1 + len_enc_int_size(affected_rows) + len_enc_int_size(last_insert_id) + 2 + 2,
-        );
-
-        inner.write_u8(OK_PACKET)?;
-        // Affected rows
-        inner.write_len_int(affected_rows)?;
-        // Last insert id
-        inner.write_len_int(last_insert_id)?;
-
-        inner.write_u16::<LittleEndian>(flags)?;
-        inner.write_u16::<LittleEndian>(warnings)?;
-        self.write_packet(inner.as_slice())
+        let pkg =
+            ok_packet_with_header(OK_PACKET, affected_rows, last_insert_id, flags, warnings)?;
+        self.write_packet(pkg.as_slice())
     }
 
     pub fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
-        let mut len = data.len();
-        let mut index = 0;
         if let Some(inner) = &mut self.stream {
-            loop {
-                let mut pkg_len = len;
-                if pkg_len > MAX_PACKET_SIZE {
-                    pkg_len = MAX_PACKET_SIZE;
-                }
-
-                let mut header = [0; 4];
-                header[0] = pkg_len as u8;
-                header[1] = (pkg_len >> 8) as u8;
-                header[2] = (pkg_len >> 16) as u8;
-                header[3] = self.sequence_id;
-                // todo check write bytes length
-                inner.write_all(&header)?;
-                //                if n != 4 {
-                //                    // todo
-                //                    panic!("");
-                //                }
-                inner.write_all(&data[index..index + pkg_len])?;
-                self.sequence_id += 1;
-                len -= pkg_len;
-                if len == 0 {
-                    if pkg_len == MAX_PACKET_SIZE {
-                        inner.write_all(&[0, 0, 0, self.sequence_id])?;
-                        self.sequence_id += 1;
-                    }
-                    return Ok(());
-                }
-                index += pkg_len;
-            }
+            let buf = frame_packet(data, &mut self.sequence_id);
+            // todo check write bytes length
+            return inner.write_all(buf.as_slice());
         }
         panic!("Invalid stream");
     }
 
+    fn write_packets(&mut self, packets: Vec<Vec<u8>>) -> io::Result<()> {
+        for pkg in packets {
+            self.write_packet(pkg.as_slice())?;
+        }
+        Ok(())
+    }
+
     pub fn handle_next_command(
         &mut self,
         handler: Arc<dyn Handler>,
+        session: &mut Session,
         status_flags: u16,
-        capability: u32,
     ) -> ProtoResult<()> {
+        let data = self.read_command()?;
+        self.handle_command(handler, session, status_flags, data.as_slice())
+    }
+
+    /// Drop the prepared statements of the connection.
+    pub fn clear_prepares(&mut self) {
+        self.commands.clear_prepares();
+    }
+
+    /// Read the next command, which starts a new sequence.
+    pub fn read_command(&mut self) -> ProtoResult<Vec<u8>> {
         self.sequence_id = 0;
-        self.capability = capability;
-        self.status_flags = status_flags;
-        let data: Vec<u8> = self.read_ephemeral_packet()?;
-        let data = data.as_slice();
-        let pt = data[0];
-        debug!("Packet type {}", PacketType::from(pt as u64).to_string());
-
-        match pt.into() {
-            PacketType::ComQuit => {
-                debug!("ComQuit");
-                return Err(ProtoError::ComQuit);
+        self.read_ephemeral_packet()
+    }
+
+    pub fn handle_command(
+        &mut self,
+        handler: Arc<dyn Handler>,
+        session: &mut Session,
+        status_flags: u16,
+        data: &[u8],
+    ) -> ProtoResult<()> {
+        self.commands.set_status_flags(status_flags);
+        match self.commands.decode(data)? {
+            Command::Quit => return Err(ProtoError::ComQuit),
+            Command::InitDb(db) => {
+                let pkg = if handler.com_init_db(session, &db) {
+                    session.set_schema(&db);
+                    self.commands.ok_packet()?
+                } else {
+                    bad_db_packet(&db)?
+                };
+                self.write_packet(pkg.as_slice())?;
             }
-            PacketType::ComInitDB => {
-                let db = parse_com_init_db(data);
-                debug!("ComInitDB {}", db);
-                // todo set db
-                self.write_ok_packet(0, 0, status_flags, 0)?;
+            Command::ProcessInfo => {
+                if let Err(e) = self.processlist(handler, session, false, false) {
+                    self.write_exec_err(e)?;
+                }
             }
-            PacketType::ComPing => {
-                self.write_ok_packet(0, 0, status_flags, 0)?;
+            Command::ProcessKill(id) => {
+                if let Err(e) = self.kill(handler, session, id, false, false) {
+                    self.write_exec_err(e)?;
+                }
             }
-            PacketType::ComQuery => {
-                let query = parse_com_query(data);
-                let statements =
-                    if capability & CapabilityFlag::CapabilityClientMultiStatements as u32 != 0 {
-                        // todo multi statements
-                        info!("Multi statements");
-                        vec![query]
-                    } else {
-                        vec![query]
-                    };
-
+            Command::FieldList(table, wildcard) => {
+                match handler.com_field_list(session, &table, &wildcard) {
+                    Ok(fields) => {
+                        let packets = self.commands.field_list(&fields)?;
+                        self.write_packets(packets)?;
+                    }
+                    Err(e) => self.write_exec_err(e.into())?,
+                }
+            }
+            Command::ResetConnection => {
+                // The schema and the user stay, the other state is dropped.
+                session.extensions_mut().clear();
+                handler.com_reset_connection(session);
+                self.write_packet(self.commands.ok_packet()?.as_slice())?;
+            }
+            Command::Query(statements) => {
                 let length = statements.len();
                 for (index, sql) in statements.iter().enumerate() {
                     debug!("sql:{}", sql);
                     let more = index != length - 1;
-                    self.exec_query(handler.clone(), sql, more)?;
+                    // An error ends the batch, the remaining statements are not run.
+                    if let Err(e) = self.exec_statement(handler.clone(), session, sql, more) {
+                        self.write_exec_err(e)?;
+                        break;
+                    }
                 }
             }
-            PacketType::ComSetOption => {
-                let operation = parse_set_option(data);
-                match operation {
-                    Ok(n) => match n {
-                        0 => {
-                            self.capability |=
-                                CapabilityFlag::CapabilityClientMultiStatements as u32;
-                        }
-                        1 => {
-                            self.capability &=
-                                !(CapabilityFlag::CapabilityClientMultiStatements as u32);
-                        }
-                        _ => {
-                            self.write_err_packet(
-                                ServerError::ERUnknownComError as u16,
-                                StateError::SSUnknownComError.into(),
-                                "Unknown set option".to_string(),
-                            )?;
-                        }
-                    },
-                    Err(_) => {
-                        self.write_err_packet(
-                            ServerError::ERUnknownComError as u16,
-                            StateError::SSUnknownComError.into(),
-                            "Error parsing set option".to_string(),
-                        )?;
+            Command::Prepare(prepare) => {
+                match handler.com_prepare(session, &prepare.prepare_stmt, prepare.params_count) {
+                    Ok(fields) => {
+                        let packets = self.commands.prepared(prepare, &fields)?;
+                        self.write_packets(packets)?;
                     }
+                    Err(e) => self.write_exec_err(e.into())?,
                 }
             }
-            PacketType::ComStmtPrepare => {}
-            PacketType::ComStmtExecute => {}
-            PacketType::ComStmtReset => {}
-            PacketType::ComStmtClose => {}
-            _ => {
-                let cmd: PacketType = pt.into();
-                let cmd_str: &'static str = cmd.into();
-                debug!("Unknown command {}", cmd_str);
-                self.write_err_packet(
-                    ServerError::ERUnknownComError as u16,
-                    StateError::SSUnknownComError.into(),
-                    format!("Unknown command: {}", cmd_str),
-                )?;
+            Command::Execute(prepare) => {
+                if let Err(e) = self.exec_stmt(handler, session, &prepare) {
+                    self.write_exec_err(e)?;
+                }
             }
+            Command::Reply(packets) => self.write_packets(packets)?,
         }
         Ok(())
     }
 
-    pub fn exec_query(
+    /// Run a statement of a query, KILL and SHOW PROCESSLIST are answered
+    /// from the registry of the listener.
+    fn exec_statement(
         &mut self,
         handler: Arc<dyn Handler>,
+        session: &mut Session,
         sql: &str,
         more: bool,
     ) -> ProtoResult<()> {
-        let mut send_finished = false;
-        let mut field_sent = false;
-        handler.com_query(sql, &mut |qr: SqlResult| -> io::Result<()> {
-            let mut flags = self.status_flags;
-            if more {
-                flags |= SERVER_MORE_RESULTS_EXISTS;
-            }
-            if send_finished {
-                // failsafe
-                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, ""));
+        match self.commands.statement(sql) {
+            QueryStatement::Kill(id, query_only) => {
+                self.kill(handler, session, id, query_only, more)
             }
-            if !field_sent {
-                field_sent = true;
-                return if qr.fields.is_empty() {
-                    send_finished = true;
-                    // todo warning count
-                    self.write_ok_packet(qr.affected_rows, qr.insert_id, flags, 0)
-                } else {
-                    self.write_fields(qr)
-                };
+            QueryStatement::Processlist(full) => self.processlist(handler, session, full, more),
+            QueryStatement::Query => self.exec_query(handler, session, sql, more),
+        }
+    }
+
+    pub fn exec_query(
+        &mut self,
+        handler: Arc<dyn Handler>,
+        session: &mut Session,
+        sql: &str,
+        more: bool,
+    ) -> ProtoResult<()> {
+        let result = self.exec_with(more, false, |callback| {
+            handler.com_query(session, sql, callback)
+        });
+        interrupted(result, session)
+    }
+
+    pub fn exec_stmt(
+        &mut self,
+        handler: Arc<dyn Handler>,
+        session: &mut Session,
+        prepare: &PrepareData,
+    ) -> ProtoResult<()> {
+        let result = self.exec_with(false, true, |callback| {
+            handler.com_stmt_execute(session, prepare, callback)
+        });
+        interrupted(result, session)
+    }
+
+    /// SHOW [FULL] PROCESSLIST, the handler may amend the connections.
+    fn processlist(
+        &mut self,
+        handler: Arc<dyn Handler>,
+        session: &mut Session,
+        full: bool,
+        more: bool,
+    ) -> ProtoResult<()> {
+        let processes = handler.processlist(session, self.commands.processes())?;
+        let qr = processlist_result(&processes, full);
+        self.exec_with(more, false, |callback| callback(qr))
+    }
+
+    /// KILL [CONNECTION | QUERY] id, the handler decides whether the session
+    /// may kill the connection.
+    fn kill(
+        &mut self,
+        handler: Arc<dyn Handler>,
+        session: &mut Session,
+        id: u32,
+        query_only: bool,
+        more: bool,
+    ) -> ProtoResult<()> {
+        let user = self.commands.kill_target(id)?;
+        if !handler.check_kill(session, id, &user) {
+            return Err(ProtoError::KillDenied(id));
+        }
+        self.commands.kill(id, query_only);
+        self.exec_with(more, false, |_| Ok(()))
+    }
+
+    /// Run a handler callback and stream the results it produces, rows are
+    /// written with the binary protocol if binary is set.
+    fn exec_with<F>(&mut self, more: bool, binary: bool, exec: F) -> ProtoResult<()>
+    where
+        F: FnOnce(&mut dyn FnMut(SqlResult) -> io::Result<()>) -> io::Result<()>,
+    {
+        let mut encoder = self.commands.encoder(more, binary);
+        exec(&mut |qr: SqlResult| -> io::Result<()> {
+            for pkg in encoder.encode(qr)? {
+                self.write_packet(pkg.as_slice())?;
             }
-            return self.write_rows(qr);
+            Ok(())
         })?;
-        debug!("field_sent:{}, send_finished:{}", field_sent, send_finished);
-        if field_sent {
-            if !send_finished {
-                self.write_end_result(more, 0, 0, 0)?;
+        if let Some(pkg) = encoder.finish()? {
+            self.write_packet(pkg.as_slice())?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: ReadAndWrite + ?Sized> Packets<S> {
+    /// Answer the error returned by a handler with an ERR packet,
+    /// other errors close the connection.
+    fn write_exec_err(&mut self, err: ProtoError) -> ProtoResult<()> {
+        let pkg = exec_err_packet(err)?;
+        self.write_packet(pkg.as_slice())?;
+        Ok(())
+    }
+}
+
+/// ResultEncoder encodes the results a handler produces for one statement
+/// into packets, the first result is either an OK packet or the head of a
+/// result set whose rows are streamed by the next results. It is shared by
+/// the blocking and the async connections.
+pub struct ResultEncoder {
+    capability: u32,
+    status_flags: u16,
+    more: bool,
+    binary: bool,
+    field_sent: bool,
+    send_finished: bool,
+    // Fields of the result set, the binary protocol encodes rows by their type.
+    fields: Vec<Field>,
+}
+
+impl ResultEncoder {
+    pub fn new(capability: u32, status_flags: u16, more: bool, binary: bool) -> Self {
+        ResultEncoder {
+            capability,
+            status_flags,
+            more,
+            binary,
+            field_sent: false,
+            send_finished: false,
+            fields: vec![],
+        }
+    }
+
+    fn flags(&self) -> u16 {
+        if self.more {
+            self.status_flags | SERVER_MORE_RESULTS_EXISTS
+        } else {
+            self.status_flags
+        }
+    }
+
+    pub fn encode(&mut self, qr: SqlResult) -> io::Result<Vec<Vec<u8>>> {
+        if self.send_finished {
+            // failsafe
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, ""));
+        }
+        let mut packets = vec![];
+        if !self.field_sent {
+            self.field_sent = true;
+            if qr.fields.is_empty() {
+                self.send_finished = true;
+                // todo warning count
+                packets.push(ok_packet_with_header(
+                    OK_PACKET,
+                    qr.affected_rows,
+                    qr.insert_id,
+                    self.flags(),
+                    0,
+                )?);
+                return Ok(packets);
             }
+            packets.extend(fields_packets(
+                self.capability,
+                self.status_flags,
+                &qr.fields,
+            )?);
+            self.fields = qr.fields;
+        }
+        for row in qr.rows {
+            if self.binary {
+                packets.push(write_binary_row(&row, &self.fields)?);
+            } else {
+                packets.push(text_row_packet(row)?);
+            }
+        }
+        Ok(packets)
+    }
+
+    /// Return the packet ending the results, the handler producing no result
+    /// is answered with an empty OK.
+    pub fn finish(&self) -> io::Result<Option<Vec<u8>>> {
+        debug!(
+            "field_sent:{}, send_finished:{}",
+            self.field_sent, self.send_finished
+        );
+        if !self.field_sent {
+            return ok_packet_with_header(OK_PACKET, 0, 0, self.flags(), 0).map(Some);
+        }
+        if self.send_finished {
+            return Ok(None);
+        }
+        end_result_packet(self.capability, self.flags(), 0, 0, 0).map(Some)
+    }
+}
+
+/// Split data into packets of at most MAX_PACKET_SIZE bytes with their
+/// headers, a payload of a multiple of MAX_PACKET_SIZE ends with an empty packet.
+pub fn frame_packet(data: &[u8], sequence_id: &mut u8) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 4);
+    let mut last_len = 0;
+    for chunk in data.chunks(MAX_PACKET_SIZE) {
+        last_len = chunk.len();
+        buf.extend_from_slice(&[
+            last_len as u8,
+            (last_len >> 8) as u8,
+            (last_len >> 16) as u8,
+            *sequence_id,
+        ]);
+        buf.extend_from_slice(chunk);
+        *sequence_id = sequence_id.wrapping_add(1);
+    }
+    if data.is_empty() || last_len == MAX_PACKET_SIZE {
+        buf.extend_from_slice(&[0, 0, 0, *sequence_id]);
+        *sequence_id = sequence_id.wrapping_add(1);
+    }
+    buf
+}
+
+/// Check the sequence id of a packet header and return the payload length.
+pub fn parse_packet_header(header: &[u8; 4], sequence_id: &mut u8) -> io::Result<usize> {
+    debug!("Header:{:?}", header);
+    let sequence = header[3];
+    if sequence != *sequence_id {
+        error!(
+            "current sequence:{}, get sequence:{}",
+            sequence_id, sequence
+        );
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid sequence",
+        ));
+    }
+    *sequence_id = sequence_id.wrapping_add(1);
+    Ok((header[0] as usize) | (header[1] as usize) << 8 | (header[2] as usize) << 16)
+}
+
+/// OK packet, header is EOF_PACKET when it ends a result set of a client
+/// that deprecates EOF.
+pub fn ok_packet_with_header(
+    header: u8,
+    affected_rows: u64,
+    last_insert_id: u64,
+    flags: u16,
+    warnings: u16,
+) -> io::Result<Vec<u8>> {
+    let mut inner = Vec::with_capacity(
+        1 + len_enc_int_size(affected_rows) + len_enc_int_size(last_insert_id) + 2 + 2,
+    );
+
+    inner.write_u8(header)?;
+    // Affected rows
+    inner.write_len_int(affected_rows)?;
+    // Last insert id
+    inner.write_len_int(last_insert_id)?;
+
+    inner.write_u16::<LittleEndian>(flags)?;
+    inner.write_u16::<LittleEndian>(warnings)?;
+    Ok(inner)
+}
+
+pub fn eof_packet(flags: u16, warnings: u16) -> io::Result<Vec<u8>> {
+    let mut inner = Vec::with_capacity(1 + 2 + 2);
+    inner.write_u8(EOF_PACKET)?;
+    inner.write_u16::<LittleEndian>(warnings)?;
+    inner.write_u16::<LittleEndian>(flags)?;
+    Ok(inner)
+}
+
+/// End of a result set, an EOF packet or an OK packet if the client
+/// deprecates EOF.
+pub fn end_result_packet(
+    capability: u32,
+    flags: u16,
+    affected_rows: u64,
+    last_insert_id: u64,
+    warnings: u16,
+) -> io::Result<Vec<u8>> {
+    if capability & CapabilityFlag::CapabilityClientDeprecateEOF as u32 == 0 {
+        eof_packet(flags, warnings)
+    } else {
+        ok_packet_with_header(EOF_PACKET, affected_rows, last_insert_id, flags, warnings)
+    }
+}
+
+pub fn err_packet(err_code: u16, mut sql_state: String, err_msg: String) -> io::Result<Vec<u8>> {
+    let mut inner = Vec::with_capacity(1 + 2 + 1 + 5 + err_msg.len());
+    inner.write_u8(ERR_PACKET)?;
+    inner.write_u16::<LittleEndian>(err_code)?;
+    inner.write_u8(b'#')?;
+    if sql_state.is_empty() {
+        sql_state = StateError::SSUnknownSQLState.into();
+    }
+    assert_eq!(sql_state.len(), 5);
+
+    inner.write_all(sql_state.as_bytes())?;
+    inner.write_all(err_msg.as_bytes())?;
+    Ok(inner)
+}
+
+/// Result set of SHOW PROCESSLIST, the queries are truncated to 100
+/// characters unless full.
+pub fn processlist_result(processes: &[ProcessInfo], full: bool) -> SqlResult {
+    let field = |name: &str, typ: MysqlType| Field {
+        name: name.to_string(),
+        typ: typ as Type,
+        ..Default::default()
+    };
+    let value = |val: &str| Value {
+        typ: MysqlType::Varchar as Type,
+        val: val.as_bytes().to_vec(),
+    };
+    let null = Value {
+        typ: MysqlType::NullType as Type,
+        val: vec![],
+    };
+    let rows = processes
+        .iter()
+        .map(|process| {
+            let db = if process.db.is_empty() {
+                null.clone()
+            } else {
+                value(&process.db)
+            };
+            let info = match &process.info {
+                Some(info) if full => value(info),
+                Some(info) => value(&info.chars().take(100).collect::<String>()),
+                None => null.clone(),
+            };
+            vec![
+                value(&process.id.to_string()),
+                value(&process.user),
+                value(&process.host),
+                db,
+                value(&process.command),
+                value(&process.time.to_string()),
+                value(""),
+                info,
+            ]
+        })
+        .collect();
+    SqlResult {
+        fields: vec![
+            field("Id", MysqlType::Uint64),
+            field("User", MysqlType::Varchar),
+            field("Host", MysqlType::Varchar),
+            field("db", MysqlType::Varchar),
+            field("Command", MysqlType::Varchar),
+            field("Time", MysqlType::Int32),
+            field("State", MysqlType::Varchar),
+            field("Info", MysqlType::Varchar),
+        ],
+        rows,
+        ..Default::default()
+    }
+}
+
+/// A query that failed once its connection is killed is interrupted.
+pub fn interrupted(result: ProtoResult<()>, session: &Session) -> ProtoResult<()> {
+    match result {
+        Err(ProtoError::Io(_)) if session.cancel_token().is_cancelled() => {
+            Err(ProtoError::QueryInterrupted)
+        }
+        result => result,
+    }
+}
+
+/// ERR packet answering the error of a command, the errors which are not
+/// reported to the client are returned.
+pub fn exec_err_packet(err: ProtoError) -> ProtoResult<Vec<u8>> {
+    match err {
+        ProtoError::Io(_)
+        | ProtoError::QueryInterrupted
+        | ProtoError::NoSuchThread(_)
+        | ProtoError::KillDenied(_) => Ok(sql_err_packet(&SqlError::from(&err))?),
+        e => Err(e),
+    }
+}
+
+/// ERR packet carrying the code, the state and the message of err.
+pub fn sql_err_packet(err: &SqlError) -> io::Result<Vec<u8>> {
+    err_packet(err.code as u16, err.state.into(), err.message.clone())
+}
+
+/// ER_BAD_DB_ERROR sent when the handler does not know the database.
+pub fn bad_db_packet(db: &str) -> io::Result<Vec<u8>> {
+    err_packet(
+        ServerError::ERBadDb as u16,
+        StateError::SSBadDb.into(),
+        format!("Unknown database '{}'", db),
+    )
+}
+
+/// Column count of a result set followed by its column definitions.
+pub fn fields_packets(
+    capability: u32,
+    status_flags: u16,
+    fields: &[Field],
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut data = Vec::new();
+    // Write length of fields
+    data.write_len_int(fields.len() as u64)?;
+    let mut packets = vec![data];
+    packets.extend(column_definitions_packets(
+        capability,
+        status_flags,
+        fields,
+    )?);
+    Ok(packets)
+}
+
+/// Column definitions followed by an EOF packet unless the client deprecates it.
+fn column_definitions_packets(
+    capability: u32,
+    status_flags: u16,
+    fields: &[Field],
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut packets = Vec::with_capacity(fields.len() + 1);
+    for f in fields {
+        packets.push(column_definition(f)?);
+    }
+    if capability & CapabilityFlag::CapabilityClientDeprecateEOF as u32 == 0 {
+        packets.push(eof_packet(status_flags, 0)?);
+    }
+    Ok(packets)
+}
+
+fn column_definition(field: &Field) -> io::Result<Vec<u8>> {
+    let (typ, mut flags) = type_to_mysql(field.typ);
+    if field.flags != 0 {
+        flags = field.flags as i64;
+    }
+    let capacity = 4 +
+        len_enc_str_size(&field.database) +
+        len_enc_str_size(&field.table) +
+        len_enc_str_size(&field.org_table) +
+        len_enc_str_size(&field.name) +
+        len_enc_str_size(&field.org_name) +
+        1 + // length of fixed length fields
+        2 + // character set
+        4 + // column length
+        1 + // type
+        2 + // flags
+        1 + // decimals
+        2; // filler
+    let mut data = Vec::with_capacity(capacity);
+    data.write_len_str("def".as_ref())?;
+    data.write_len_str(field.database.as_bytes())?;
+    data.write_len_str(field.table.as_bytes())?;
+    data.write_len_str(field.org_table.as_bytes())?;
+    data.write_len_str(field.name.as_bytes())?;
+    data.write_len_str(field.org_name.as_bytes())?;
+
+    data.write_u8(0x0c)?;
+    data.write_u16::<LittleEndian>(field.charset as u16)?;
+    data.write_u32::<LittleEndian>(field.column_len)?;
+    data.write_u8(typ as u8)?;
+    data.write_u16::<LittleEndian>(flags as u16)?;
+    data.write_u8(field.decimals as u8)?;
+    data.write_u16::<LittleEndian>(0x0000)?;
+    Ok(data)
+}
+
+/// Column definitions answering COM_FIELD_LIST, they carry the default value
+/// of the columns and are ended by an EOF packet.
+pub fn field_list_packets(
+    capability: u32,
+    status_flags: u16,
+    fields: &[Field],
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut packets = Vec::with_capacity(fields.len() + 1);
+    for f in fields {
+        let mut data = column_definition(f)?;
+        match &f.default_value {
+            Some(value) => data.write_len_str(value.as_bytes())?,
+            None => data.write_u8(0xfb)?, // NULL
+        }
+        packets.push(data);
+    }
+    packets.push(end_result_packet(capability, status_flags, 0, 0, 0)?);
+    Ok(packets)
+}
+
+fn text_row_packet(row: Vec<Value>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for val in row {
+        if val.is_null() {
+            data.write_u8(0xfb)?; // NULL
         } else {
-            // todo failsafe
+            let l = val.val.len();
+            data.write_len_int(l as u64)?;
+            data.write_all(val.val.as_slice())?;
         }
-        Ok(())
     }
+    Ok(data)
 }
 
-fn parse_com_init_db(data: &[u8]) -> String {
+/// COM_STMT_PREPARE_OK, followed by the parameter and column definitions.
+/// See https://dev.mysql.com/doc/internals/en/com-stmt-prepare-response.html
+pub fn prepare_ok_packets(
+    capability: u32,
+    status_flags: u16,
+    prepare: &PrepareData,
+    fields: &[Field],
+) -> io::Result<Vec<Vec<u8>>> {
+    let mut data = Vec::with_capacity(1 + 4 + 2 + 2 + 1 + 2);
+    data.write_u8(OK_PACKET)?;
+    data.write_u32::<LittleEndian>(prepare.statement_id)?;
+    data.write_u16::<LittleEndian>(prepare.columns_count)?;
+    data.write_u16::<LittleEndian>(prepare.params_count)?;
+    // reserved
+    data.write_u8(0x00)?;
+    // warning count
+    data.write_u16::<LittleEndian>(0)?;
+    let mut packets = vec![data];
+
+    if prepare.params_count > 0 {
+        let params: Vec<Field> = (0..prepare.params_count)
+            .map(|_| Field {
+                name: "?".to_string(),
+                typ: MysqlType::VarBinary as Type,
+                charset: CHARACTER_SET_BINARY as u32,
+                ..Default::default()
+            })
+            .collect();
+        packets.extend(column_definitions_packets(
+            capability,
+            status_flags,
+            &params,
+        )?);
+    }
+    if !fields.is_empty() {
+        packets.extend(column_definitions_packets(
+            capability,
+            status_flags,
+            fields,
+        )?);
+    }
+    Ok(packets)
+}
+
+pub fn parse_com_init_db(data: &[u8]) -> ProtoResult<String> {
     trim_packet_type(data)
 }
 
-fn parse_com_query(data: &[u8]) -> String {
+pub fn parse_com_query(data: &[u8]) -> ProtoResult<String> {
     trim_packet_type(data)
 }
 
-fn trim_packet_type(data: &[u8]) -> String {
+/// Return the payload of a command as a string, it must be valid UTF-8.
+fn trim_packet_type(data: &[u8]) -> ProtoResult<String> {
     let tmp = data[1..].to_vec();
-    String::from_utf8(tmp).unwrap()
+    String::from_utf8(tmp).map_err(|_| ProtoError::MalformedPacket)
+}
+
+pub fn parse_com_process_kill(data: &[u8]) -> ProtoResult<u32> {
+    let mut data = &data[1..];
+    let id = data.read_u32::<LittleEndian>()?;
+    Ok(id)
+}
+
+/// Return the table and the column wildcard of COM_FIELD_LIST.
+pub fn parse_com_field_list(data: &[u8]) -> (String, String) {
+    let data = &data[1..];
+    match data.iter().position(|&b| b == 0x00) {
+        Some(pos) => (
+            String::from_utf8_lossy(&data[..pos]).into_owned(),
+            String::from_utf8_lossy(&data[pos + 1..]).into_owned(),
+        ),
+        None => (String::from_utf8_lossy(data).into_owned(), "".to_string()),
+    }
 }
 
-fn parse_com_statement(data: &[u8]) -> ProtoResult<u32> {
+pub fn parse_com_statement(data: &[u8]) -> ProtoResult<u32> {
     let mut data = &data[1..];
     let stmt_id = data.read_u32::<LittleEndian>()?;
     Ok(stmt_id)
 }
 
-fn parse_set_option(data: &[u8]) -> ProtoResult<u16> {
+pub fn parse_set_option(data: &[u8]) -> ProtoResult<u16> {
     let mut data = &data[1..];
     let option_result = data.read_u16::<LittleEndian>()?;
     Ok(option_result)
 }
 
+/// Parse an OK packet, return the affected rows, the last insert id, the
+/// status flags and the warnings.
+pub fn parse_ok_packet(data: &[u8]) -> ProtoResult<(u64, u64, u16, u16)> {
+    let mut data = data.get(1..).ok_or(ProtoError::MalformedPacket)?;
+    let affected_rows = data.read_len_int()?;
+    let last_insert_id = data.read_len_int()?;
+    let flags = data.read_u16::<LittleEndian>()?;
+    let warnings = data.read_u16::<LittleEndian>()?;
+    Ok((affected_rows, last_insert_id, flags, warnings))
+}
+
+/// Parse an EOF packet, return the status flags and the warnings.
+pub fn parse_eof_packet(data: &[u8]) -> ProtoResult<(u16, u16)> {
+    let mut data = data.get(1..).ok_or(ProtoError::MalformedPacket)?;
+    let warnings = data.read_u16::<LittleEndian>()?;
+    let flags = data.read_u16::<LittleEndian>()?;
+    Ok((flags, warnings))
+}
+
+/// Return the status flags of the packet ending column definitions or rows,
+/// None if data is another packet. It is an EOF packet, or an OK packet with
+/// the EOF header if the client deprecates EOF.
+pub fn parse_end_packet(data: &[u8], deprecate_eof: bool) -> Option<u16> {
+    if data.first() != Some(&EOF_PACKET) {
+        return None;
+    }
+    if deprecate_eof && data.len() < MAX_PACKET_SIZE {
+        if let Ok(ok) = parse_ok_packet(data) {
+            return Some(ok.2);
+        }
+    }
+    if data.len() < 9 {
+        return parse_eof_packet(data).ok().map(|eof| eof.0);
+    }
+    None
+}
+
+/// Parse an ERR packet into the error it reports, the sql state is missing
+/// from the errors sent before the handshake.
+pub fn parse_err_packet(data: &[u8]) -> ProtoError {
+    if data.len() < 3 || data[0] != ERR_PACKET {
+        return ProtoError::MalformedPacket;
+    }
+    let code = (&data[1..3]).read_u16::<LittleEndian>().unwrap_or(0);
+    let (state, message) = match data.get(3) {
+        Some(b'#') if data.len() >= 9 => (
+            String::from_utf8_lossy(&data[4..9]).into_owned(),
+            &data[9..],
+        ),
+        _ => (StateError::SSUnknownSQLState.into(), &data[3..]),
+    };
+    ProtoError::Server(code, state, String::from_utf8_lossy(message).into_owned())
+}
+
+/// Parse a column definition, see column_definition.
+pub fn parse_column_definition(data: &[u8]) -> ProtoResult<Field> {
+    let mut data = data;
+    let read_str = |data: &mut &[u8]| -> ProtoResult<String> {
+        Ok(String::from_utf8_lossy(&data.read_len_str()?).into_owned())
+    };
+    // catalog
+    data.read_len_str()?;
+    let database = read_str(&mut data)?;
+    let table = read_str(&mut data)?;
+    let org_table = read_str(&mut data)?;
+    let name = read_str(&mut data)?;
+    let org_name = read_str(&mut data)?;
+    // length of fixed length fields
+    data.read_len_int()?;
+    let charset = data.read_u16::<LittleEndian>()?;
+    let column_len = data.read_u32::<LittleEndian>()?;
+    let typ = data.read_u8()?;
+    let flags = data.read_u16::<LittleEndian>()?;
+    let decimals = data.read_u8()?;
+    let typ = mysql_to_type(typ as i64, flags as i64).ok_or(ProtoError::MalformedPacket)?;
+    Ok(Field {
+        name,
+        typ,
+        table,
+        org_table,
+        database,
+        org_name,
+        column_len,
+        charset: charset as u32,
+        decimals: decimals as u32,
+        flags: flags as u32,
+        default_value: None,
+    })
+}
+
+/// Parse a row of a text result set, the values take the type of their field.
+pub fn parse_text_row(data: &[u8], fields: &[Field]) -> ProtoResult<Vec<Value>> {
+    let mut data = data;
+    let mut row = Vec::with_capacity(fields.len());
+    for field in fields {
+        if data.first() == Some(&0xfb) {
+            data = &data[1..];
+            row.push(Value {
+                typ: MysqlType::NullType as Type,
+                val: vec![],
+            });
+        } else {
+            row.push(Value {
+                typ: field.typ,
+                val: data.read_len_str()?,
+            });
+        }
+    }
+    Ok(row)
+}
+
 fn len_enc_int_size(n: u64) -> usize {
     if n < 251 {
         1
@@ -586,10 +1128,22 @@ fn len_enc_str_size(v: &str) -> usize {
 
 #[cfg(test)]
 mod tests {
-    use crate::constants::OK_PACKET;
-    use crate::proto::packets::Packets;
+    use crate::constants::{
+        CapabilityFlag, ServerError, StateError, DEFAULT_SERVER_CAPABILITY, EOF_PACKET,
+        ERR_PACKET, OK_PACKET, SERVER_MORE_RESULTS_EXISTS,
+    };
+    use crate::errors::{ProtoError, SqlError};
+    use crate::proto::packets::{
+        column_definition, eof_packet, err_packet, ok_packet_with_header, parse_column_definition,
+        parse_end_packet, parse_eof_packet, parse_err_packet, parse_ok_packet, Packets,
+    };
+    use crate::proto::{Auth, PrepareData, Session};
+    use crate::sql_type::{Field, MysqlType, SqlResult, Type, Value};
+    use crate::Handler;
     use std::cell::RefCell;
     use std::io;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
 
     struct MockStorage {
         content: *const RefCell<String>,
@@ -646,4 +1200,429 @@ mod tests {
         let data = client.read_packets().unwrap();
         assert_eq!(data[0], OK_PACKET);
     }
+
+    struct MockHandler {}
+
+    impl Handler for MockHandler {
+        fn new_connection(&self, _session: &mut Session) {}
+        fn close_connection(&self, _session: &mut Session) {}
+        fn com_query(
+            &self,
+            _session: &mut Session,
+            sql: &str,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            match sql {
+                "SELECT 1" => callback(SqlResult {
+                    fields: vec![Field {
+                        name: "1".to_string(),
+                        typ: MysqlType::Int64 as Type,
+                        ..Default::default()
+                    }],
+                    rows: vec![vec![Value {
+                        typ: MysqlType::Int64 as Type,
+                        val: b"1".to_vec(),
+                    }]],
+                    ..Default::default()
+                }),
+                "UPDATE t SET a = 1" => callback(SqlResult {
+                    affected_rows: 1,
+                    ..Default::default()
+                }),
+                "SELECT * FROM missing" => Err(SqlError::new(
+                    ServerError::ERNoSuchTable,
+                    StateError::SSNoSuchTable,
+                    "Table 'test.missing' doesn't exist",
+                )
+                .into()),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Unknown statement: {}", sql),
+                )),
+            }
+        }
+        fn com_init_db(&self, _session: &mut Session, schema: &str) -> bool {
+            schema == "test"
+        }
+        fn com_field_list(
+            &self,
+            _session: &mut Session,
+            table: &str,
+            wildcard: &str,
+        ) -> io::Result<Vec<Field>> {
+            if table != "t" {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Unknown table: {}", table),
+                ));
+            }
+            let fields = vec![
+                Field {
+                    name: "a".to_string(),
+                    typ: MysqlType::Int32 as Type,
+                    default_value: Some("1".to_string()),
+                    ..Default::default()
+                },
+                Field {
+                    name: "b".to_string(),
+                    typ: MysqlType::Varchar as Type,
+                    ..Default::default()
+                },
+            ];
+            Ok(fields
+                .into_iter()
+                .filter(|f| wildcard.is_empty() || f.name == wildcard)
+                .collect())
+        }
+        fn com_reset_connection(&self, session: &mut Session) {
+            session.extensions_mut().insert(0u32);
+        }
+        fn check_auth(&self, _auth: &Auth, _salt: &[u8], _addr: &SocketAddr) -> bool {
+            true
+        }
+        fn com_prepare(
+            &self,
+            _session: &mut Session,
+            sql: &str,
+            params_count: u16,
+        ) -> io::Result<Vec<Field>> {
+            assert_eq!(sql, "SELECT a FROM t WHERE b = ?");
+            assert_eq!(params_count, 1);
+            Ok(vec![Field {
+                name: "a".to_string(),
+                typ: MysqlType::Int32 as Type,
+                ..Default::default()
+            }])
+        }
+        fn com_stmt_execute(
+            &self,
+            _session: &mut Session,
+            prepare: &PrepareData,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            assert_eq!(prepare.params[0].val, b"7".to_vec());
+            callback(SqlResult {
+                affected_rows: prepare.params.len() as u64,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn test_prepare() {
+        let store = RefCell::new(String::default());
+        let mut client = Packets::new();
+        client.set_stream(Box::new(MockStorage { content: &store }));
+        let mut server = Packets::new();
+        server.set_stream(Box::new(MockStorage { content: &store }));
+        let handler = Arc::new(MockHandler {});
+        let mut session = Session::new(1, "127.0.0.1:3306".parse().unwrap());
+
+        client
+            .write_packet(b"\x16SELECT a FROM t WHERE b = ?")
+            .unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        // statement id 1, 1 column, 1 param
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[..9], [OK_PACKET, 1, 0, 0, 0, 1, 0, 1, 0]);
+        // param definition, EOF, column definition, EOF
+        for _ in 0..2 {
+            let column = client.read_packets().unwrap();
+            assert_eq!(column[..4], [3, b'd', b'e', b'f']);
+            assert_eq!(client.read_packets().unwrap()[0], EOF_PACKET);
+        }
+
+        client.sequence_id = 0;
+        client
+            .write_packet(&[
+                0x17, 1, 0, 0, 0, 0, 1, 0, 0, 0, // header
+                0x00, 0x01, 0x03, 0x00, 7, 0, 0, 0, // one LONG param
+            ])
+            .unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[..2], [OK_PACKET, 1]);
+
+        // Close, no response is sent.
+        client.sequence_id = 0;
+        client.write_packet(&[0x19, 1, 0, 0, 0]).unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        assert!(store.borrow().is_empty());
+
+        client.sequence_id = 0;
+        client.write_packet(&[0x1a, 1, 0, 0, 0]).unwrap();
+        server
+            .handle_next_command(handler, &mut session, 0)
+            .unwrap();
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0xdb, 0x04]);
+    }
+
+    #[test]
+    fn test_init_db() {
+        let store = RefCell::new(String::default());
+        let mut client = Packets::new();
+        client.set_stream(Box::new(MockStorage { content: &store }));
+        let mut server = Packets::new();
+        server.set_stream(Box::new(MockStorage { content: &store }));
+        let handler = Arc::new(MockHandler {});
+        let mut session = Session::new(1, "127.0.0.1:3306".parse().unwrap());
+
+        client.write_packet(b"\x02test").unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        assert_eq!(client.read_packets().unwrap()[0], OK_PACKET);
+        assert_eq!(session.schema(), "test");
+
+        // The schema is kept when the database is unknown.
+        client.sequence_id = 0;
+        client.write_packet(b"\x02unknown").unwrap();
+        server
+            .handle_next_command(handler, &mut session, 0)
+            .unwrap();
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0x19, 0x04]);
+        assert!(String::from_utf8_lossy(&data).ends_with("Unknown database 'unknown'"));
+        assert_eq!(session.schema(), "test");
+    }
+
+    #[test]
+    fn test_sql_error() {
+        let store = RefCell::new(String::default());
+        let mut client = Packets::new();
+        client.set_stream(Box::new(MockStorage { content: &store }));
+        let mut server = Packets::new();
+        server.set_stream(Box::new(MockStorage { content: &store }));
+        let handler = Arc::new(MockHandler {});
+        let mut session = Session::new(1, "127.0.0.1:3306".parse().unwrap());
+
+        // The handler error is reported and the connection stays open.
+        client.write_packet(b"\x03SELECT * FROM missing").unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0x7a, 0x04]);
+        assert_eq!(&data[3..9], b"#42S02");
+        assert!(String::from_utf8_lossy(&data).ends_with("Table 'test.missing' doesn't exist"));
+
+        client.sequence_id = 0;
+        client.write_packet(b"\x03UPDATE t SET a = 1").unwrap();
+        server
+            .handle_next_command(handler, &mut session, 0)
+            .unwrap();
+        assert_eq!(client.read_packets().unwrap()[..2], [OK_PACKET, 1]);
+
+        client.sequence_id = 0;
+        server.sequence_id = 0;
+        server
+            .write_err_packet_from_err(&ProtoError::UnknownStmtHandler(3))
+            .unwrap();
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0xdb, 0x04]);
+        assert_eq!(&data[3..9], b"#HY000");
+    }
+
+    #[test]
+    fn test_parse_packets() {
+        let field = Field {
+            name: "id".to_string(),
+            typ: MysqlType::Uint32 as Type,
+            table: "t".to_string(),
+            database: "test".to_string(),
+            column_len: 10,
+            charset: 63,
+            ..Default::default()
+        };
+        let parsed = parse_column_definition(&column_definition(&field).unwrap()).unwrap();
+        assert_eq!(parsed.name, "id");
+        assert_eq!(parsed.typ, MysqlType::Uint32 as Type);
+        assert_eq!(
+            (parsed.table.as_str(), parsed.database.as_str()),
+            ("t", "test")
+        );
+        assert_eq!((parsed.column_len, parsed.charset), (10, 63));
+
+        let data = ok_packet_with_header(OK_PACKET, 300, 7, 2, 1).unwrap();
+        assert_eq!(parse_ok_packet(&data).unwrap(), (300, 7, 2, 1));
+        let data = eof_packet(SERVER_MORE_RESULTS_EXISTS, 3).unwrap();
+        assert_eq!(
+            parse_eof_packet(&data).unwrap(),
+            (SERVER_MORE_RESULTS_EXISTS, 3)
+        );
+        // The EOF packets still end the rows of the clients deprecating EOF.
+        assert_eq!(
+            parse_end_packet(&data, true),
+            Some(SERVER_MORE_RESULTS_EXISTS)
+        );
+        let data = ok_packet_with_header(EOF_PACKET, 0, 0, 2, 0).unwrap();
+        assert_eq!(parse_end_packet(&data, true), Some(2));
+        assert_eq!(parse_end_packet(&[0xfe; 12], false), None);
+        let data = err_packet(
+            1049,
+            "42000".to_string(),
+            "Unknown database 'a'".to_string(),
+        )
+        .unwrap();
+        match parse_err_packet(&data) {
+            ProtoError::Server(code, state, message) => {
+                assert_eq!((code, state.as_str()), (1049, "42000"));
+                assert_eq!(message, "Unknown database 'a'");
+            }
+            e => panic!("Unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn test_reset_connection() {
+        let store = RefCell::new(String::default());
+        let mut client = Packets::new();
+        client.set_stream(Box::new(MockStorage { content: &store }));
+        let mut server = Packets::new();
+        server.set_stream(Box::new(MockStorage { content: &store }));
+        let handler = Arc::new(MockHandler {});
+        let mut session = Session::new(1, "127.0.0.1:3306".parse().unwrap());
+        session.set_schema("test");
+        session.extensions_mut().insert("variable".to_string());
+        session.extensions_mut().insert(7u32);
+
+        client
+            .write_packet(b"\x16SELECT a FROM t WHERE b = ?")
+            .unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        for _ in 0..5 {
+            client.read_packets().unwrap();
+        }
+
+        client.sequence_id = 0;
+        client.write_packet(&[0x1f]).unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        assert_eq!(client.read_packets().unwrap()[0], OK_PACKET);
+        assert_eq!(session.schema(), "test");
+        assert_eq!(session.extensions().get::<String>(), None);
+        // Set again by the handler.
+        assert_eq!(session.extensions().get::<u32>(), Some(&0));
+
+        // The statement is gone.
+        client.sequence_id = 0;
+        client.write_packet(&[0x1a, 1, 0, 0, 0]).unwrap();
+        server
+            .handle_next_command(handler, &mut session, 0)
+            .unwrap();
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[..3], [ERR_PACKET, 0xdb, 0x04]);
+    }
+
+    #[test]
+    fn test_field_list() {
+        let store = RefCell::new(String::default());
+        let mut client = Packets::new();
+        client.set_stream(Box::new(MockStorage { content: &store }));
+        let mut server = Packets::new();
+        server.set_stream(Box::new(MockStorage { content: &store }));
+        let handler = Arc::new(MockHandler {});
+        let mut session = Session::new(1, "127.0.0.1:3306".parse().unwrap());
+
+        client.write_packet(b"\x04t\x00").unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        // The default value follows the column definition.
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[..4], [3, b'd', b'e', b'f']);
+        assert!(data.ends_with(&[0x00, 0x00, 1, b'1']));
+        let data = client.read_packets().unwrap();
+        assert!(data.ends_with(&[0x00, 0x00, 0xfb]));
+        assert_eq!(client.read_packets().unwrap()[0], EOF_PACKET);
+        assert!(store.borrow().is_empty());
+
+        client.sequence_id = 0;
+        client.write_packet(b"\x04t\x00b").unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        assert_eq!(client.read_packets().unwrap()[..4], [3, b'd', b'e', b'f']);
+        assert_eq!(client.read_packets().unwrap()[0], EOF_PACKET);
+
+        client.sequence_id = 0;
+        client.write_packet(b"\x04unknown\x00").unwrap();
+        server
+            .handle_next_command(handler, &mut session, 0)
+            .unwrap();
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[0], ERR_PACKET);
+        assert!(String::from_utf8_lossy(&data).ends_with("Unknown table: unknown"));
+    }
+
+    /// Return the status flags of an OK packet with short length encoded integers.
+    fn ok_flags(data: &[u8]) -> u16 {
+        u16::from_le_bytes([data[3], data[4]])
+    }
+
+    #[test]
+    fn test_multi_statements() {
+        let store = RefCell::new(String::default());
+        let mut client = Packets::new();
+        client.set_stream(Box::new(MockStorage { content: &store }));
+        let mut server = Packets::new();
+        server.set_stream(Box::new(MockStorage { content: &store }));
+        server.set_capability(DEFAULT_SERVER_CAPABILITY);
+        let handler = Arc::new(MockHandler {});
+        let mut session = Session::new(1, "127.0.0.1:3306".parse().unwrap());
+
+        client
+            .write_packet(b"\x03SELECT 1; UPDATE t SET a = 1; BAD; SELECT 1")
+            .unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        // column count, column definition, row, then the end of the result set
+        // with an OK packet as EOF is deprecated
+        assert_eq!(client.read_packets().unwrap(), vec![1]);
+        client.read_packets().unwrap();
+        assert_eq!(client.read_packets().unwrap(), vec![1, b'1']);
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[0], EOF_PACKET);
+        assert_eq!(ok_flags(&data), SERVER_MORE_RESULTS_EXISTS);
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[..2], [OK_PACKET, 1]);
+        assert_eq!(ok_flags(&data), SERVER_MORE_RESULTS_EXISTS);
+        // The error ends the batch.
+        let data = client.read_packets().unwrap();
+        assert_eq!(data[0], ERR_PACKET);
+        assert!(String::from_utf8_lossy(&data).ends_with("Unknown statement: BAD"));
+        assert!(store.borrow().is_empty());
+
+        // Disable multi statements, the query is run as a whole.
+        client.sequence_id = 0;
+        client.write_packet(&[0x1b, 0x01, 0x00]).unwrap();
+        server
+            .handle_next_command(handler.clone(), &mut session, 0)
+            .unwrap();
+        assert_eq!(client.read_packets().unwrap()[0], EOF_PACKET);
+        assert_eq!(
+            server.commands.capability() & CapabilityFlag::CapabilityClientMultiStatements as u32,
+            0
+        );
+        client.sequence_id = 0;
+        client
+            .write_packet(b"\x03UPDATE t SET a = 1; UPDATE t SET a = 1")
+            .unwrap();
+        server
+            .handle_next_command(handler, &mut session, 0)
+            .unwrap();
+        assert_eq!(client.read_packets().unwrap()[0], ERR_PACKET);
+        assert!(store.borrow().is_empty());
+    }
 }
@@ -0,0 +1,344 @@
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::Client;
+
+use dakv_logger::prelude::*;
+
+struct IdleClient {
+    client: Client,
+    // since is when the client was returned to the pool.
+    since: Instant,
+}
+
+#[derive(Default)]
+struct PoolState {
+    // idle holds the clients ready to be borrowed, the most recently returned last.
+    idle: VecDeque<IdleClient>,
+    // size is the number of open clients, idle or borrowed.
+    size: usize,
+}
+
+/// Pool keeps connections to a server for reuse, share it between threads
+/// with an Arc. The idle connections are checked with COM_PING when they are
+/// borrowed and reset with COM_RESET_CONNECTION when they are returned.
+pub struct Pool {
+    addr: String,
+    user: String,
+    password: String,
+    database: String,
+    // The idle connections past idle_timeout are closed while the pool holds
+    // more than min_size connections.
+    min_size: usize,
+    max_size: usize,
+    idle_timeout: Duration,
+    // checkout_timeout is how long get waits for a connection once max_size
+    // connections are borrowed. It does not bound connecting or the COM_PING
+    // of an idle connection, the connections have no read or write timeout.
+    checkout_timeout: Duration,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+impl Pool {
+    pub fn new(addr: &str, user: &str, password: &str, database: &str) -> Self {
+        Pool {
+            addr: addr.to_string(),
+            user: user.to_string(),
+            password: password.to_string(),
+            database: database.to_string(),
+            min_size: 0,
+            max_size: 10,
+            idle_timeout: Duration::from_secs(600),
+            checkout_timeout: Duration::from_secs(30),
+            state: Mutex::new(PoolState::default()),
+            available: Condvar::new(),
+        }
+    }
+
+    pub fn set_min_size(&mut self, min_size: usize) {
+        self.min_size = min_size;
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn set_checkout_timeout(&mut self, checkout_timeout: Duration) {
+        self.checkout_timeout = checkout_timeout;
+    }
+
+    /// Open connections until the pool holds min_size of them.
+    pub fn fill(&self) -> ProtoResult<()> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.size >= self.min_size {
+                    return Ok(());
+                }
+                state.size += 1;
+            }
+            let client = self.connect()?;
+            self.put(client);
+        }
+    }
+
+    /// Return the number of open connections, idle or borrowed.
+    pub fn size(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+
+    pub fn idle_count(&self) -> usize {
+        self.state.lock().unwrap().idle.len()
+    }
+
+    /// Borrow a connection, it goes back to the pool when the PooledClient is
+    /// dropped. Wait for a connection to be returned if max_size connections
+    /// are borrowed, until checkout_timeout, which only covers the wait for a
+    /// free slot.
+    pub fn get(&self) -> ProtoResult<PooledClient<'_>> {
+        let deadline = Instant::now() + self.checkout_timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            self.close_expired(&mut state);
+            if let Some(idle) = state.idle.pop_back() {
+                drop(state);
+                let mut client = idle.client;
+                match client.ping() {
+                    Ok(()) => return Ok(self.pooled(client)),
+                    Err(e) => {
+                        debug!("Close broken pooled connection: {}", e);
+                        state = self.state.lock().unwrap();
+                        state.size -= 1;
+                        continue;
+                    }
+                }
+            }
+            if state.size < self.max_size {
+                state.size += 1;
+                drop(state);
+                return self.connect().map(|client| self.pooled(client));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ProtoError::PoolTimeout);
+            }
+            state = self
+                .available
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    fn pooled(&self, client: Client) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    /// Open a connection counted in the size of the pool, the slot is
+    /// released if it fails.
+    fn connect(&self) -> ProtoResult<Client> {
+        let result = Client::connect(
+            self.addr.as_str(),
+            &self.user,
+            &self.password,
+            &self.database,
+        );
+        if result.is_err() {
+            self.release();
+        }
+        result
+    }
+
+    fn put(&self, client: Client) {
+        let mut state = self.state.lock().unwrap();
+        state.idle.push_back(IdleClient {
+            client,
+            since: Instant::now(),
+        });
+        self.available.notify_one();
+    }
+
+    /// Forget a closed connection.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.size -= 1;
+        self.available.notify_one();
+    }
+
+    /// Close the connections idle for longer than idle_timeout, oldest first.
+    fn close_expired(&self, state: &mut PoolState) {
+        while state.size > self.min_size {
+            match state.idle.front() {
+                Some(idle) if idle.since.elapsed() >= self.idle_timeout => {
+                    state.idle.pop_front();
+                    state.size -= 1;
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+/// PooledClient is a connection borrowed from a Pool.
+pub struct PooledClient<'a> {
+    pool: &'a Pool,
+    client: Option<Client>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        let mut client = self.client.take().unwrap();
+        match client.reset_connection() {
+            Ok(()) => self.pool.put(client),
+            Err(e) => {
+                debug!("Close pooled connection failing to reset: {}", e);
+                self.pool.release();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::errors::ProtoError;
+    use crate::proto::{Auth, Handler, Listener, Pool, Session};
+    use crate::sql_type::SqlResult;
+
+    #[derive(Default)]
+    struct CountHandler {
+        resets: AtomicUsize,
+        closed: AtomicUsize,
+    }
+
+    impl Handler for CountHandler {
+        fn new_connection(&self, _session: &mut Session) {}
+        fn close_connection(&self, _session: &mut Session) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+        fn com_query(
+            &self,
+            _session: &mut Session,
+            _sql: &str,
+            callback: &mut dyn FnMut(SqlResult) -> io::Result<()>,
+        ) -> io::Result<()> {
+            callback(SqlResult::default())
+        }
+        fn com_reset_connection(&self, _session: &mut Session) {
+            self.resets.fetch_add(1, Ordering::SeqCst);
+        }
+        fn check_auth(&self, _auth: &Auth, _salt: &[u8], _addr: &SocketAddr) -> bool {
+            true
+        }
+    }
+
+    fn listen(handler: Arc<CountHandler>) -> String {
+        let mut listener = Listener::new_tcp_listener("127.0.0.1:0");
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || listener.accept(handler));
+        addr.to_string()
+    }
+
+    #[test]
+    fn test_pool() {
+        let handler = Arc::new(CountHandler::default());
+        let mut pool = Pool::new(&listen(handler.clone()), "root", "", "");
+        pool.set_min_size(1);
+        pool.set_max_size(2);
+        pool.set_checkout_timeout(Duration::from_millis(100));
+        let pool = Arc::new(pool);
+        pool.fill().unwrap();
+        assert_eq!((pool.size(), pool.idle_count()), (1, 1));
+
+        let mut first = pool.get().unwrap();
+        let first_id = first.connection_id();
+        first.query("SELECT 1").unwrap();
+        let second = pool.get().unwrap();
+        assert_eq!(pool.size(), 2);
+        match pool.get() {
+            Err(ProtoError::PoolTimeout) => {}
+            _ => panic!("Pool should be exhausted"),
+        }
+
+        // The connection is reset when it is returned, then reused.
+        drop(first);
+        assert_eq!(handler.resets.load(Ordering::SeqCst), 1);
+        let first = pool.get().unwrap();
+        assert_eq!(first.connection_id(), first_id);
+
+        // A waiting borrower gets the connection returned by another thread.
+        let waiter = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get().map(|client| client.connection_id()).unwrap())
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(first);
+        assert_eq!(waiter.join().unwrap(), first_id);
+        drop(second);
+        assert_eq!((pool.size(), pool.idle_count()), (2, 2));
+    }
+
+    #[test]
+    fn test_health_check() {
+        let handler = Arc::new(CountHandler::default());
+        let mut pool = Pool::new(&listen(handler.clone()), "root", "", "");
+        pool.set_idle_timeout(Duration::from_millis(50));
+
+        // Kill a connection while it is idle in the pool.
+        let mut killer = pool.get().unwrap();
+        let victim = pool.get().unwrap();
+        let victim_id = victim.connection_id();
+        drop(victim);
+        killer.query(&format!("KILL {}", victim_id)).unwrap();
+        // The broken connection is replaced.
+        let client = pool.get().unwrap();
+        assert_ne!(client.connection_id(), victim_id);
+        assert_eq!(pool.size(), 2);
+        drop(client);
+        drop(killer);
+
+        // Idle connections expire.
+        thread::sleep(Duration::from_millis(100));
+        let client = pool.get().unwrap();
+        assert_eq!(pool.size(), 1);
+        drop(client);
+        for _ in 0..100 {
+            if handler.closed.load(Ordering::SeqCst) == 3 {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Expired connections are not closed");
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::constants::PacketType;
+use crate::errors::{ProtoError, ProtoResult};
+use crate::proto::binary::{binary_type, param_type, read_binary_value, write_binary_value};
+use crate::sql_type::{MysqlType, Type, Value};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// PrepareData is the state of a prepared statement, kept by the connection
+/// between COM_STMT_PREPARE and COM_STMT_CLOSE.
+#[derive(Debug, Clone, Default)]
+pub struct PrepareData {
+    pub statement_id: u32,
+    pub prepare_stmt: String,
+    pub params_count: u16,
+    pub columns_count: u16,
+    // params_type is the (type, flag) pair of every parameter, as bound by the
+    // last COM_STMT_EXECUTE with the new-params-bound flag set.
+    pub params_type: Vec<(u8, u8)>,
+    // params holds the values bound by the last COM_STMT_EXECUTE.
+    pub params: Vec<Value>,
+    // long_data holds the data sent by COM_STMT_SEND_LONG_DATA, by parameter index.
+    long_data: HashMap<u16, Vec<u8>>,
+}
+
+impl PrepareData {
+    pub fn new(statement_id: u32, prepare_stmt: String, params_count: u16) -> Self {
+        PrepareData {
+            statement_id,
+            prepare_stmt,
+            params_count,
+            ..Default::default()
+        }
+    }
+
+    pub fn append_long_data(&mut self, param_id: u16, data: &[u8]) {
+        self.long_data
+            .entry(param_id)
+            .or_default()
+            .extend_from_slice(data);
+    }
+
+    /// Reset the data accumulated by COM_STMT_SEND_LONG_DATA.
+    pub fn reset(&mut self) {
+        self.long_data.clear();
+    }
+}
+
+/// Parse a COM_STMT_EXECUTE packet and bind its parameters into the prepared
+/// statement it refers to, return the statement id.
+/// See https://dev.mysql.com/doc/internals/en/com-stmt-execute.html
+pub fn parse_com_stmt_execute(
+    data: &[u8],
+    prepares: &mut HashMap<u32, PrepareData>,
+) -> ProtoResult<u32> {
+    let mut data = &data[1..];
+    let stmt_id = data
+        .read_u32::<LittleEndian>()
+        .map_err(|_| ProtoError::ParseComStmtExecuteError)?;
+    let prepare = prepares
+        .get_mut(&stmt_id)
+        .ok_or(ProtoError::UnknownStmtHandler(stmt_id))?;
+    // [u8] flags, only CURSOR_TYPE_NO_CURSOR is supported
+    data.read_u8()
+        .map_err(|_| ProtoError::ParseComStmtExecuteError)?;
+    // [u32] iteration count, always 1
+    data.read_u32::<LittleEndian>()
+        .map_err(|_| ProtoError::ParseComStmtExecuteError)?;
+
+    let count = prepare.params_count as usize;
+    if count == 0 {
+        return Ok(stmt_id);
+    }
+    let bitmap_len = (count + 7) / 8;
+    if data.len() < bitmap_len {
+        return Err(ProtoError::ParseComStmtExecuteError);
+    }
+    let (null_bitmap, mut data) = data.split_at(bitmap_len);
+    let new_params_bound = data
+        .read_u8()
+        .map_err(|_| ProtoError::ParseComStmtExecuteError)?;
+    if new_params_bound == 1 {
+        prepare.params_type.clear();
+        for _ in 0..count {
+            let typ = data
+                .read_u8()
+                .map_err(|_| ProtoError::ParseComStmtExecuteError)?;
+            let flag = data
+                .read_u8()
+                .map_err(|_| ProtoError::ParseComStmtExecuteError)?;
+            prepare.params_type.push((typ, flag));
+        }
+    }
+    if prepare.params_type.len() != count {
+        return Err(ProtoError::ParseComStmtExecuteError);
+    }
+
+    let mut params = Vec::with_capacity(count);
+    for i in 0..count {
+        let (typ, flag) = prepare.params_type[i];
+        if let Some(long_data) = prepare.long_data.remove(&(i as u16)) {
+            params.push(Value {
+                typ: param_type(typ, flag)?,
+                val: long_data,
+            });
+        } else if null_bitmap[i / 8] & (1 << (i % 8)) != 0 {
+            params.push(Value {
+                typ: MysqlType::NullType as Type,
+                val: vec![],
+            });
+        } else {
+            let value = read_binary_value(&mut data, typ, flag)
+                .map_err(|_| ProtoError::ParseComStmtExecuteError)?;
+            params.push(value);
+        }
+    }
+    prepare.params = params;
+    Ok(stmt_id)
+}
+
+/// Encode a COM_STMT_EXECUTE packet binding params, the parameter types are
+/// sent on every execution.
+pub fn write_com_stmt_execute(stmt_id: u32, params: &[Value]) -> io::Result<Vec<u8>> {
+    let mut data = vec![PacketType::ComStmtExecute as u8];
+    data.write_u32::<LittleEndian>(stmt_id)?;
+    // [u8] flags, CURSOR_TYPE_NO_CURSOR
+    data.write_u8(0x00)?;
+    // [u32] iteration count
+    data.write_u32::<LittleEndian>(1)?;
+    if params.is_empty() {
+        return Ok(data);
+    }
+    let mut null_bitmap = vec![0; (params.len() + 7) / 8];
+    let mut types = Vec::with_capacity(params.len() * 2);
+    let mut values = vec![];
+    for (i, param) in params.iter().enumerate() {
+        if param.is_null() {
+            null_bitmap[i / 8] |= 1 << (i % 8);
+            types.extend_from_slice(&[0x06, 0x00]);
+            continue;
+        }
+        let (typ, flag) = binary_type(param.typ);
+        types.extend_from_slice(&[typ, flag]);
+        write_binary_value(&mut values, param, typ, flag)?;
+    }
+    data.extend_from_slice(&null_bitmap);
+    // new params bound
+    data.write_u8(1)?;
+    data.extend_from_slice(&types);
+    data.extend_from_slice(&values);
+    Ok(data)
+}
+
+/// Parse a COM_STMT_SEND_LONG_DATA packet, return the statement id,
+/// the parameter index and the data.
+pub fn parse_com_stmt_send_long_data(data: &[u8]) -> ProtoResult<(u32, u16, &[u8])> {
+    let mut data = &data[1..];
+    let stmt_id = data
+        .read_u32::<LittleEndian>()
+        .map_err(|_| ProtoError::ParseComStatementError)?;
+    let param_id = data
+        .read_u16::<LittleEndian>()
+        .map_err(|_| ProtoError::ParseComStatementError)?;
+    Ok((stmt_id, param_id, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::errors::ProtoError;
+    use crate::proto::prepare::{parse_com_stmt_execute, write_com_stmt_execute, PrepareData};
+    use crate::sql_type::{MysqlType, Type, Value};
+
+    #[test]
+    fn test_parse_com_stmt_execute() {
+        let mut prepares = HashMap::new();
+        prepares.insert(1, PrepareData::new(1, "SELECT ?, ?, ?, ?".to_string(), 4));
+        let data = &[
+            0x17, 0x01, 0x00, 0x00, 0x00, // statement id
+            0x00, // flags
+            0x01, 0x00, 0x00, 0x00, // iteration count
+            0x02, // null bitmap, the second parameter is NULL
+            0x01, // new params bound
+            0x08, 0x00, 0x06, 0x00, 0xfd, 0x00, 0x0a, 0x00, // types
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // -1
+            0x03, 0x61, 0x62, 0x63, // "abc"
+            0x04, 0xe4, 0x07, 0x02, 0x1d, // 2020-02-29
+        ];
+        assert_eq!(parse_com_stmt_execute(data, &mut prepares).unwrap(), 1);
+        let params = &prepares[&1].params;
+        assert_eq!(params.len(), 4);
+        assert_eq!(params[0].typ, MysqlType::Int64 as Type);
+        assert_eq!(params[0].val, b"-1".to_vec());
+        assert!(params[1].is_null());
+        assert_eq!(params[2].typ, MysqlType::Varchar as Type);
+        assert_eq!(params[2].val, b"abc".to_vec());
+        assert_eq!(params[3].typ, MysqlType::Date as Type);
+        assert_eq!(params[3].val, b"2020-02-29".to_vec());
+
+        // Reuse the bound types, with unsigned long data for the first parameter.
+        prepares.get_mut(&1).unwrap().append_long_data(0, b"42");
+        let data = &[
+            0x17, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, // header
+            0x0e, // null bitmap
+            0x00, // new params bound
+        ];
+        parse_com_stmt_execute(data, &mut prepares).unwrap();
+        let params = &prepares[&1].params;
+        assert_eq!(params[0].val, b"42".to_vec());
+        assert!(params[1].is_null() && params[2].is_null() && params[3].is_null());
+
+        let data = &[0x17, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        match parse_com_stmt_execute(data, &mut prepares) {
+            Err(ProtoError::UnknownStmtHandler(2)) => {}
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[test]
+    fn test_write_com_stmt_execute() {
+        let params = vec![
+            Value::from(-1i64),
+            Value::default(),
+            Value::from("abc"),
+            Value {
+                typ: MysqlType::Date as Type,
+                val: b"2020-02-29".to_vec(),
+            },
+        ];
+        let data = write_com_stmt_execute(1, &params).unwrap();
+        let mut prepares = HashMap::new();
+        prepares.insert(1, PrepareData::new(1, "SELECT ?, ?, ?, ?".to_string(), 4));
+        assert_eq!(parse_com_stmt_execute(&data, &mut prepares).unwrap(), 1);
+        let actual = &prepares[&1].params;
+        assert_eq!(actual[0].val, b"-1".to_vec());
+        assert!(actual[1].is_null());
+        assert_eq!(actual[2].typ, MysqlType::Varchar as Type);
+        assert_eq!(actual[2].val, b"abc".to_vec());
+        assert_eq!(actual[3].val, b"2020-02-29".to_vec());
+
+        let data = write_com_stmt_execute(2, &[]).unwrap();
+        assert_eq!(data, [
+            0x17, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00
+        ]);
+    }
+}
@@ -0,0 +1,210 @@
+/// Count the `?` placeholders of a statement, skipping the ones that appear in
+/// quoted strings, quoted identifiers and comments.
+pub fn count_params(sql: &str) -> u16 {
+    let bytes = sql.as_bytes();
+    let mut count = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' | b'`' => {
+                i = skip_quoted(bytes, i);
+                continue;
+            }
+            b'#' => {
+                i = skip_line(bytes, i);
+                continue;
+            }
+            b'-' if bytes[i..].starts_with(b"-- ") || bytes[i..] == b"--"[..] => {
+                i = skip_line(bytes, i);
+                continue;
+            }
+            b'/' if bytes[i..].starts_with(b"/*") => {
+                i = skip_block_comment(bytes, i);
+                continue;
+            }
+            b'?' => count += 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    count
+}
+
+/// Split a multi-statement query on the semicolons that are not in quoted
+/// strings, quoted identifiers or comments. Statements made only of blanks and
+/// comments are dropped, e.g. the one after a trailing semicolon.
+pub fn split_statements(sql: &str) -> Vec<String> {
+    let bytes = sql.as_bytes();
+    let mut statements = vec![];
+    let mut start = 0;
+    // Whether the current statement holds more than blanks and comments.
+    let mut has_content = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' | b'`' => {
+                has_content = true;
+                i = skip_quoted(bytes, i);
+                continue;
+            }
+            b'#' => {
+                i = skip_line(bytes, i);
+                continue;
+            }
+            b'-' if bytes[i..].starts_with(b"-- ") || bytes[i..] == b"--"[..] => {
+                i = skip_line(bytes, i);
+                continue;
+            }
+            b'/' if bytes[i..].starts_with(b"/*") => {
+                // Executable comments /*! ... */ are part of the statement.
+                has_content |= bytes[i..].starts_with(b"/*!");
+                i = skip_block_comment(bytes, i);
+                continue;
+            }
+            b';' => {
+                if has_content {
+                    statements.push(sql[start..i].trim().to_string());
+                }
+                start = i + 1;
+                has_content = false;
+            }
+            c if !c.is_ascii_whitespace() => has_content = true,
+            _ => {}
+        }
+        i += 1;
+    }
+    if has_content {
+        statements.push(sql[start..].trim().to_string());
+    }
+    statements
+}
+
+/// Parse `KILL [CONNECTION | QUERY] processlist_id`, return the id and
+/// whether only the running query is killed.
+pub fn parse_kill(sql: &str) -> Option<(u32, bool)> {
+    let mut words = sql.trim().trim_end_matches(';').split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("kill") {
+        return None;
+    }
+    let mut word = words.next()?;
+    let query_only = word.eq_ignore_ascii_case("query");
+    if query_only || word.eq_ignore_ascii_case("connection") {
+        word = words.next()?;
+    }
+    let id = word.parse().ok()?;
+    if words.next().is_some() {
+        return None;
+    }
+    Some((id, query_only))
+}
+
+/// Parse `SHOW [FULL] PROCESSLIST`, return whether FULL is set.
+pub fn parse_show_processlist(sql: &str) -> Option<bool> {
+    let words: Vec<&str> = sql
+        .trim()
+        .trim_end_matches(';')
+        .split_whitespace()
+        .collect();
+    let is = |index: usize, word: &str| words[index].eq_ignore_ascii_case(word);
+    match words.len() {
+        2 if is(0, "show") && is(1, "processlist") => Some(false),
+        3 if is(0, "show") && is(1, "full") && is(2, "processlist") => Some(true),
+        _ => None,
+    }
+}
+
+/// Return the index right after the quoted part starting at `start`.
+fn skip_quoted(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if quote != b'`' => i += 1,
+            c if c == quote => {
+                // A doubled quote is an escaped quote.
+                if bytes.get(i + 1) == Some(&quote) {
+                    i += 1;
+                } else {
+                    return i + 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+fn skip_line(bytes: &[u8], start: usize) -> usize {
+    match bytes[start..].iter().position(|&c| c == b'\n') {
+        Some(n) => start + n + 1,
+        None => bytes.len(),
+    }
+}
+
+fn skip_block_comment(bytes: &[u8], start: usize) -> usize {
+    match bytes[start + 2..].windows(2).position(|w| w == b"*/") {
+        Some(n) => start + 2 + n + 2,
+        None => bytes.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::query::{
+        count_params, parse_kill, parse_show_processlist, split_statements,
+    };
+
+    #[test]
+    fn test_count_params() {
+        assert_eq!(count_params("SELECT 1"), 0);
+        assert_eq!(count_params("SELECT * FROM t WHERE a = ? AND b = ?"), 2);
+        assert_eq!(count_params("SELECT '?', \"?\", `?` FROM t WHERE a = ?"), 1);
+        assert_eq!(count_params("SELECT 'it''s ?', 'a\\'?' , ?"), 1);
+        assert_eq!(count_params("SELECT ? -- ?\n, ? # ?\n, /* ? */ ?"), 3);
+    }
+
+    #[test]
+    fn test_split_statements() {
+        assert_eq!(split_statements("SELECT 1"), vec!["SELECT 1"]);
+        assert_eq!(split_statements("SELECT 1; SELECT 2;"), vec![
+            "SELECT 1", "SELECT 2"
+        ]);
+        assert_eq!(
+            split_statements("SELECT ';', \";\", `;`; SELECT 'a\\';'"),
+            vec!["SELECT ';', \";\", `;`", "SELECT 'a\\';'"]
+        );
+        assert_eq!(
+            split_statements("SELECT 1 -- ;\n; # ;\n /* ; */ ; SELECT 2; -- done"),
+            vec!["SELECT 1 -- ;", "SELECT 2"]
+        );
+        assert_eq!(split_statements("/*!40101 SET NAMES utf8 */;"), vec![
+            "/*!40101 SET NAMES utf8 */"
+        ]);
+        assert!(split_statements(" ; ").is_empty());
+    }
+
+    #[test]
+    fn test_parse_kill() {
+        assert_eq!(parse_kill("KILL 3"), Some((3, false)));
+        assert_eq!(parse_kill("kill connection 3;"), Some((3, false)));
+        assert_eq!(parse_kill(" Kill Query  12 "), Some((12, true)));
+        assert_eq!(parse_kill("KILL QUERY"), None);
+        assert_eq!(parse_kill("KILL QUERY abc"), None);
+        assert_eq!(parse_kill("KILL 3 4"), None);
+        assert_eq!(parse_kill("SELECT 3"), None);
+        assert_eq!(parse_kill("KILLS 3"), None);
+    }
+
+    #[test]
+    fn test_parse_show_processlist() {
+        assert_eq!(parse_show_processlist("SHOW PROCESSLIST"), Some(false));
+        assert_eq!(
+            parse_show_processlist("show full  processlist;"),
+            Some(true)
+        );
+        assert_eq!(parse_show_processlist("SHOW FULL"), None);
+        assert_eq!(parse_show_processlist("SHOW TABLES"), None);
+        assert_eq!(parse_show_processlist("SHOW PROCESSLIST LIMIT 1"), None);
+    }
+}
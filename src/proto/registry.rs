@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::constants::PacketType;
+use crate::proto::{CancelToken, Session};
+
+use dakv_logger::prelude::*;
+
+/// How long drain waits for the connections closed at the shutdown deadline
+/// to return.
+const FORCED_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Names of the commands shown by SHOW PROCESSLIST, by command byte.
+const COMMAND_NAMES: [&str; 32] = [
+    "Sleep",
+    "Quit",
+    "Init DB",
+    "Query",
+    "Field List",
+    "Create DB",
+    "Drop DB",
+    "Refresh",
+    "Shutdown",
+    "Statistics",
+    "Processlist",
+    "Connect",
+    "Kill",
+    "Debug",
+    "Ping",
+    "Time",
+    "Delayed insert",
+    "Change user",
+    "Binlog Dump",
+    "Table Dump",
+    "Connect Out",
+    "Register Slave",
+    "Prepare",
+    "Execute",
+    "Long Data",
+    "Close stmt",
+    "Reset stmt",
+    "Set option",
+    "Fetch",
+    "Daemon",
+    "Binlog Dump GTID",
+    "Reset Connection",
+];
+
+/// ProcessInfo is a connection as listed by SHOW PROCESSLIST.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    pub id: u32,
+    pub user: String,
+    // host is the address of the client, host:port.
+    pub host: String,
+    pub db: String,
+    pub command: String,
+    // time is the number of seconds since the connection entered its command.
+    pub time: u64,
+    // info is the query being run.
+    pub info: Option<String>,
+}
+
+struct Entry {
+    // A clone of the socket, used to wake or close the connection.
+    stream: TcpStream,
+    // busy is set while the connection runs the handshake or a command.
+    busy: bool,
+    // user is empty until the connection is authenticated.
+    user: String,
+    host: String,
+    db: String,
+    // command is the name of the running command, Sleep while idle.
+    command: &'static str,
+    since: Instant,
+    info: Option<String>,
+    // cancel_token of the session, cancelled by KILL.
+    cancel_token: CancelToken,
+    // killed is set by KILL CONNECTION, the connection is closed once its
+    // command ends.
+    killed: bool,
+}
+
+/// Registry tracks the connections of a Listener by id, to kill them and to
+/// drain them on shutdown.
+#[derive(Default)]
+pub struct Registry {
+    shutdown: AtomicBool,
+    deadline: Mutex<Option<Instant>>,
+    connections: Mutex<HashMap<u32, Entry>>,
+    drained: Condvar,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Track a new connection, it is busy until it waits for its first command.
+    pub fn register(&self, id: u32, stream: TcpStream) {
+        let mut connections = self.connections.lock().unwrap();
+        let host = match stream.peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "".to_string(),
+        };
+        connections.insert(id, Entry {
+            stream,
+            busy: true,
+            user: "".to_string(),
+            host,
+            db: "".to_string(),
+            command: "Connect",
+            since: Instant::now(),
+            info: None,
+            cancel_token: CancelToken::new(),
+            killed: false,
+        });
+    }
+
+    /// Attach the session of an authenticated connection, it is updated after
+    /// every command as the schema may change.
+    pub fn set_session(&self, id: u32, session: &Session) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(entry) = connections.get_mut(&id) {
+            entry.user = session.user().to_string();
+            entry.db = session.schema().to_string();
+            entry.cancel_token = session.cancel_token().clone();
+        }
+    }
+
+    /// Record the command a connection runs, with the query text if any.
+    pub fn set_command(&self, id: u32, command: u8, info: Option<String>) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(entry) = connections.get_mut(&id) {
+            entry.command = COMMAND_NAMES
+                .get(command as usize)
+                .copied()
+                .unwrap_or("Error");
+            entry.since = Instant::now();
+            entry.info = info;
+        }
+    }
+
+    pub fn unregister(&self, id: u32) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.remove(&id);
+        self.drained.notify_all();
+    }
+
+    /// Mark a connection busy before it runs a command and idle before it
+    /// waits for the next one. Return false once the listener shuts down or
+    /// the connection is killed, the connection should then be closed instead.
+    pub fn set_busy(&self, id: u32, busy: bool) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        if self.is_shutdown() {
+            return false;
+        }
+        if let Some(entry) = connections.get_mut(&id) {
+            if entry.killed {
+                return false;
+            }
+            entry.busy = busy;
+            if busy {
+                // A KILL QUERY only interrupts the running command.
+                entry.cancel_token.reset();
+            } else {
+                entry.command = "Sleep";
+                entry.since = Instant::now();
+                entry.info = None;
+            }
+        }
+        true
+    }
+
+    /// Return the user of a connection, None if there is no connection id.
+    pub fn user(&self, id: u32) -> Option<String> {
+        let connections = self.connections.lock().unwrap();
+        connections.get(&id).map(|entry| entry.user.clone())
+    }
+
+    /// List the connections ordered by id.
+    pub fn processlist(&self) -> Vec<ProcessInfo> {
+        let connections = self.connections.lock().unwrap();
+        let mut processes: Vec<ProcessInfo> = connections
+            .iter()
+            .map(|(id, entry)| ProcessInfo {
+                id: *id,
+                user: entry.user.clone(),
+                host: entry.host.clone(),
+                db: entry.db.clone(),
+                command: entry.command.to_string(),
+                time: entry.since.elapsed().as_secs(),
+                info: entry.info.clone(),
+            })
+            .collect();
+        processes.sort_by_key(|process| process.id);
+        processes
+    }
+
+    /// Cancel the running query of a connection, then close the connection
+    /// unless query_only, an idle one is woken up like on shutdown. Return
+    /// false if there is no connection id.
+    pub fn kill(&self, id: u32, query_only: bool) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let entry = match connections.get_mut(&id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        entry.cancel_token.cancel();
+        if !query_only {
+            entry.killed = true;
+            entry.command = "Killed";
+            if !entry.busy {
+                let _ = entry.stream.shutdown(Shutdown::Read);
+            }
+        }
+        true
+    }
+
+    /// Stop the listener, the idle connections are woken up by closing the
+    /// read side of their socket, the busy ones finish their command first.
+    pub fn shutdown(&self, timeout: Duration) {
+        *self.deadline.lock().unwrap() = Some(Instant::now() + timeout);
+        let connections = self.connections.lock().unwrap();
+        self.shutdown.store(true, Ordering::SeqCst);
+        for entry in connections.values().filter(|entry| !entry.busy) {
+            let _ = entry.stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Wait for the connections to close until the shutdown deadline, the
+    /// connections still running a command past it are closed and given
+    /// FORCED_CLOSE_TIMEOUT to return.
+    pub fn drain(&self) {
+        let deadline = self.deadline.lock().unwrap().unwrap_or_else(Instant::now);
+        let connections = self.wait_drained(self.connections.lock().unwrap(), deadline);
+        if connections.is_empty() {
+            return;
+        }
+        for (id, entry) in connections.iter() {
+            warn!("Close connection {} still busy at shutdown", id);
+            let _ = entry.stream.shutdown(Shutdown::Both);
+        }
+        let connections = self.wait_drained(connections, Instant::now() + FORCED_CLOSE_TIMEOUT);
+        if !connections.is_empty() {
+            warn!(
+                "{} connections still open after shutdown",
+                connections.len()
+            );
+        }
+    }
+
+    /// Wait until the connections are empty or the deadline passes.
+    fn wait_drained<'a>(
+        &self,
+        mut connections: MutexGuard<'a, HashMap<u32, Entry>>,
+        deadline: Instant,
+    ) -> MutexGuard<'a, HashMap<u32, Entry>> {
+        while !connections.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            connections = self
+                .drained
+                .wait_timeout(connections, deadline - now)
+                .unwrap()
+                .0;
+        }
+        connections
+    }
+}
+
+/// Tracker reports the state of a connection to the registry of its listener,
+/// the blocking and the async connections share it. A connection without a
+/// listener has no registry, it is then never killed nor shut down.
+pub struct Tracker {
+    id: u32,
+    registry: Option<Arc<Registry>>,
+}
+
+impl Tracker {
+    pub fn new(id: u32) -> Self {
+        Tracker { id, registry: None }
+    }
+
+    pub fn set_registry(&mut self, registry: Arc<Registry>) {
+        self.registry = Some(registry);
+    }
+
+    pub fn set_session(&self, session: &Session) {
+        if let Some(registry) = &self.registry {
+            registry.set_session(self.id, session);
+        }
+    }
+
+    /// Show the command in the processlist, with the query text if any.
+    pub fn set_command(&self, data: &[u8]) {
+        if let (Some(registry), Some((&command, payload))) = (&self.registry, data.split_first()) {
+            let info = match PacketType::from(command as u64) {
+                PacketType::ComQuery => Some(String::from_utf8_lossy(payload).into_owned()),
+                _ => None,
+            };
+            registry.set_command(self.id, command, info);
+        }
+    }
+
+    /// Tell the registry whether the connection runs a command, return false
+    /// if the listener shuts down or the connection is killed.
+    pub fn set_busy(&self, busy: bool) -> bool {
+        match &self.registry {
+            Some(registry) => registry.set_busy(self.id, busy),
+            None => true,
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        match &self.registry {
+            Some(registry) => registry.is_shutdown(),
+            None => false,
+        }
+    }
+}
+
+/// ShutdownHandle stops a Listener from another thread: the listener stops
+/// accepting connections, the idle clients receive an ERR packet and the
+/// running commands are given until the deadline to finish, then `accept` returns.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    registry: Arc<Registry>,
+    // The listener address, connected to in order to wake up accept.
+    addr: SocketAddr,
+}
+
+impl ShutdownHandle {
+    pub fn new(registry: Arc<Registry>, mut addr: SocketAddr) -> Self {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+                SocketAddr::V6(_) => [0, 0, 0, 0, 0, 0, 0, 1].into(),
+            });
+        }
+        ShutdownHandle { registry, addr }
+    }
+
+    /// Start the shutdown, the connections still running a command after
+    /// timeout are closed.
+    pub fn shutdown(&self, timeout: Duration) {
+        if self.registry.is_shutdown() {
+            return;
+        }
+        self.registry.shutdown(timeout);
+        // accept blocks until a connection comes in.
+        let _ = TcpStream::connect(self.addr);
+    }
+}
@@ -0,0 +1,231 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::proto::Auth;
+
+/// Session is the state of an authenticated connection, it is passed to every
+/// Handler callback of the connection. Handlers keep their own state in the
+/// extensions.
+#[derive(Debug)]
+pub struct Session {
+    connection_id: u32,
+    user: String,
+    addr: SocketAddr,
+    // schema is the current database, empty if none is selected.
+    schema: String,
+    // capability flags sent by the client.
+    capability: u32,
+    charset: u8,
+    connection_attrs: HashMap<String, String>,
+    extensions: Extensions,
+    cancel_token: CancelToken,
+}
+
+impl Session {
+    pub fn new(connection_id: u32, addr: SocketAddr) -> Self {
+        Session {
+            connection_id,
+            user: "".to_string(),
+            addr,
+            schema: "".to_string(),
+            capability: 0,
+            charset: 0,
+            connection_attrs: HashMap::new(),
+            extensions: Extensions::new(),
+            cancel_token: CancelToken::new(),
+        }
+    }
+
+    /// Create the session of a client authenticated with auth, the database
+    /// of auth becomes the schema once the handler accepts it.
+    pub fn from_auth(connection_id: u32, addr: SocketAddr, auth: &Auth) -> Self {
+        Session {
+            connection_id,
+            user: auth.user().clone(),
+            addr,
+            schema: "".to_string(),
+            capability: auth.capability_flags(),
+            charset: auth.charset(),
+            connection_attrs: auth.connection_attrs().clone(),
+            extensions: Extensions::new(),
+            cancel_token: CancelToken::new(),
+        }
+    }
+
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    pub fn schema(&self) -> &str {
+        &self.schema
+    }
+
+    pub fn set_schema(&mut self, schema: &str) {
+        self.schema = schema.to_string();
+    }
+
+    pub fn capability(&self) -> u32 {
+        self.capability
+    }
+
+    pub fn charset(&self) -> u8 {
+        self.charset
+    }
+
+    pub fn connection_attrs(&self) -> &HashMap<String, String> {
+        &self.connection_attrs
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Return the token cancelled when the running query is killed.
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel_token
+    }
+}
+
+/// CancelToken is cancelled by KILL, long running queries should check it and
+/// return an error, the client then gets ER_QUERY_INTERRUPTED. It is reset
+/// when the connection receives its next command.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Extensions holds at most one value of each type.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Extensions::default()
+    }
+
+    /// Insert a value, return the previous value of its type.
+    pub fn insert<T: Any + Send>(&mut self, val: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(val))
+            .and_then(|prev| prev.downcast().ok().map(|prev| *prev))
+    }
+
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_ref())
+    }
+
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_mut())
+    }
+
+    pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|val| val.downcast().ok().map(|val| *val))
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::constants::DEFAULT_CLIENT_CAPABILITY;
+    use crate::proto::{Auth, Session};
+
+    #[derive(Debug, PartialEq)]
+    struct Counter(u32);
+
+    #[test]
+    fn test_session() {
+        let mut attrs = HashMap::new();
+        attrs.insert("program_name".to_string(), "mysql".to_string());
+        let resp = Auth::write_handshake_resp(
+            DEFAULT_CLIENT_CAPABILITY,
+            33,
+            "root".to_string(),
+            "".to_string(),
+            &[0; 20],
+            "mysql_native_password",
+            "test".to_string(),
+            &attrs,
+        )
+        .unwrap();
+        let mut auth = Auth::new();
+        auth.parse_client_handshake_packet(&resp, false).unwrap();
+
+        let addr = "127.0.0.1:3306".parse().unwrap();
+        let mut session = Session::from_auth(7, addr, &auth);
+        assert_eq!(session.connection_id(), 7);
+        assert_eq!(session.user(), "root");
+        assert_eq!(session.schema(), "");
+        assert_eq!(session.charset(), 33);
+        assert_eq!(session.connection_attrs(), &attrs);
+        session.set_schema("other");
+        assert_eq!(session.schema(), "other");
+
+        assert_eq!(session.extensions().get::<Counter>(), None);
+        session.extensions_mut().insert(Counter(1));
+        session.extensions_mut().get_mut::<Counter>().unwrap().0 += 1;
+        assert_eq!(session.extensions().get::<Counter>(), Some(&Counter(2)));
+        assert_eq!(
+            session.extensions_mut().insert(Counter(5)),
+            Some(Counter(2))
+        );
+        assert_eq!(
+            session.extensions_mut().remove::<Counter>(),
+            Some(Counter(5))
+        );
+        assert_eq!(session.extensions().get::<Counter>(), None);
+    }
+}
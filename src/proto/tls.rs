@@ -0,0 +1,66 @@
+use crate::constants::TLSVersion;
+use crate::errors::ProtoResult;
+
+use native_tls::{Identity, Protocol, TlsAcceptor};
+
+/// TlsConfig holds the server certificate used to upgrade the connections
+/// of clients sending an SSLRequest.
+pub struct TlsConfig {
+    identity: Identity,
+    min_version: TLSVersion,
+}
+
+impl TlsConfig {
+    /// Create a config from a PEM encoded certificate chain and a PEM encoded
+    /// PKCS #8 private key.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> ProtoResult<Self> {
+        Ok(TlsConfig {
+            identity: Identity::from_pkcs8(cert, key)?,
+            min_version: TLSVersion::VersionTLS12,
+        })
+    }
+
+    /// Create a config from a DER encoded PKCS #12 archive.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> ProtoResult<Self> {
+        Ok(TlsConfig {
+            identity: Identity::from_pkcs12(der, password)?,
+            min_version: TLSVersion::VersionTLS12,
+        })
+    }
+
+    /// Set the minimum protocol version accepted, default to TLS 1.2.
+    pub fn set_min_version(&mut self, version: TLSVersion) {
+        self.min_version = version;
+    }
+
+    pub fn build_acceptor(&self) -> ProtoResult<TlsAcceptor> {
+        let acceptor = TlsAcceptor::builder(self.identity.clone())
+            .min_protocol_version(Some(to_protocol(&self.min_version)))
+            .build()?;
+        Ok(acceptor)
+    }
+}
+
+fn to_protocol(version: &TLSVersion) -> Protocol {
+    match version {
+        TLSVersion::VersionSSL30 => Protocol::Sslv3,
+        TLSVersion::VersionTLS10 => Protocol::Tlsv10,
+        TLSVersion::VersionTLS11 => Protocol::Tlsv11,
+        TLSVersion::VersionTLS12 => Protocol::Tlsv12,
+        TLSVersion::VersionTLS13 => Protocol::Tlsv13,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ProtoError;
+    use crate::proto::TlsConfig;
+
+    #[test]
+    fn test_invalid_identity() {
+        match TlsConfig::from_pem(b"not a certificate", b"not a key") {
+            Err(ProtoError::Tls(_)) => {}
+            _ => panic!("Unexpected result"),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
-enum MysqlType {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MysqlType {
     // NULL_TYPE specifies a NULL type.
     NullType = 0,
     // INT8 specifies a TINYINT type.
@@ -110,13 +111,13 @@ pub enum MysqlFlag {
 
 pub type Type = i32;
 
-#[derive(Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Value {
     pub typ: Type,
     pub val: Vec<u8>,
 }
 
-#[derive(Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Field {
     pub name: String,
     pub typ: i32,
@@ -128,6 +129,9 @@ pub struct Field {
     pub charset: u32,
     pub decimals: u32,
     pub flags: u32,
+    // default_value is only sent in the responses to COM_FIELD_LIST, None
+    // stands for NULL.
+    pub default_value: Option<String>,
 }
 
 #[derive(Default)]
@@ -144,6 +148,42 @@ impl Value {
     }
 }
 
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value {
+            typ: MysqlType::Int64 as Type,
+            val: n.to_string().into_bytes(),
+        }
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value {
+            typ: MysqlType::Uint64 as Type,
+            val: n.to_string().into_bytes(),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value {
+            typ: MysqlType::Float64 as Type,
+            val: n.to_string().into_bytes(),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value {
+            typ: MysqlType::Varchar as Type,
+            val: s.as_bytes().to_vec(),
+        }
+    }
+}
+
 // fixme macro bodies max length
 lazy_static! {
     static ref TYPE_TO_MYSQL: HashMap<i32, (i64, i64)> = {
@@ -222,3 +262,112 @@ pub fn type_to_mysql(typ: Type) -> (i64, i64) {
         }
     };
 }
+
+lazy_static! {
+    static ref MYSQL_TO_TYPE: HashMap<i64, i32> = {
+        let mut m = HashMap::new();
+        m.insert(0, MysqlType::Decimal as i32);
+        m.insert(1, MysqlType::Int8 as i32);
+        m.insert(2, MysqlType::Int16 as i32);
+        m.insert(3, MysqlType::Int32 as i32);
+        m.insert(4, MysqlType::Float32 as i32);
+        m.insert(5, MysqlType::Float64 as i32);
+        m.insert(6, MysqlType::NullType as i32);
+        m.insert(7, MysqlType::Timestamp as i32);
+        m.insert(8, MysqlType::Int64 as i32);
+        m.insert(9, MysqlType::Int24 as i32);
+        m.insert(10, MysqlType::Date as i32);
+        m.insert(11, MysqlType::Time as i32);
+        m.insert(12, MysqlType::Datetime as i32);
+        m.insert(13, MysqlType::Year as i32);
+        m.insert(15, MysqlType::Varchar as i32);
+        m.insert(16, MysqlType::Bit as i32);
+        m.insert(17, MysqlType::Timestamp as i32);
+        m.insert(18, MysqlType::Datetime as i32);
+        m.insert(19, MysqlType::Time as i32);
+        m.insert(245, MysqlType::Json as i32);
+        m.insert(246, MysqlType::Decimal as i32);
+        m.insert(247, MysqlType::Enum as i32);
+        m.insert(248, MysqlType::Set as i32);
+        m.insert(249, MysqlType::Text as i32);
+        m.insert(250, MysqlType::Text as i32);
+        m.insert(251, MysqlType::Text as i32);
+        m.insert(252, MysqlType::Text as i32);
+        m.insert(253, MysqlType::Varchar as i32);
+        m.insert(254, MysqlType::Char as i32);
+        m.insert(255, MysqlType::Geometry as i32);
+        m
+    };
+}
+
+/// Convert a mysql wire type and its column flags back into a Type.
+/// Return None if the wire type is unknown.
+pub fn mysql_to_type(typ: i64, flags: i64) -> Option<Type> {
+    let result = *MYSQL_TO_TYPE.get(&typ)?;
+    Some(modify_type(result, flags))
+}
+
+fn modify_type(typ: Type, flags: i64) -> Type {
+    if flags & MysqlFlag::MysqlUnsigned as i64 != 0 {
+        let unsigned = match typ {
+            t if t == MysqlType::Int8 as Type => MysqlType::Uint8,
+            t if t == MysqlType::Int16 as Type => MysqlType::Uint16,
+            t if t == MysqlType::Int24 as Type => MysqlType::Uint24,
+            t if t == MysqlType::Int32 as Type => MysqlType::Uint32,
+            t if t == MysqlType::Int64 as Type => MysqlType::Uint64,
+            _ => return typ,
+        };
+        return unsigned as Type;
+    }
+    if flags & MysqlFlag::MysqlBinary as i64 != 0 {
+        let binary = match typ {
+            t if t == MysqlType::Text as Type => MysqlType::Blob,
+            t if t == MysqlType::Varchar as Type => MysqlType::VarBinary,
+            t if t == MysqlType::Char as Type => MysqlType::Binary,
+            _ => return typ,
+        };
+        return binary as Type;
+    }
+    if typ == MysqlType::Char as Type {
+        if flags & MysqlFlag::MysqlEnum as i64 != 0 {
+            return MysqlType::Enum as Type;
+        }
+        if flags & MysqlFlag::MysqlSet as i64 != 0 {
+            return MysqlType::Set as Type;
+        }
+    }
+    typ
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sql_type::{mysql_to_type, type_to_mysql, MysqlFlag, MysqlType, Type};
+
+    #[test]
+    fn test_mysql_to_type() {
+        assert_eq!(mysql_to_type(1, 0), Some(MysqlType::Int8 as Type));
+        assert_eq!(
+            mysql_to_type(8, MysqlFlag::MysqlUnsigned as i64),
+            Some(MysqlType::Uint64 as Type)
+        );
+        assert_eq!(
+            mysql_to_type(253, MysqlFlag::MysqlBinary as i64),
+            Some(MysqlType::VarBinary as Type)
+        );
+        assert_eq!(
+            mysql_to_type(254, MysqlFlag::MysqlEnum as i64),
+            Some(MysqlType::Enum as Type)
+        );
+        assert_eq!(mysql_to_type(14, 0), None);
+        for typ in &[
+            MysqlType::Int32,
+            MysqlType::Uint24,
+            MysqlType::Datetime,
+            MysqlType::Blob,
+            MysqlType::Set,
+        ] {
+            let (t, f) = type_to_mysql(*typ as Type);
+            assert_eq!(mysql_to_type(t, f), Some(*typ as Type));
+        }
+    }
+}